@@ -0,0 +1,458 @@
+use std::time::{Duration, Instant};
+
+use image::RgbImage;
+
+use crate::panel::Panel;
+
+/// Size of the data chunks of `AT+IMG`, each chunk is followed by `\r\n`
+pub const CHUNK_SIZE: usize = 1000;
+/// An image transfer fails when no data arrives for this long
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_millis(500);
+/// Protocol version that adds framed transfers with `AT+BIMG`
+pub const FRAMED_VERSION: u32 = 2;
+
+const OK: &[u8] = b"OK\r\n";
+const BUSY: &[u8] = b"BUSY\r\n";
+const ERROR: &[u8] = b"ERROR\r\n";
+
+/// Misbehaviour to inject, the counters go down as the faults happen
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Stay busy this long after `AT+SHOW`, like a slow full refresh
+    pub refresh_time: Duration,
+    /// Answer BUSY to this many commands
+    pub busy_replies: u32,
+    /// Leave this many commands unanswered, like firmware that hangs on them
+    pub silent_replies: u32,
+    /// Fail the checksum of this many image transfers
+    pub crc_errors: u32,
+    /// Lose this many bytes of the next image transfer
+    pub dropped_bytes: u32,
+    /// Disconnect after showing this many frames
+    pub disconnect_after: Option<u32>,
+}
+
+/// A frame shown with `AT+SHOW`
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub image: RgbImage,
+    pub full_refresh: bool,
+    pub border: bool,
+}
+
+enum Encoding {
+    /// `AT+IMG`: chunks followed by `\r\n`, checked with an 8 bit sum
+    Chunks {
+        /// Bytes of the `\r\n` after the current chunk that are still expected
+        delimiter: usize,
+        valid: bool,
+    },
+    /// `AT+BIMG`: COBS frames with a sequence number and CRC32, checked with a CRC32
+    Frames { next_sequence: u16, frame: Vec<u8> },
+}
+
+struct Transfer {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    with_red: bool,
+    checksum: u32,
+    data: Vec<u8>,
+    expected: usize,
+    encoding: Encoding,
+    last_data: Instant,
+}
+
+/// The AT command handling of the driver board firmware, without the serial port
+pub struct Firmware {
+    panel: Panel,
+    /// Answer `AT+VER` and accept `AT+BIMG`, old firmware only knows `AT+IMG`
+    pub framed: bool,
+    pub faults: Faults,
+    pub frames: Vec<Frame>,
+    pub leds: Vec<u8>,
+    line: Vec<u8>,
+    transfer: Option<Transfer>,
+    busy_until: Option<Instant>,
+    disconnected: bool,
+}
+
+impl Firmware {
+    pub fn new(width: u32, height: u32, faults: Faults) -> Self {
+        Self {
+            panel: Panel::new(width, height),
+            framed: true,
+            faults,
+            frames: Vec::new(),
+            leds: Vec::new(),
+            line: Vec::new(),
+            transfer: None,
+            busy_until: None,
+            disconnected: false,
+        }
+    }
+
+    /// Whether the firmware wants the connection closed
+    pub fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Handle received bytes, returns the reply to send back
+    pub fn receive(&mut self, bytes: &[u8], now: Instant) -> Vec<u8> {
+        let mut reply = Vec::new();
+        for &byte in bytes {
+            if self.disconnected {
+                break;
+            }
+            if self.transfer.is_some() {
+                self.receive_data(byte, now, &mut reply);
+                continue;
+            }
+
+            self.line.push(byte);
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                self.line.clear();
+                if !line.is_empty() {
+                    reply.extend_from_slice(&self.command(&line, now));
+                }
+            }
+        }
+        reply
+    }
+
+    /// Fail an image transfer that stalled, returns the reply to send back
+    pub fn poll(&mut self, now: Instant) -> Vec<u8> {
+        match &self.transfer {
+            Some(transfer) if now.duration_since(transfer.last_data) > TRANSFER_TIMEOUT => {
+                self.transfer = None;
+                ERROR.to_vec()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn receive_data(&mut self, byte: u8, now: Instant, reply: &mut Vec<u8>) {
+        if self.faults.dropped_bytes > 0 {
+            self.faults.dropped_bytes -= 1;
+            return;
+        }
+
+        let Some(transfer) = self.transfer.as_mut() else {
+            return;
+        };
+        transfer.last_data = now;
+
+        match &mut transfer.encoding {
+            Encoding::Chunks { delimiter, valid } => {
+                if *delimiter > 0 {
+                    let expected = if *delimiter == 2 { b'\r' } else { b'\n' };
+                    *valid &= byte == expected;
+                    *delimiter -= 1;
+                } else {
+                    transfer.data.push(byte);
+                    if transfer.data.len() % CHUNK_SIZE == 0
+                        || transfer.data.len() == transfer.expected
+                    {
+                        *delimiter = 2;
+                    }
+                }
+                if transfer.data.len() < transfer.expected || *delimiter > 0 {
+                    return;
+                }
+                if !*valid {
+                    self.transfer = None;
+                    reply.extend_from_slice(ERROR);
+                    return;
+                }
+            }
+            Encoding::Frames {
+                next_sequence,
+                frame,
+            } => {
+                if byte != 0 {
+                    frame.push(byte);
+                    return;
+                }
+                let received = decode_frame(frame);
+                frame.clear();
+
+                match received {
+                    Some((sequence, payload))
+                        if sequence == *next_sequence
+                            && transfer.data.len() + payload.len() <= transfer.expected =>
+                    {
+                        transfer.data.extend_from_slice(&payload);
+                        *next_sequence = next_sequence.wrapping_add(1);
+                        reply.extend_from_slice(format!("ACK {}\r\n", sequence).as_bytes());
+                    }
+                    // The ACK got lost and the frame was sent again
+                    Some((sequence, _)) if sequence.wrapping_add(1) == *next_sequence => {
+                        reply.extend_from_slice(format!("ACK {}\r\n", sequence).as_bytes());
+                    }
+                    _ => {
+                        reply.extend_from_slice(format!("NAK {}\r\n", next_sequence).as_bytes());
+                    }
+                }
+                if transfer.data.len() < transfer.expected {
+                    return;
+                }
+            }
+        }
+
+        let Some(transfer) = self.transfer.take() else {
+            return;
+        };
+        reply.extend_from_slice(self.finish(transfer));
+    }
+
+    /// Check a complete transfer and copy it to the panel
+    fn finish(&mut self, transfer: Transfer) -> &'static [u8] {
+        let checksum = match transfer.encoding {
+            Encoding::Chunks { .. } => transfer
+                .data
+                .iter()
+                .fold(0u8, |acc, &x| acc.wrapping_add(x))
+                as u32,
+            Encoding::Frames { .. } => crc32fast::hash(&transfer.data),
+        };
+        let crc_fault = self.faults.crc_errors > 0;
+        if crc_fault {
+            self.faults.crc_errors -= 1;
+        }
+
+        if checksum != transfer.checksum || crc_fault {
+            return ERROR;
+        }
+        self.panel.write(
+            &transfer.data,
+            transfer.x,
+            transfer.y,
+            transfer.width,
+            transfer.height,
+            transfer.with_red,
+        );
+        OK
+    }
+
+    fn command(&mut self, line: &str, now: Instant) -> Vec<u8> {
+        if self.faults.silent_replies > 0 {
+            self.faults.silent_replies -= 1;
+            return vec![];
+        }
+        if self.busy_until.is_some_and(|until| now < until) {
+            return BUSY.to_vec();
+        }
+        if self.faults.busy_replies > 0 {
+            self.faults.busy_replies -= 1;
+            return BUSY.to_vec();
+        }
+
+        let (name, args) = line.split_once('=').unwrap_or((line, ""));
+        let Ok(args) = args
+            .split_whitespace()
+            .map(str::parse::<u32>)
+            .collect::<Result<Vec<u32>, _>>()
+        else {
+            return ERROR.to_vec();
+        };
+
+        match (name, args.as_slice()) {
+            ("AT+VER", &[]) if self.framed => format!("OK {}\r\n", FRAMED_VERSION).into_bytes(),
+            ("AT+IMG", &[with_red, x, y, width, height, crc]) if crc <= u8::MAX as u32 => {
+                let encoding = Encoding::Chunks {
+                    delimiter: 0,
+                    valid: true,
+                };
+                self.start_transfer([with_red, x, y, width, height], crc, encoding, now)
+            }
+            ("AT+BIMG", &[with_red, x, y, width, height, crc]) if self.framed => {
+                let encoding = Encoding::Frames {
+                    next_sequence: 0,
+                    frame: Vec::new(),
+                };
+                self.start_transfer([with_red, x, y, width, height], crc, encoding, now)
+            }
+            ("AT+SHOW", &[full_refresh, border]) => {
+                self.frames.push(Frame {
+                    image: self.panel.to_rgb(),
+                    full_refresh: full_refresh == 1,
+                    border: border == 1,
+                });
+                if !self.faults.refresh_time.is_zero() {
+                    self.busy_until = Some(now + self.faults.refresh_time);
+                }
+                if let Some(frames) = self.faults.disconnect_after.as_mut() {
+                    *frames = frames.saturating_sub(1);
+                    self.disconnected = *frames == 0;
+                }
+                OK.to_vec()
+            }
+            ("AT+LED", &[color]) if color <= u8::MAX as u32 => {
+                self.leds.push(color as u8);
+                OK.to_vec()
+            }
+            ("AT+READY", &[]) => OK.to_vec(),
+            _ => ERROR.to_vec(),
+        }
+    }
+
+    fn start_transfer(
+        &mut self,
+        [with_red, x, y, width, height]: [u32; 5],
+        checksum: u32,
+        encoding: Encoding,
+        now: Instant,
+    ) -> Vec<u8> {
+        if with_red > 1 || !self.panel.fits(x, y, width, height) {
+            return ERROR.to_vec();
+        }
+        let planes = if with_red == 1 { 2 } else { 1 };
+        let expected = (width * height / 8 * planes) as usize;
+        if expected == 0 {
+            return ERROR.to_vec();
+        }
+        self.transfer = Some(Transfer {
+            x,
+            y,
+            width,
+            height,
+            with_red: with_red == 1,
+            checksum,
+            data: Vec::with_capacity(expected),
+            expected,
+            encoding,
+            last_data: now,
+        });
+        OK.to_vec()
+    }
+}
+
+/// Decode a COBS frame without the 0 delimiter: sequence number, payload and CRC32, all little endian
+fn decode_frame(frame: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let decoded = cobs::decode_vec(frame).ok()?;
+    if decoded.len() < 6 {
+        return None;
+    }
+    let (content, crc) = decoded.split_at(decoded.len() - 4);
+    if crc32fast::hash(content).to_le_bytes() != crc {
+        return None;
+    }
+    let sequence = u16::from_le_bytes([content[0], content[1]]);
+    Some((sequence, content[2..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn transfer(data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for chunk in data.chunks(CHUNK_SIZE) {
+            bytes.extend_from_slice(chunk);
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes
+    }
+
+    fn crc(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |acc, &x| acc.wrapping_add(x))
+    }
+
+    #[test]
+    fn show_image() {
+        let now = Instant::now();
+        let mut firmware = Firmware::new(250, 122, Faults::default());
+
+        // Every byte holds 8 pixels of a column, the data contains \r\n on purpose
+        let mut data = vec![0u8; 250 * 128 / 8];
+        data[0] = 0b1000_0000;
+        data[17] = b'\r';
+        data[18] = b'\n';
+        let command = format!("AT+IMG=0 0 0 250 128 {}\r\n", crc(&data));
+
+        assert_eq!(firmware.receive(command.as_bytes(), now), OK);
+        assert_eq!(firmware.receive(&transfer(&data), now), OK);
+        assert_eq!(firmware.receive(b"AT+SHOW=1 0\r\n", now), OK);
+
+        let frame = &firmware.frames[0];
+        assert!(frame.full_refresh);
+        assert_eq!(frame.image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(frame.image.get_pixel(0, 1), &Rgb([255, 255, 255]));
+        assert_eq!(frame.image.get_pixel(1, 0), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn inject_faults() {
+        let now = Instant::now();
+        let faults = Faults {
+            refresh_time: Duration::from_secs(1),
+            busy_replies: 1,
+            crc_errors: 1,
+            ..Default::default()
+        };
+        let mut firmware = Firmware::new(8, 8, faults);
+
+        assert_eq!(firmware.receive(b"AT+READY=\r\n", now), BUSY);
+        assert_eq!(firmware.receive(b"AT+IMG=0 0 0 8 8 0\r\n", now), OK);
+        assert_eq!(firmware.receive(&transfer(&[0; 8]), now), ERROR);
+        assert_eq!(firmware.receive(b"AT+SHOW=0 0\r\n", now), OK);
+        assert_eq!(firmware.receive(b"AT+LED=1\r\n", now), BUSY);
+        let later = now + Duration::from_secs(2);
+        assert_eq!(firmware.receive(b"AT+LED=1\r\n", later), OK);
+
+        // Lost bytes stall the transfer until it times out
+        firmware.faults.dropped_bytes = 3;
+        assert_eq!(firmware.receive(b"AT+IMG=0 0 0 8 8 0\r\n", later), OK);
+        assert_eq!(firmware.receive(&transfer(&[0; 8]), later), b"");
+        assert_eq!(firmware.poll(later + TRANSFER_TIMEOUT * 2), ERROR);
+        assert_eq!(firmware.receive(b"AT+BOGUS\r\n", later), ERROR);
+    }
+
+    fn encode_frame(sequence: u16, payload: &[u8]) -> Vec<u8> {
+        let mut content = sequence.to_le_bytes().to_vec();
+        content.extend_from_slice(payload);
+        content.extend_from_slice(&crc32fast::hash(&content).to_le_bytes());
+        let mut frame = cobs::encode_vec(&content);
+        frame.push(0);
+        frame
+    }
+
+    #[test]
+    fn framed_transfer() {
+        let now = Instant::now();
+        let mut firmware = Firmware::new(16, 16, Faults::default());
+        assert_eq!(firmware.receive(b"AT+VER\r\n", now), b"OK 2\r\n");
+
+        let data: Vec<u8> = (0..32).collect();
+        let command = format!("AT+BIMG=0 0 0 16 16 {}\r\n", crc32fast::hash(&data));
+        assert_eq!(firmware.receive(command.as_bytes(), now), OK);
+
+        let mut damaged = encode_frame(0, &data[..16]);
+        damaged[3] ^= 0xff;
+        assert_eq!(firmware.receive(&damaged, now), b"NAK 0\r\n");
+        assert_eq!(
+            firmware.receive(&encode_frame(1, &data[16..]), now),
+            b"NAK 0\r\n"
+        );
+        assert_eq!(
+            firmware.receive(&encode_frame(0, &data[..16]), now),
+            b"ACK 0\r\n"
+        );
+        // Sent again because the ACK got lost
+        assert_eq!(
+            firmware.receive(&encode_frame(0, &data[..16]), now),
+            b"ACK 0\r\n"
+        );
+        assert_eq!(
+            firmware.receive(&encode_frame(1, &data[16..]), now),
+            b"ACK 1\r\nOK\r\n"
+        );
+
+        firmware.framed = false;
+        assert_eq!(firmware.receive(b"AT+VER\r\n", now), ERROR);
+    }
+}
@@ -0,0 +1,167 @@
+//! Emulates the firmware of a RemijnPi driver board on a pseudo terminal,
+//! so the serial code of the driver can run without hardware.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::openpty,
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+    unistd::ttyname,
+};
+
+pub mod firmware;
+pub mod panel;
+
+pub use firmware::{Faults, Firmware, Frame};
+
+const POLL_INTERVAL_MS: i32 = 20;
+
+/// A driver board on a pty, the driver opens `path()` like a serial port
+pub struct Emulator {
+    path: PathBuf,
+    firmware: Arc<Mutex<Firmware>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Emulator {
+    /// Open a pty and start answering commands for a panel of `width` x `height`.
+    /// Every shown frame is saved as `frame-NNNN.png` in `output` when it is set.
+    pub fn start(
+        width: u32,
+        height: u32,
+        faults: Faults,
+        output: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let pty = openpty(None, None)?;
+        // Safety: openpty returned new descriptors, the files close them
+        let (master, slave) =
+            unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+
+        let mut termios = tcgetattr(slave.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+        let path = ttyname(slave.as_raw_fd())?;
+
+        if let Some(output) = &output {
+            fs::create_dir_all(output)?;
+        }
+
+        let firmware = Arc::new(Mutex::new(Firmware::new(width, height, faults)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let firmware = Arc::clone(&firmware);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                if let Err(error) = run(master, slave, &firmware, &stop, output.as_deref()) {
+                    println!("Emulator stopped: {}", error);
+                }
+            })
+        };
+
+        Ok(Self {
+            path,
+            firmware,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Path of the serial port to give to the driver
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The emulated firmware, to inject faults or look at the shown frames
+    pub fn firmware(&self) -> MutexGuard<'_, Firmware> {
+        self.firmware.lock().expect("Firmware lock poisoned")
+    }
+
+    /// Whether the pty is still open
+    pub fn is_connected(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Wait until `count` frames are shown, returns false on timeout
+    pub fn wait_for_frames(&self, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.firmware().frames.len() < count {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Serve the pty until stopped or the firmware disconnects, the pty is closed on return.
+/// The slave side is kept open so the pty survives the driver closing and reopening it.
+fn run(
+    mut master: File,
+    _slave: File,
+    firmware: &Mutex<Firmware>,
+    stop: &AtomicBool,
+    output: Option<&Path>,
+) -> io::Result<()> {
+    let mut buffer = [0; 4096];
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, POLL_INTERVAL_MS)?;
+        let readable = fds[0]
+            .revents()
+            .is_some_and(|events| events.contains(PollFlags::POLLIN));
+
+        let mut firmware = firmware.lock().expect("Firmware lock poisoned");
+        let frames = firmware.frames.len();
+
+        let mut reply = Vec::new();
+        if readable {
+            let count = master.read(&mut buffer)?;
+            reply = firmware.receive(&buffer[..count], Instant::now());
+        }
+        reply.extend(firmware.poll(Instant::now()));
+
+        if let Some(output) = output {
+            for (index, frame) in firmware.frames.iter().enumerate().skip(frames) {
+                let path = output.join(format!("frame-{:04}.png", index));
+                frame.image.save(&path).map_err(io::Error::other)?;
+            }
+        }
+
+        if !reply.is_empty() {
+            master.write_all(&reply)?;
+            master.flush()?;
+        }
+        if firmware.disconnected() {
+            // Give the driver a moment to read the reply, it is lost when the pty closes
+            thread::sleep(Duration::from_millis(100));
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::{path::PathBuf, process::exit, str::FromStr, thread, time::Duration};
+
+use tag_driver_emulator::{Emulator, Faults};
+
+const USAGE: &str = "Usage: tag_driver_emulator [options]
+
+  --width <px>             panel width, defaults to 250
+  --height <px>            panel height, defaults to 122
+  --output <dir>           save every shown frame as png in <dir>
+  --text-only              act like old firmware without AT+VER and AT+BIMG
+  --refresh-time <ms>      stay busy after AT+SHOW
+  --busy <count>           answer BUSY to the first commands
+  --silent <count>         leave the first commands unanswered
+  --crc-errors <count>     fail the checksum of the first image transfers
+  --drop-bytes <count>     lose bytes of the first image transfer
+  --disconnect-after <n>   close the port after showing n frames";
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str) -> T {
+    match args.next().map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        _ => {
+            println!("Invalid value for {}\n\n{}", name, USAGE);
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut width = 250;
+    let mut height = 122;
+    let mut output = None;
+    let mut faults = Faults::default();
+    let mut framed = true;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => width = value(&mut args, &arg),
+            "--height" => height = value(&mut args, &arg),
+            "--output" => output = Some(value::<PathBuf>(&mut args, &arg)),
+            "--text-only" => framed = false,
+            "--refresh-time" => faults.refresh_time = Duration::from_millis(value(&mut args, &arg)),
+            "--busy" => faults.busy_replies = value(&mut args, &arg),
+            "--silent" => faults.silent_replies = value(&mut args, &arg),
+            "--crc-errors" => faults.crc_errors = value(&mut args, &arg),
+            "--drop-bytes" => faults.dropped_bytes = value(&mut args, &arg),
+            "--disconnect-after" => faults.disconnect_after = Some(value(&mut args, &arg)),
+            _ => {
+                println!("{}", USAGE);
+                exit(1);
+            }
+        }
+    }
+
+    let emulator = match Emulator::start(width, height, faults, output) {
+        Ok(emulator) => emulator,
+        Err(error) => {
+            println!("Could not open a pty: {}", error);
+            exit(1);
+        }
+    };
+    emulator.firmware().framed = framed;
+    println!(
+        "Emulating a {}x{} panel on {}",
+        width,
+        height,
+        emulator.path().display()
+    );
+
+    let mut shown = 0;
+    while emulator.is_connected() {
+        thread::sleep(Duration::from_millis(100));
+        let firmware = emulator.firmware();
+        for frame in &firmware.frames[shown..] {
+            println!(
+                "Frame {} ({} refresh)",
+                shown,
+                if frame.full_refresh { "full" } else { "fast" }
+            );
+            shown += 1;
+        }
+    }
+    println!("Disconnected");
+}
@@ -0,0 +1,70 @@
+use image::{Rgb, RgbImage};
+
+/// The framebuffer of the emulated controller.
+///
+/// The image data uses the layout of the driver: column by column,
+/// 8 vertical pixels per byte with the top pixel in the highest bit.
+#[derive(Clone, Debug)]
+pub struct Panel {
+    pub width: u32,
+    pub height: u32,
+    buffer_height: u32,
+    black: Vec<u8>,
+    red: Vec<u8>,
+}
+
+impl Panel {
+    pub fn new(width: u32, height: u32) -> Self {
+        let buffer_height = height.div_ceil(8) * 8;
+        let size = (width * buffer_height / 8) as usize;
+        Self {
+            width,
+            height,
+            buffer_height,
+            black: vec![0; size],
+            red: vec![0; size],
+        }
+    }
+
+    /// Whether an `AT+IMG` area fits on the panel
+    pub fn fits(&self, x: u32, y: u32, width: u32, height: u32) -> bool {
+        y.is_multiple_of(8)
+            && height.is_multiple_of(8)
+            && x + width <= self.width
+            && y + height <= self.buffer_height
+    }
+
+    /// Copy transferred image data into an area, with red the red plane follows the black one
+    pub fn write(&mut self, data: &[u8], x: u32, y: u32, width: u32, height: u32, with_red: bool) {
+        let plane_size = (width * height / 8) as usize;
+        let (black, red) = data.split_at(plane_size);
+        let bytes = (height / 8) as usize;
+
+        for column in 0..width {
+            let source = (column * height / 8) as usize;
+            let target = ((x + column) * self.buffer_height / 8 + y / 8) as usize;
+
+            self.black[target..target + bytes].copy_from_slice(&black[source..source + bytes]);
+            if with_red {
+                self.red[target..target + bytes].copy_from_slice(&red[source..source + bytes]);
+            } else {
+                self.red[target..target + bytes].fill(0);
+            }
+        }
+    }
+
+    /// Render the panel like it looks on paper
+    pub fn to_rgb(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let index = (x * self.buffer_height / 8 + y / 8) as usize;
+            let bit = 0b1000_0000 >> (y % 8);
+            if self.red[index] & bit != 0 {
+                Rgb([255, 0, 0])
+            } else if self.black[index] & bit != 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        })
+    }
+}
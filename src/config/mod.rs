@@ -0,0 +1,671 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    dbus::notifications::NotificationsConfig,
+    discovery::{DeviceFilter, UsbSerialDevice},
+    display::{
+        bwr_display::BWRDisplay, components::layout::ComponentConfig, DisplayFlip, DisplayRotation,
+    },
+    eink::{backends::BackendConfig, ghosting::GhostingConfig},
+    library::LibraryConfig,
+    state::StateConfig,
+};
+
+pub mod watch;
+
+const CONFIG_DIR: &str = "tagdriver";
+const CONFIG_FILE: &str = "config.toml";
+const DEFAULT_BAUD: u32 = 912600;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read config file {0}: {1}")]
+    ReadError(PathBuf, #[source] io::Error),
+    #[error("Could not parse config file {0}: {1}")]
+    ParseError(PathBuf, #[source] toml::de::Error),
+    #[error("No displays configured, add at least one [[display]] section")]
+    NoDisplays,
+    #[error("Display {index}: {message}")]
+    InvalidDisplay { index: usize, message: String },
+    #[error("State key '{key}': {message}")]
+    InvalidState { key: String, message: String },
+}
+
+/// How a display is refreshed when its content changes
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RefreshPolicy {
+    #[default]
+    Full,
+    Fast,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// Fixed serial port of the driver board, i.e. /dev/serial/by-id/...
+    pub port: Option<String>,
+    /// USB interface of the display on a discovered driver board, instead of a fixed `port`
+    pub interface: Option<u8>,
+    /// Serial number of the board, when more than one is connected
+    pub serial: Option<String>,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    /// Width after rotation, as seen by the components
+    pub width: u32,
+    /// Height after rotation, as seen by the components
+    pub height: u32,
+    #[serde(default)]
+    pub rotation: DisplayRotation,
+    #[serde(default)]
+    pub flip: DisplayFlip,
+    #[serde(default)]
+    pub refresh: RefreshPolicy,
+    /// The panel can show red, without it red is drawn black
+    #[serde(default)]
+    pub red: bool,
+    /// When fast refreshes are followed by a full refresh or a clean cycle
+    #[serde(default)]
+    pub ghosting: GhostingConfig,
+    /// Where the frames go, defaults to the driver board
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Components drawn on this display
+    #[serde(rename = "component", default)]
+    pub components: Vec<ComponentConfig>,
+}
+
+fn default_baud() -> u32 {
+    DEFAULT_BAUD
+}
+
+impl DisplayConfig {
+    /// Size of the panel as the controller sees it, before rotation
+    pub fn panel_size(&self) -> (u32, u32) {
+        if self.rotation.is_portrait() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    pub fn build_display(&self) -> BWRDisplay {
+        BWRDisplay::new(self.width, self.height, self.rotation, self.flip)
+    }
+
+    /// The configured port, or the port of the matching interface in the discovered devices
+    pub fn resolve_port(
+        &self,
+        filter: &DeviceFilter,
+        devices: &[UsbSerialDevice],
+    ) -> Option<String> {
+        if let Some(port) = &self.port {
+            return Some(port.clone());
+        }
+        devices
+            .iter()
+            .filter(|device| filter.matches(device))
+            .find(|device| {
+                Some(device.interface) == self.interface
+                    && (self.serial.is_none() || device.serial == self.serial)
+            })
+            .map(|device| device.port.clone())
+    }
+
+    /// Short description of where the display is connected, for logging
+    pub fn location(&self) -> String {
+        if self.backend != BackendConfig::Uart {
+            return self.backend.to_string();
+        }
+        match (&self.port, self.interface, &self.serial) {
+            (Some(port), _, _) => port.clone(),
+            (None, Some(interface), Some(serial)) => {
+                format!("board {} interface {}", serial, interface)
+            }
+            (None, Some(interface), None) => format!("interface {}", interface),
+            (None, None, _) => "nowhere".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "display", default)]
+    pub displays: Vec<DisplayConfig>,
+    /// State keys on top of the ones the driver publishes itself
+    #[serde(default)]
+    pub state: HashMap<String, StateConfig>,
+    /// Which USB devices are driver boards, for displays configured by `interface`
+    #[serde(default)]
+    pub discovery: DeviceFilter,
+    /// Notification daemon that feeds the notification:* keys
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    /// Directory of the image library
+    #[serde(default)]
+    pub library: LibraryConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::ReadError(path.to_owned(), e))?;
+
+        let config: Config =
+            toml::from_str(&contents).map_err(|e| ConfigError::ParseError(path.to_owned(), e))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.displays.is_empty() {
+            return Err(ConfigError::NoDisplays);
+        }
+        if self.displays.len() > u8::MAX as usize {
+            return Err(ConfigError::InvalidDisplay {
+                index: self.displays.len() - 1,
+                message: format!("at most {} displays are supported", u8::MAX),
+            });
+        }
+
+        let mut locations = HashSet::new();
+        // SDL has one event loop per process, so there can only be one window
+        let mut simulators = 0;
+        for (index, display) in self.displays.iter().enumerate() {
+            let invalid = |message: String| ConfigError::InvalidDisplay { index, message };
+
+            match (&display.port, display.interface) {
+                // Other backends do not use the port
+                _ if display.backend != BackendConfig::Uart => {}
+                (Some(_), Some(_)) => {
+                    return Err(invalid(
+                        "set either a port or an interface, not both".to_string(),
+                    ))
+                }
+                (None, None) => return Err(invalid("set a port or an interface".to_string())),
+                (Some(port), None) if port.trim().is_empty() => {
+                    return Err(invalid("port can not be empty".to_string()))
+                }
+                (Some(_), None) if display.serial.is_some() => {
+                    return Err(invalid(
+                        "serial can only be used together with an interface".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+            if matches!(display.backend, BackendConfig::Simulator { .. }) {
+                simulators += 1;
+                if simulators > 1 {
+                    return Err(invalid(
+                        "only one display can use the simulator".to_string(),
+                    ));
+                }
+            } else if !locations.insert(display.location()) {
+                return Err(invalid(format!(
+                    "{} is used by another display",
+                    display.location()
+                )));
+            }
+            if display.baud == 0 {
+                return Err(invalid("baud must be greater than 0".to_string()));
+            }
+            if display.width == 0 || display.height == 0 {
+                return Err(invalid(format!(
+                    "size {}x{} is invalid, width and height must be greater than 0",
+                    display.width, display.height
+                )));
+            }
+        }
+
+        for (key, declaration) in self.state.iter() {
+            let invalid = |message: String| ConfigError::InvalidState {
+                key: key.clone(),
+                message,
+            };
+
+            if key.trim().is_empty() {
+                return Err(invalid("key can not be empty".to_string()));
+            }
+            declaration.validate().map_err(invalid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the config path from `--config <path>`, or fall back to the XDG config dir
+pub fn config_path(args: &[String]) -> PathBuf {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            if let Some(path) = args.next() {
+                return PathBuf::from(path);
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return PathBuf::from(path);
+        }
+    }
+
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    config_home.join(CONFIG_DIR).join(CONFIG_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dbus::BusType,
+        display::components::{
+            layout::LayoutError, make_ui_components, pushed_image::PushedImages,
+        },
+        library::ImageLibrary,
+        state::{
+            build_state_map,
+            value::{Filter, FilterMultiply, StateValueType},
+            StateSource,
+        },
+    };
+
+    fn parse(contents: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(contents)
+            .map_err(|e| ConfigError::ParseError(PathBuf::from("test.toml"), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn parse_displays() {
+        let config = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+            refresh = "fast"
+
+            [[display]]
+            port = "/dev/ttyACM1"
+            baud = 115200
+            width = 400
+            height = 300
+            rotation = 270
+            flip = "horizontal"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.displays.len(), 2);
+        assert_eq!(config.displays[0].baud, DEFAULT_BAUD);
+        assert_eq!(config.displays[0].rotation, DisplayRotation::Zero);
+        assert_eq!(config.displays[0].refresh, RefreshPolicy::Fast);
+        assert_eq!(config.displays[0].panel_size(), (250, 122));
+        assert_eq!(config.displays[1].rotation, DisplayRotation::Rotate270);
+        assert_eq!(config.displays[1].flip, DisplayFlip::Horizontal);
+        assert_eq!(config.displays[1].panel_size(), (300, 400));
+    }
+
+    #[test]
+    fn reject_invalid_rotation() {
+        let result = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+            rotation = 45
+            "#,
+        );
+        assert!(matches!(result, Err(ConfigError::ParseError(..))));
+    }
+
+    #[test]
+    fn reject_duplicate_port() {
+        let result = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidDisplay { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_state() {
+        let config = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+
+            [state."player:volume"]
+            source = { type = "dbus", bus = "session", dest = "org.mpris.MediaPlayer2.playerctld", path = "/org/mpris/MediaPlayer2", interface = "org.mpris.MediaPlayer2.Player", property = "Volume" }
+            filters = [{ type = "multiply", factor = 100.0 }]
+
+            [state."custom:counter"]
+            default = { U64 = 3 }
+            "#,
+        )
+        .unwrap();
+
+        let volume = config.state.get("player:volume").unwrap();
+        let StateSource::DBus(source) = &volume.source else {
+            panic!("Expected a dbus source");
+        };
+        assert_eq!(source.bus, BusType::Session);
+        assert_eq!(source.property, "Volume");
+        assert_eq!(
+            volume.filters,
+            vec![Filter::Multiply(FilterMultiply { factor: 100.0 })]
+        );
+
+        let counter = config.state.get("custom:counter").unwrap();
+        assert_eq!(counter.source, StateSource::Manual);
+        assert_eq!(counter.default, Some(StateValueType::U64(3)));
+    }
+
+    #[test]
+    fn reject_relative_dbus_path() {
+        let result = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+
+            [state."battery:level"]
+            source = { type = "dbus", bus = "system", dest = "org.freedesktop.UPower", path = "battery", interface = "org.freedesktop.UPower.Device", property = "Percentage" }
+            "#,
+        );
+        assert!(matches!(result, Err(ConfigError::InvalidState { .. })));
+    }
+
+    #[test]
+    fn parse_components() {
+        let config = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+
+            [[display.component]]
+            type = "state_item"
+            name = "Battery Icon"
+            icon = "battery"
+            z_index = 30
+
+            [[display.component]]
+            type = "workspace_indicator"
+            name = "Workspaces"
+            area = { x = 0, y = 66, width = 250, height = 56 }
+            "#,
+        )
+        .unwrap();
+
+        let components = &config.displays[0].components;
+        assert_eq!(components.len(), 2);
+        assert_eq!(
+            components[0].keys(),
+            vec!["battery:level".to_string(), "battery:state".to_string()]
+        );
+        assert!(matches!(
+            components[1],
+            ComponentConfig::WorkspaceIndicator { z_index: None, .. }
+        ));
+    }
+
+    #[test]
+    fn reject_unknown_component() {
+        for component in [
+            r#"type = "clock""#,
+            r#"type = "simple_item"
+            icon = "rocket""#,
+            r#"type = "bar_dialog"
+            key = "player:volume"
+            icon = "volume"
+            z_index = 3"#,
+        ] {
+            let result = parse(&format!(
+                r#"
+                [[display]]
+                port = "/dev/ttyACM0"
+                width = 250
+                height = 122
+
+                [[display.component]]
+                name = "test"
+                {}
+                "#,
+                component
+            ));
+            assert!(
+                matches!(result, Err(ConfigError::ParseError(..))),
+                "{} should not parse",
+                component
+            );
+        }
+    }
+
+    #[test]
+    fn reject_unknown_key() {
+        let config = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            width = 250
+            height = 122
+
+            [[display.component]]
+            type = "bar_dialog"
+            name = "volume"
+            key = "player:volume"
+            icon = "volume"
+            "#,
+        )
+        .unwrap();
+
+        let state = build_state_map(&config.state, config.displays.len());
+        assert!(matches!(
+            make_ui_components(
+                &config.displays,
+                &state,
+                &PushedImages::default(),
+                &ImageLibrary::new(PathBuf::new()),
+            ),
+            Err(LayoutError::UnknownKey { .. })
+        ));
+    }
+
+    #[test]
+    fn example_config() {
+        let config: Config =
+            toml::from_str(include_str!("../../resources/config.example.toml")).unwrap();
+        config.validate().unwrap();
+
+        let state = build_state_map(&config.state, config.displays.len());
+        make_ui_components(
+            &config.displays,
+            &state,
+            &PushedImages::default(),
+            &ImageLibrary::new(PathBuf::new()),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn resolve_interface() {
+        let config = parse(
+            r#"
+            [[display]]
+            interface = 2
+            width = 250
+            height = 122
+
+            [[display]]
+            interface = 4
+            serial = "B"
+            width = 250
+            height = 122
+
+            [[display]]
+            port = "/dev/ttyUSB0"
+            width = 250
+            height = 122
+            "#,
+        )
+        .unwrap();
+
+        let device = |port: &str, serial: &str, interface: u8| UsbSerialDevice {
+            port: port.to_string(),
+            vendor_id: 0x2e8a,
+            product_id: 0x000a,
+            manufacturer: Some("RemijnPi".to_string()),
+            product: Some("Eink Driver".to_string()),
+            serial: Some(serial.to_string()),
+            interface,
+        };
+        let devices = vec![
+            device("/dev/ttyACM0", "A", 2),
+            device("/dev/ttyACM1", "A", 4),
+            device("/dev/ttyACM2", "B", 2),
+            device("/dev/ttyACM3", "B", 4),
+        ];
+
+        let resolve =
+            |index: usize| config.displays[index].resolve_port(&config.discovery, &devices);
+        assert_eq!(resolve(0), Some("/dev/ttyACM0".to_string()));
+        assert_eq!(resolve(1), Some("/dev/ttyACM3".to_string()));
+        assert_eq!(resolve(2), Some("/dev/ttyUSB0".to_string()));
+        assert_eq!(
+            config.displays[1].resolve_port(&config.discovery, &devices[..2]),
+            None
+        );
+    }
+
+    #[test]
+    fn reject_port_and_interface() {
+        let result = parse(
+            r#"
+            [[display]]
+            port = "/dev/ttyACM0"
+            interface = 0
+            width = 250
+            height = 122
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidDisplay { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_backends() {
+        let config = parse(
+            r#"
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "png", path = "/tmp/display.png" }
+
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "simulator" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.displays[0].backend,
+            BackendConfig::Png {
+                path: PathBuf::from("/tmp/display.png")
+            }
+        );
+        assert_eq!(
+            config.displays[1].backend,
+            BackendConfig::Simulator { scale: 2 }
+        );
+        assert_eq!(config.displays[0].location(), "png /tmp/display.png");
+
+        // A png backend does not need a port, but it cannot share the file
+        let result = parse(
+            r#"
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "png", path = "/tmp/display.png" }
+
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "png", path = "/tmp/display.png" }
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidDisplay { index: 1, .. })
+        ));
+
+        // There is one SDL event loop, it can not serve a second window
+        let result = parse(
+            r#"
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "simulator" }
+
+            [[display]]
+            width = 250
+            height = 122
+            backend = { type = "simulator", scale = 3 }
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidDisplay { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn reject_empty() {
+        assert!(matches!(parse(""), Err(ConfigError::NoDisplays)));
+    }
+
+    #[test]
+    fn config_path_from_args() {
+        let args = vec![
+            "tag_driver".to_string(),
+            "--config".to_string(),
+            "/tmp/tags.toml".to_string(),
+        ];
+        assert_eq!(config_path(&args), PathBuf::from("/tmp/tags.toml"));
+
+        let args = vec!["tag_driver".to_string(), "--config=/tmp/a.toml".to_string()];
+        assert_eq!(config_path(&args), PathBuf::from("/tmp/a.toml"));
+    }
+}
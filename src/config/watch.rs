@@ -0,0 +1,52 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use futures_util::StreamExt;
+use inotify::{Inotify, WatchMask};
+use tokio::{sync::mpsc::Sender, time::timeout};
+
+use crate::{control::ControlMessage, log};
+
+/// Editors write a file in multiple steps, wait for them to settle before reloading
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Watch the config file and request a reload when it changes.
+///
+/// The parent directory is watched instead of the file itself, so editors that
+/// replace the file (write to a temp file and rename) are picked up as well.
+pub async fn watch_config(path: PathBuf, control_tx: Sender<ControlMessage>) -> io::Result<()> {
+    let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        ));
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir.to_path_buf()
+    };
+
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        &dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+
+    println!("{} Watching config {}", log::CONFIG, path.display());
+
+    let mut events = inotify.into_event_stream([0; 1024])?;
+    while let Some(event) = events.next().await {
+        if event?.name.as_deref() != Some(file_name) {
+            continue;
+        }
+
+        // Drain the events of the rest of the write
+        while let Ok(Some(_)) = timeout(SETTLE_TIME, events.next()).await {}
+
+        println!("{} Config file changed", log::CONFIG);
+        if control_tx.send(ControlMessage::Reload).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
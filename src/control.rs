@@ -0,0 +1,12 @@
+/// Requests to the main loop, from the stdin, DBus and watcher threads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Load the config file again and rebuild the layout
+    Reload,
+    /// Serial ports were added or removed, scan for driver boards again
+    DevicesChanged,
+    /// Draw a display again, with a full refresh or the fast refresh of its refresh policy
+    Refresh { display: u8, full: bool },
+    /// Set the LED of the driver board of a display
+    SetLed { display: u8, color: u8 },
+}
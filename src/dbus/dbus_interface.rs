@@ -1,133 +1,430 @@
 use dbus::{
     arg::RefArg,
-    blocking::{
-        stdintf::{self, org_freedesktop_dbus::Properties},
-        Connection,
+    channel::{MatchingReceiver, Sender as _},
+    message::{MatchRule, SignalArgs},
+    nonblock::{
+        self,
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        MsgMatch, SyncConnection,
     },
-    channel::MatchingReceiver,
     Message,
 };
 
-use dbus_crossroads::{Context, Crossroads};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder, IfaceToken, MethodErr};
+use dbus_tokio::connection;
+use embedded_graphics::geometry::Size;
+use futures_util::future::join_all;
+use image::RgbaImage;
 use itertools::Itertools;
-use networkmanager::{
-    devices::{Any, Device, Wireless},
-    NetworkManager,
-};
 use tokio::{
     sync::{
-        mpsc::{self, Sender},
-        Mutex,
+        mpsc::{self, Sender, UnboundedSender},
+        watch, Mutex,
     },
-    time::Instant,
+    task::JoinSet,
+    time::sleep,
 };
 
-use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
-
-use stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use std::{
+    iter,
+    sync::{Arc, Mutex as SyncMutex},
+    time::Duration,
+};
 
 use crate::{
-    dbus::networkmanager::NMDeviceState,
+    control::ControlMessage,
+    display::components::pushed_image::{decode_image, unpack_image, PushImageError, PushedImages},
+    library::{validate_id, ImageLibrary, LibraryError},
     log,
-    state::{app::ApplicationState, value::NetworkState, value::StateValueType},
+    state::{
+        app::{ApplicationState, ApplicationStateError},
+        display_key,
+        value::StateValueType,
+        DISPLAY_IMAGE_KEY, DISPLAY_PUSHED_KEY,
+    },
 };
 
-use super::{BusType, DBusPropertyAdress, DBusProxyAdress, DBusUpdate};
+use super::{
+    mpris::{run_mpris, MEDIA_KEYS},
+    networkmanager::{run_networkmanager, NETWORK_KEYS},
+    notifications::{run_notifications, NOTIFICATION_KEYS},
+    supervisor::{supervise, SourceResult},
+    BusType, DBusPropertyAdress, DBusProxyAdress, DBusUpdate,
+};
 
-fn update_data_nm(
-    system_conn: &Connection,
-    state: &mut ApplicationState,
-) -> Result<bool, networkmanager::Error> {
-    let nm = &NetworkManager::new(system_conn);
+const INTERFACE: &str = "io.remijn.tagdriver";
+const DISPLAY_INTERFACE: &str = "io.remijn.tagdriver.Display";
 
-    let mut values: HashMap<&str, Option<StateValueType>> = HashMap::new();
+/// Get the initial values of the DBus properties in the state and listen for their changes.
+/// The state is not locked while waiting for the replies, they are applied when all are in.
+/// Returns the matches, so the listeners can be removed when the state is replaced.
+async fn subscribe_properties(
+    bus: &BusType,
+    connection: &Arc<SyncConnection>,
+    state: &Mutex<ApplicationState>,
+    tx: &UnboundedSender<Vec<DBusUpdate>>,
+) -> Vec<MsgMatch> {
+    let mut matches = Vec::new();
 
-    // Get wifi device
-    let Device::WiFi(wifi) = nm.get_device_by_ip_iface("wlp1s0").expect("") else {
-        panic!();
-    };
-    // Get wifi state
-    let nm_wifi_state = NMDeviceState::from_int(wifi.state()?);
-    let wifi_state = NetworkState::from(nm_wifi_state);
-
-    // Update the strength
-    if wifi_state == NetworkState::Connected {
-        let ap = wifi.active_access_point();
-
-        if ap.is_err() {
-            values.insert("wifi:strength", Some(StateValueType::F64(0.0)));
-        } else {
-            values.insert(
-                "wifi:strength",
-                Some(StateValueType::F64(ap?.strength()? as f64)),
-            );
+    let mut proxies: Vec<DBusProxyAdress> = Vec::new();
+    let mut properties: Vec<DBusPropertyAdress> = Vec::new();
+
+    // Get the properties we monitor from the ApplicationState
+    for state_value in state.lock().await.map.values() {
+        if let Some(prop) = state_value
+            .dbus_property
+            .as_ref()
+            .filter(|prop| prop.proxy.bus == *bus)
+        {
+            properties.push(prop.clone());
+            if !proxies.contains(&prop.proxy) {
+                proxies.push(prop.proxy.clone());
+            }
         }
-    } else {
-        values.insert("wifi:strength", None);
     }
 
-    values.insert("wifi:state", Some(StateValueType::NetworkState(wifi_state)));
+    // Get initial values and start listening for updates
+    let mut initial_values = Vec::new();
+    for proxy in proxies {
+        println!("{} Init Proxy {} {}", log::DBUS, proxy.dest, proxy.path);
+
+        let conn_proxy = nonblock::Proxy::new(
+            proxy.dest.as_str(),
+            proxy.path.as_str(),
+            Duration::from_secs(2),
+            connection.clone(),
+        );
 
-    // Get eth device
-    let Device::Ethernet(eth) = nm.get_device_by_ip_iface("enp2s0").expect("") else {
-        panic!();
-    };
+        let proxy_properties: Vec<&DBusPropertyAdress> = properties
+            .iter()
+            .filter(|property| property.proxy == proxy)
+            .collect();
+        let results = join_all(proxy_properties.iter().map(|property| {
+            conn_proxy.get::<Box<dyn RefArg>>(&property.interface, &property.property)
+        }))
+        .await;
+        for (property, result) in proxy_properties.into_iter().zip(results) {
+            match result {
+                Ok(value) => initial_values.push((property.clone(), value)),
+                Err(_) => println!("{} Unable to get property {}", log::ERROR, property),
+            }
+        }
+
+        let props = properties.clone();
+        let match_proxy = proxy.clone();
+
+        let clone_tx = tx.clone();
+
+        let rule = PropertiesPropertiesChanged::match_rule(
+            Some(&conn_proxy.destination),
+            Some(&conn_proxy.path),
+        )
+        .static_clone();
+        let msg_match = match connection.add_match(rule).await {
+            Ok(msg_match) => msg_match,
+            Err(error) => {
+                println!(
+                    "{} Could not listen to {} {}: {}",
+                    log::ERROR,
+                    proxy.dest,
+                    proxy.path,
+                    error
+                );
+                continue;
+            }
+        };
+        let msg_match = msg_match.cb(move |_: Message, h: PropertiesPropertiesChanged| {
+            let iface = h.interface_name;
 
-    let nm_eth_state = NMDeviceState::from_int(eth.state()?);
-    let eth_state = NetworkState::from(nm_eth_state);
-    values.insert("eth:state", Some(StateValueType::NetworkState(eth_state)));
+            let mut updates: Vec<DBusUpdate> = Vec::new();
 
-    let updated = state
-        .update_multiple(values.clone())
-        .expect("Error updating state");
+            for (key, value) in h.changed_properties {
+                for prop in props.iter() {
+                    if prop.proxy == match_proxy
+                        && prop.interface == iface.as_str()
+                        && prop.property == key.as_str()
+                    {
+                        updates.push(DBusUpdate::PropertyUpdate((
+                            prop.clone(),
+                            Some(value.0.box_clone()),
+                        )));
+                    }
+                }
+            }
+            if !updates.is_empty() {
+                println!("{} {} Values {:?} ", log::DBUS, iface, updates);
+                clone_tx.send(updates).ok();
+            }
+            true
+        });
+        matches.push(msg_match);
+    }
+
+    let mut state_lock = state.lock().await;
+    for (property, value) in initial_values {
+        if let Err(error) = state_lock.update_dbus(&property, &value) {
+            println!("{} Could not set initial value: {}", log::ERROR, error);
+        }
+    }
 
-    Ok(updated)
+    matches
 }
 
-pub async fn run_dbus_thread(
-    update_tx: Sender<()>,
+/// The state of a display, served as an object at `/display/<index>`
+#[derive(Clone)]
+struct DisplayObject {
+    index: usize,
     state: Arc<Mutex<ApplicationState>>,
-) -> Result<(), Box<dyn Error>> {
-    let session_conn = Connection::new_session().expect("Error connecting to Session DBus");
+}
 
-    let system_conn: Connection =
-        Connection::new_system().expect("Error connecting to System DBus");
+fn display_path(display: usize) -> dbus::Path<'static> {
+    format!("/display/{}", display).into()
+}
 
-    let (tx, mut rx) = mpsc::channel::<Vec<DBusUpdate>>(20);
+/// Number of displays, from their status keys in the state
+fn display_count(state: &ApplicationState) -> usize {
+    (0..)
+        .take_while(|display| state.map.contains_key(&display_key(*display, "status")))
+        .count()
+}
 
-    // let values: Arc<Mutex<DBusValueMap>> = Arc::new(Mutex::new(HashMap::new()));
+/// The io.remijn.tagdriver objects on the session bus
+struct ControlApi {
+    cr: Arc<SyncMutex<Crossroads>>,
+    display_iface: IfaceToken<DisplayObject>,
+    displays: usize,
+    /// Values sent with the last StateChanged signals
+    values: serde_json::Map<String, serde_json::Value>,
+}
 
-    // Start the DBus Server
+impl ControlApi {
+    /// Serve an object for every display in the state, and forget the values that were sent
+    fn rebind(&mut self, state_arc: &Arc<Mutex<ApplicationState>>, state: &ApplicationState) {
+        let mut cr = self.cr.lock().unwrap();
+        for display in 0..self.displays {
+            cr.remove::<DisplayObject>(&display_path(display));
+        }
+        self.displays = display_count(state);
+        for index in 0..self.displays {
+            let object = DisplayObject {
+                index,
+                state: state_arc.clone(),
+            };
+            cr.insert(display_path(index), &[self.display_iface], object);
+        }
+        self.values = state.to_json();
+    }
 
-    let mut cr = Crossroads::new();
+    /// Send StateChanged for every key with another value than the last time
+    fn state_changed(
+        &mut self,
+        conn: &SyncConnection,
+        state: &ApplicationState,
+    ) -> Result<(), &'static str> {
+        let values = state.to_json();
+        let removed = self
+            .values
+            .keys()
+            .filter(|key| !values.contains_key(*key))
+            .map(|key| (key, &serde_json::Value::Null));
+        for (key, value) in values
+            .iter()
+            .filter(|(key, value)| self.values.get(*key) != Some(value))
+            .chain(removed)
+        {
+            let signal = Message::signal(&"/".into(), &INTERFACE.into(), &"StateChanged".into())
+                .append2(key, value.to_string());
+            conn.send(signal)
+                .map_err(|()| "could not send StateChanged")?;
+        }
+        self.values = values;
+        Ok(())
+    }
+}
 
-    // Let's build a new interface, which can be used for "Hello" objects.
-    let iface_token = cr.register("io.remijn.tagdriver", |b| {
-        // This row advertises (when introspected) that we can send a HelloHappened signal.
-        // We use the single-tuple to say that we have one single argument, named "sender" of type "String".
-        // The msg_fn returns a boxed function, which when called constructs the message to be emitted.
-        // let state_changed = b.signal::<(String,), _>("StateChanged", ("json",)).msg_fn();
+/// Serve the io.remijn.tagdriver methods on the session bus, and the status of the displays
+async fn serve_methods(
+    session_conn: &Arc<SyncConnection>,
+    tx: &UnboundedSender<Vec<DBusUpdate>>,
+    control_tx: Sender<ControlMessage>,
+    state: Arc<Mutex<ApplicationState>>,
+    pushed_images: PushedImages,
+    library: ImageLibrary,
+) -> Result<ControlApi, dbus::Error> {
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((
+        session_conn.clone(),
+        Box::new(|future| {
+            tokio::spawn(future);
+        }),
+    )));
 
-        // Let's add a method to the interface. We have the method name, followed by
-        // names of input and output arguments (used for introspection). The closure then controls
-        // the types of these arguments. The last argument to the closure is a tuple of the input arguments.
+    let iface_token = cr.register(INTERFACE, |b| {
+        b.signal::<(String, String), _>("StateChanged", ("key", "json"));
 
-        let clone_tx = tx.clone();
-        b.method(
+        let method_state = state.clone();
+        let (clone_tx, show_library) = (tx.clone(), library.clone());
+        b.method_with_cr_async(
             "show",
-            ("png", "display"),
+            ("id", "display"),
             ("reply",),
-            move |_ctx: &mut Context,
-                  _state: &mut Arc<Mutex<ApplicationState>>,
-                  (png, display): (String, u32)| {
-                // And here's what happens when the method is called.
-                println!("{} SetImage called for display {}", log::DBUS, display);
-
-                clone_tx
-                    .try_send(vec![DBusUpdate::MethodShowImage(png)])
-                    .expect("Could not send");
-                let reply = format!("Drawing on display {}", display);
-                Ok((reply,))
+            move |mut ctx: Context, _cr: &mut Crossroads, (id, display): (String, u32)| {
+                let (state, tx) = (method_state.clone(), clone_tx.clone());
+                let library = show_library.clone();
+                async move {
+                    println!("{} Method show called for display {}", log::DBUS, display);
+                    let reply = if display_count(&*state.lock().await) <= display as usize {
+                        Err(MethodErr::invalid_arg("display"))
+                    } else if let Err(error) = validate_id(&id) {
+                        Err(MethodErr::invalid_arg(&error))
+                    } else if !library.contains(&id) {
+                        Err(MethodErr::failed(&LibraryError::NotFound(id)))
+                    } else {
+                        tx.send(vec![DBusUpdate::MethodShowImage(
+                            display as usize,
+                            Some(id),
+                        )])
+                        .map(|_| (format!("Drawing on display {}", display),))
+                        .map_err(|_| MethodErr::failed("The state is not available"))
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        let clone_tx = tx.clone();
+        b.method_with_cr_async(
+            "ClearImage",
+            ("display",),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (display,): (u32,)| {
+                let (state, tx) = (method_state.clone(), clone_tx.clone());
+                async move {
+                    println!(
+                        "{} Method ClearImage called for display {}",
+                        log::DBUS,
+                        display
+                    );
+                    let reply = match display_count(&*state.lock().await) > display as usize {
+                        true => tx
+                            .send(vec![DBusUpdate::MethodShowImage(display as usize, None)])
+                            .map_err(|_| MethodErr::failed("The state is not available")),
+                        false => Err(MethodErr::invalid_arg("display")),
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        let (clone_tx, images) = (tx.clone(), pushed_images.clone());
+        b.method_with_cr_async(
+            "ShowImageData",
+            ("display", "data", "mime", "timeout"),
+            (),
+            move |mut ctx: Context,
+                  _cr: &mut Crossroads,
+                  (display, data, mime, timeout): (u32, Vec<u8>, String, u32)| {
+                let (state, tx, images) = (method_state.clone(), clone_tx.clone(), images.clone());
+                async move {
+                    println!(
+                        "{} Method ShowImageData called for display {}, {} bytes of {}",
+                        log::DBUS,
+                        display,
+                        data.len(),
+                        mime
+                    );
+                    let image = move |_panel| decode_image(&data, &mime);
+                    let reply = push_image(&state, &tx, &images, display, image, timeout).await;
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        let (clone_tx, images) = (tx.clone(), pushed_images);
+        b.method_with_cr_async(
+            "ShowPackedImage",
+            ("display", "data", "width", "height", "bits", "timeout"),
+            (),
+            move |mut ctx: Context,
+                  _cr: &mut Crossroads,
+                  (display, data, width, height, bits, timeout): (
+                u32,
+                Vec<u8>,
+                u32,
+                u32,
+                u8,
+                u32,
+            )| {
+                let (state, tx, images) = (method_state.clone(), clone_tx.clone(), images.clone());
+                async move {
+                    println!(
+                        "{} Method ShowPackedImage called for display {}, {}x{} with {} bits",
+                        log::DBUS,
+                        display,
+                        width,
+                        height,
+                        bits
+                    );
+                    let image = move |panel| unpack_image(&data, width, height, bits, panel);
+                    let reply = push_image(&state, &tx, &images, display, image, timeout).await;
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let import_library = library.clone();
+        b.method_with_cr_async(
+            "ImportImage",
+            ("id", "data"),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (id, data): (String, Vec<u8>)| {
+                let library = import_library.clone();
+                async move {
+                    println!("{} Method ImportImage called for {}", log::DBUS, id);
+                    let reply = tokio::task::spawn_blocking(move || library.import(&id, &data))
+                        .await
+                        .map_err(|error| MethodErr::failed(&error))
+                        .and_then(|result| result.map_err(|error| MethodErr::invalid_arg(&error)));
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let list_library = library.clone();
+        b.method_with_cr_async(
+            "ListImages",
+            (),
+            ("ids",),
+            move |mut ctx: Context, _cr: &mut Crossroads, (): ()| {
+                let library = list_library.clone();
+                async move {
+                    let reply = tokio::task::spawn_blocking(move || library.list())
+                        .await
+                        .map_err(|error| MethodErr::failed(&error))
+                        .and_then(|result| result.map_err(|error| MethodErr::failed(&error)))
+                        .map(|ids| (ids,));
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let delete_library = library;
+        b.method_with_cr_async(
+            "DeleteImage",
+            ("id",),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (id,): (String,)| {
+                let library = delete_library.clone();
+                async move {
+                    println!("{} Method DeleteImage called for {}", log::DBUS, id);
+                    let reply = tokio::task::spawn_blocking(move || library.delete(&id))
+                        .await
+                        .map_err(|error| MethodErr::failed(&error))
+                        .and_then(|result| {
+                            result.map_err(|error| match error {
+                                LibraryError::InvalidId(_) => MethodErr::invalid_arg(&error),
+                                _ => MethodErr::failed(&error),
+                            })
+                        });
+                    ctx.reply(reply)
+                }
             },
         );
         let clone_tx = tx.clone();
@@ -138,6 +435,9 @@ pub async fn run_dbus_thread(
             move |_ctx: &mut Context,
                   _state: &mut Arc<Mutex<ApplicationState>>,
                   (active, count): (u32, u32)| {
+                if active >= count {
+                    return Err(MethodErr::invalid_arg("active"));
+                }
                 // And here's what happens when the method is called.
                 let mut workspaces = vec![false; count as usize];
                 workspaces[active as usize] = true;
@@ -156,185 +456,693 @@ pub async fn run_dbus_thread(
                 );
 
                 clone_tx
-                    .try_send(vec![DBusUpdate::MethodSetWorkspaces(active, count)])
-                    .expect("Could not send");
+                    .send(vec![DBusUpdate::MethodSetWorkspaces(active, count)])
+                    .map_err(|_| MethodErr::failed("The state is not available"))?;
                 Ok(("ok",))
             },
         );
+        let reload_tx = control_tx.clone();
+        b.method(
+            "Reload",
+            (),
+            ("reply",),
+            move |_ctx: &mut Context, _state: &mut Arc<Mutex<ApplicationState>>, (): ()| {
+                println!("{} Method Reload called", log::DBUS);
+
+                reload_tx
+                    .try_send(ControlMessage::Reload)
+                    .map_err(|_| MethodErr::failed("Busy, try again"))?;
+                Ok(("ok",))
+            },
+        );
+
+        let method_state = state.clone();
+        b.method_with_cr_async(
+            "GetState",
+            (),
+            ("json",),
+            move |mut ctx: Context, _cr: &mut Crossroads, (): ()| {
+                let state = method_state.clone();
+                async move {
+                    let json = serde_json::Value::Object(state.lock().await.to_json());
+                    ctx.reply(Ok((json.to_string(),)))
+                }
+            },
+        );
+        let method_state = state.clone();
+        b.method_with_cr_async(
+            "GetValue",
+            ("key",),
+            ("json",),
+            move |mut ctx: Context, _cr: &mut Crossroads, (key,): (String,)| {
+                let state = method_state.clone();
+                async move {
+                    let state = state.lock().await;
+                    let reply = match state.map.contains_key(&key) {
+                        true => Ok((state
+                            .get(&key)
+                            .map_or(serde_json::Value::Null, StateValueType::to_json)
+                            .to_string(),)),
+                        false => Err(MethodErr::failed(&format!("Key '{}' does not exist", key))),
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        let clone_tx = tx.clone();
+        b.method_with_cr_async(
+            "SetValue",
+            ("key", "json"),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (key, json): (String, String)| {
+                let (state, tx) = (method_state.clone(), clone_tx.clone());
+                async move {
+                    let value = serde_json::from_str(&json)
+                        .map_err(|error| error.to_string())
+                        .and_then(|json| StateValueType::from_json(&json));
+                    let reply = match (value, state.lock().await.map.get(&key)) {
+                        (Err(error), _) => Err(MethodErr::invalid_arg(&error)),
+                        (_, None) => {
+                            Err(MethodErr::failed(&format!("Key '{}' does not exist", key)))
+                        }
+                        (_, Some(current)) if current.dbus_property.is_some() => Err(
+                            MethodErr::failed(&format!("Key '{}' is read from DBus", key)),
+                        ),
+                        (Ok(value), Some(_)) => {
+                            println!("{} Method SetValue called for {}", log::DBUS, key);
+                            tx.send(vec![DBusUpdate::MethodSetValue(key, value)])
+                                .map_err(|_| MethodErr::failed("The state is not available"))
+                        }
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        b.method_with_cr_async(
+            "ListDisplays",
+            (),
+            ("displays",),
+            move |mut ctx: Context, _cr: &mut Crossroads, (): ()| {
+                let state = method_state.clone();
+                async move {
+                    let state = state.lock().await;
+                    let displays: Vec<(u32, dbus::Path<'static>, String)> =
+                        (0..display_count(&state))
+                            .map(|display| {
+                                let status = match state.get(&display_key(display, "status")) {
+                                    Some(StateValueType::String(status)) => status.clone(),
+                                    _ => String::new(),
+                                };
+                                (display as u32, display_path(display), status)
+                            })
+                            .collect();
+                    ctx.reply(Ok((displays,)))
+                }
+            },
+        );
+        let method_state = state.clone();
+        let refresh_tx = control_tx.clone();
+        b.method_with_cr_async(
+            "ForceRefresh",
+            ("display", "full"),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (display, full): (u32, bool)| {
+                let (state, control_tx) = (method_state.clone(), refresh_tx.clone());
+                async move {
+                    let reply = match display_count(&*state.lock().await) > display as usize {
+                        true => control_tx
+                            .try_send(ControlMessage::Refresh {
+                                display: display as u8,
+                                full,
+                            })
+                            .map_err(|_| MethodErr::failed("Busy, try again")),
+                        false => Err(MethodErr::invalid_arg("display")),
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
+        let method_state = state.clone();
+        let led_tx = control_tx;
+        b.method_with_cr_async(
+            "SetLed",
+            ("display", "color"),
+            (),
+            move |mut ctx: Context, _cr: &mut Crossroads, (display, color): (u32, u8)| {
+                let (state, control_tx) = (method_state.clone(), led_tx.clone());
+                async move {
+                    let reply = match display_count(&*state.lock().await) > display as usize {
+                        true => control_tx
+                            .try_send(ControlMessage::SetLed {
+                                display: display as u8,
+                                color,
+                            })
+                            .map_err(|_| MethodErr::failed("Busy, try again")),
+                        false => Err(MethodErr::invalid_arg("display")),
+                    };
+                    ctx.reply(reply)
+                }
+            },
+        );
     });
-    cr.insert("/", &[iface_token], state.clone());
+    cr.insert("/", &[iface_token], state);
 
-    session_conn.request_name("io.remijn.tagdriver", false, true, false)?;
+    // Read-only status of a display, the StateChanged signal tells when it changes
+    let display_iface = cr.register(DISPLAY_INTERFACE, |b: &mut IfaceBuilder<DisplayObject>| {
+        for (name, key) in [("Status", "status"), ("LastError", "last_error")] {
+            b.property::<String, _>(name)
+                .get_async(move |mut ctx, display| {
+                    let display = display.clone();
+                    async move {
+                        let state = display.state.lock().await;
+                        let value = match state.get(&display_key(display.index, key)) {
+                            Some(StateValueType::String(value)) => value.clone(),
+                            _ => String::new(),
+                        };
+                        ctx.reply(Ok(value))
+                    }
+                })
+                .emits_changed_false();
+        }
+        for (name, key) in [
+            ("LastFrame", "last_frame"),
+            ("FastRefreshes", "fast_refreshes"),
+            ("LastFullRefresh", "last_full_refresh"),
+            ("LastClean", "last_clean"),
+        ] {
+            b.property::<u64, _>(name)
+                .get_async(move |mut ctx, display| {
+                    let display = display.clone();
+                    async move {
+                        let state = display.state.lock().await;
+                        let value = match state.get(&display_key(display.index, key)) {
+                            Some(StateValueType::U64(value)) => *value,
+                            _ => 0,
+                        };
+                        ctx.reply(Ok(value))
+                    }
+                })
+                .emits_changed_false();
+        }
+    });
 
+    session_conn
+        .request_name(INTERFACE, false, true, false)
+        .await?;
+
+    let cr = Arc::new(SyncMutex::new(cr));
+    let receive_cr = cr.clone();
     session_conn.start_receive(
-        dbus::message::MatchRule::new_method_call(),
+        MatchRule::new_method_call(),
         Box::new(move |msg, conn| {
-            cr.handle_message(msg, conn).unwrap();
+            receive_cr.lock().unwrap().handle_message(msg, conn).ok();
             true
         }),
     );
+    Ok(ControlApi {
+        cr,
+        display_iface,
+        displays: 0,
+        values: Default::default(),
+    })
+}
 
-    let mut state_lock: tokio::sync::MutexGuard<'_, ApplicationState> = state.lock().await;
+/// Keep the pushed image and show it on the display, until the timeout in milliseconds
+/// passes when it is not 0. The image is decoded for the size of the display off the runtime.
+async fn push_image(
+    state: &Mutex<ApplicationState>,
+    tx: &UnboundedSender<Vec<DBusUpdate>>,
+    images: &PushedImages,
+    display: u32,
+    image: impl FnOnce(Size) -> Result<RgbaImage, PushImageError> + Send + 'static,
+    timeout: u32,
+) -> Result<(), MethodErr> {
+    let display = display as usize;
+    let panel = match images.size(display) {
+        Some(size) if display_count(&*state.lock().await) > display => size,
+        _ => return Err(MethodErr::invalid_arg("display")),
+    };
+    let image = tokio::task::spawn_blocking(move || image(panel))
+        .await
+        .map_err(|error| MethodErr::failed(&error))?
+        .map_err(|error| MethodErr::invalid_arg(&error))?;
 
-    let mut proxies: Vec<&DBusProxyAdress> = Vec::new();
-    let mut properties: Vec<&DBusPropertyAdress> = Vec::new();
+    let id = images.push(display, image);
+    tx.send(vec![DBusUpdate::MethodPushImage(display, id)])
+        .map_err(|_| MethodErr::failed("The state is not available"))?;
+    if timeout > 0 {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(timeout as u64)).await;
+            tx.send(vec![DBusUpdate::PushedImageExpired(display, id)])
+                .ok();
+        });
+    }
+    Ok(())
+}
 
-    // Get the properties we monitor from the ApplicationState
-    for key in state_lock.map.keys() {
-        if let Some(state_value) = state_lock.map.get(key) {
-            if let Some(prop) = state_value.dbus_property {
-                properties.push(prop);
-                proxies.push(prop.proxy);
+/// Apply an update from a DBus callback to the state, returns if the state changed
+fn apply_update(
+    state: &mut ApplicationState,
+    update: DBusUpdate,
+) -> Result<bool, ApplicationStateError> {
+    match update {
+        DBusUpdate::PropertyUpdate((key, Some(value))) => {
+            state.update_dbus(&key, &value)?;
+            Ok(true)
+        }
+        DBusUpdate::PropertyUpdate((key, None)) => {
+            println!("{} Recieved empty value for {}", log::ERROR, key);
+            Ok(false)
+        }
+        DBusUpdate::MethodShowImage(display, png) => {
+            println!("{} Show image {:?} on display {}", log::DBUS, png, display);
+            let cleared = match png {
+                Some(_) => false,
+                None => state.update(&display_key(display, DISPLAY_PUSHED_KEY), None)?,
+            };
+            Ok(state.update(
+                &display_key(display, DISPLAY_IMAGE_KEY),
+                png.map(StateValueType::String),
+            )? | cleared)
+        }
+        DBusUpdate::MethodPushImage(display, id) => state.update(
+            &display_key(display, DISPLAY_PUSHED_KEY),
+            Some(StateValueType::U64(id)),
+        ),
+        DBusUpdate::PushedImageExpired(display, id) => {
+            let key = display_key(display, DISPLAY_PUSHED_KEY);
+            match state.get(&key) {
+                Some(StateValueType::U64(pushed)) if *pushed == id => state.update(&key, None),
+                _ => Ok(false),
             }
         }
+        DBusUpdate::MethodSetWorkspaces(active, count) => Ok(state
+            .update("workspace:active", Some(StateValueType::U64(active as u64)))?
+            | state.update("workspace:count", Some(StateValueType::U64(count as u64)))?),
+        DBusUpdate::MethodSetValue(key, value) => state.update(&key, value),
     }
+}
 
-    // Get initial values and start listening for updates
-    for proxy in proxies {
-        println!("{} Init Proxy {} {}", log::DBUS, proxy.dest, proxy.path);
+/// Follow the DBus properties on one bus, the session bus also serves our methods and signals
+/// the changes of the state. Returns an error when the connection is lost.
+#[allow(clippy::too_many_arguments)]
+async fn run_bus(
+    bus: BusType,
+    update_tx: Sender<()>,
+    control_tx: Sender<ControlMessage>,
+    mut rebind_rx: watch::Receiver<()>,
+    mut state_changed_rx: watch::Receiver<()>,
+    state: Arc<Mutex<ApplicationState>>,
+    pushed_images: PushedImages,
+    library: ImageLibrary,
+) -> SourceResult {
+    let (resource, conn) = match bus {
+        BusType::Session => connection::new_session_sync()?,
+        BusType::System => connection::new_system_sync()?,
+    };
 
-        // let clone_proxy: DBusProxyAdress = proxy.clone();
+    // The resource handles the messages of the connection, it only returns when it is lost
+    let mut resources = JoinSet::new();
+    resources.spawn(resource);
 
-        let connection = match proxy.bus {
-            BusType::Session => &session_conn,
-            BusType::System => &system_conn,
-        };
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<DBusUpdate>>();
 
-        let conn_proxy = connection.with_proxy(proxy.dest, proxy.path, Duration::from_secs(2));
+    let mut api = match bus {
+        BusType::Session => Some(
+            serve_methods(
+                &conn,
+                &tx,
+                control_tx,
+                state.clone(),
+                pushed_images.clone(),
+                library.clone(),
+            )
+            .await?,
+        ),
+        BusType::System => None,
+    };
 
-        for property in properties.clone() {
-            if property.proxy != proxy {
-                continue;
+    rebind_rx.borrow_and_update();
+    state_changed_rx.borrow_and_update();
+    let mut matches = subscribe_properties(&bus, &conn, &state, &tx).await;
+    if let Some(api) = &mut api {
+        api.rebind(&state, &*state.lock().await);
+    }
+    update_tx.send(()).await?;
+
+    loop {
+        let mut updated = false;
+
+        tokio::select! {
+            Some(result) = resources.join_next() => {
+                return Err(match result {
+                    Ok(error) => error.into(),
+                    Err(error) => error.into(),
+                });
             }
-            // Get initial value
-            let res = conn_proxy.get::<Box<dyn RefArg>>(property.interface, property.property);
-
-            if let Ok(result) = res {
-                state_lock
-                    .update_dbus(property, &result)
-                    .expect("Error setting initial DBus values");
-            } else {
-                println!("{} Unable to get property {}", log::ERROR, property);
+            // The config was reloaded, listen to the properties of the new state
+            Ok(()) = rebind_rx.changed() => {
+                for msg_match in matches.drain(..) {
+                    conn.remove_match(msg_match.token()).await.ok();
+                }
+                matches = subscribe_properties(&bus, &conn, &state, &tx).await;
+                if let Some(api) = &mut api {
+                    let state_lock = state.lock().await;
+                    api.state_changed(&conn, &state_lock)?;
+                    api.rebind(&state, &state_lock);
+                }
+                updated = true;
             }
+            Ok(()) = state_changed_rx.changed(), if api.is_some() => {
+                if let Some(api) = &mut api {
+                    api.state_changed(&conn, &*state.lock().await)?;
+                }
+            }
+            Some(dbus_values) = rx.recv() => {
+                let mut state_lock = state.lock().await;
+                let more_values = iter::from_fn(|| rx.try_recv().ok());
+                for update in iter::once(dbus_values).chain(more_values).flatten() {
+                    match apply_update(&mut state_lock, update) {
+                        Ok(changed) => updated |= changed,
+                        Err(error) => println!(
+                            "{} Could not match into Application state: {}",
+                            log::WARN,
+                            error
+                        ),
+                    }
+                }
+            }
+        }
 
-            // conn_proxy.method_call(
-            //     m,
-            //     args,
-            // )
-
-            // let value = self.values.get(property).expect("Unknown value");
+        if updated {
+            update_tx.send(()).await?;
         }
+    }
+}
 
-        let props = properties.clone();
+/// The keys that get their value from a property on `bus`
+fn bus_keys(state: &ApplicationState, bus: &BusType) -> Vec<String> {
+    state
+        .map
+        .iter()
+        .filter(|(_, value)| {
+            value
+                .dbus_property
+                .as_ref()
+                .is_some_and(|property| property.proxy.bus == *bus)
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
 
-        let clone_tx = tx.clone();
+/// Run the session bus, system bus, NetworkManager and media player sources and the notification
+/// server when it is enabled, each one is restarted when it fails
+#[allow(clippy::too_many_arguments)]
+pub async fn run_dbus_thread(
+    update_tx: Sender<()>,
+    control_tx: Sender<ControlMessage>,
+    rebind_rx: watch::Receiver<()>,
+    state_changed_rx: watch::Receiver<()>,
+    state: Arc<Mutex<ApplicationState>>,
+    pushed_images: PushedImages,
+    library: ImageLibrary,
+    notification_server: bool,
+) {
+    let bus_source = |bus: BusType| {
+        let (update_tx, control_tx, rebind_rx, state_changed_rx, state) = (
+            update_tx.clone(),
+            control_tx.clone(),
+            rebind_rx.clone(),
+            state_changed_rx.clone(),
+            state.clone(),
+        );
+        let (pushed_images, library) = (pushed_images.clone(), library.clone());
+        supervise(
+            match bus {
+                BusType::Session => "Session DBus",
+                BusType::System => "System DBus",
+            },
+            state.clone(),
+            update_tx.clone(),
+            {
+                let bus = bus.clone();
+                move |state: &ApplicationState| bus_keys(state, &bus)
+            },
+            move || {
+                run_bus(
+                    bus.clone(),
+                    update_tx.clone(),
+                    control_tx.clone(),
+                    rebind_rx.clone(),
+                    state_changed_rx.clone(),
+                    state.clone(),
+                    pushed_images.clone(),
+                    library.clone(),
+                )
+            },
+        )
+    };
 
-        conn_proxy
-            .match_signal(
-                move |h: PropertiesPropertiesChanged, _: &Connection, _: &Message| {
-                    // let values = self.values.lock().expect("Could not lock values mutex");
-
-                    // let iface: String = h.interface_name.as_str().clone();
-                    let iface = h.interface_name;
-
-                    let mut updates: Vec<DBusUpdate> = Vec::new();
-
-                    for (key, value) in h.changed_properties {
-                        for prop in props.iter() {
-                            if prop.proxy == proxy
-                                && prop.interface == iface.as_str()
-                                && prop.property == key.as_str()
-                            {
-                                updates.push(DBusUpdate::PropertyUpdate((
-                                    prop,
-                                    Some(value.0.box_clone()),
-                                )));
-                            }
-                        }
+    let nm_state = state.clone();
+    let nm_update_tx = update_tx.clone();
+    let networkmanager = supervise(
+        "NetworkManager",
+        state.clone(),
+        update_tx.clone(),
+        |_: &ApplicationState| NETWORK_KEYS.map(String::from).to_vec(),
+        move || run_networkmanager(nm_update_tx.clone(), nm_state.clone()),
+    );
 
-                        // print_refarg(&value.1.expect("huh?"));
-                    }
-                    if !updates.is_empty() {
-                        println!("{} {} Values {:?} ", log::DBUS, iface, updates);
-                        clone_tx.try_send(updates).expect("Could not send");
-                    }
-                    true
+    let media_state = state.clone();
+    let media_update_tx = update_tx.clone();
+    let media = supervise(
+        "Media player",
+        state.clone(),
+        update_tx.clone(),
+        |_: &ApplicationState| MEDIA_KEYS.map(String::from).to_vec(),
+        move || run_mpris(media_update_tx.clone(), media_state.clone()),
+    );
+
+    let notification_state = state.clone();
+    let notification_update_tx = update_tx.clone();
+    let notifications = async {
+        if notification_server {
+            supervise(
+                "Notification server",
+                state.clone(),
+                update_tx.clone(),
+                |_: &ApplicationState| NOTIFICATION_KEYS.map(String::from).to_vec(),
+                move || {
+                    run_notifications(notification_update_tx.clone(), notification_state.clone())
                 },
             )
-            .expect("error");
-    }
+            .await
+        }
+    };
 
-    drop(state_lock);
+    tokio::join!(
+        bus_source(BusType::Session),
+        bus_source(BusType::System),
+        networkmanager,
+        media,
+        notifications
+    );
+}
 
-    let mut next_nm_tick = Instant::now();
-    let nm_duration = Duration::from_secs(1);
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
 
-    loop {
-        session_conn
-            .process(Duration::from_millis(10))
-            .expect("Could not process session dbus messages");
+    use dbus::nonblock::Proxy;
 
-        system_conn
-            .process(Duration::from_millis(10))
-            .expect("Could not process system dbus messages");
+    use crate::{
+        dbus::private_bus::PrivateBus,
+        state::{build_state_map, StateConfig},
+    };
 
-        let mut updated = false;
+    use super::*;
 
-        if next_nm_tick <= Instant::now() {
-            let mut state_lock = state.lock().await;
-            updated |= update_data_nm(&system_conn, &mut state_lock).expect("NetworkManager error");
-            drop(state_lock);
-            next_nm_tick = Instant::now() + nm_duration;
-        }
+    #[tokio::test]
+    async fn control_api() {
+        let Some(bus) = PrivateBus::start() else {
+            println!("dbus-daemon is not available, skipping");
+            return;
+        };
 
-        while let Ok(dbus_values) = rx.try_recv() {
-            if dbus_values.is_empty() {
-                continue;
-            }
-            let mut state_lock = state.lock().await;
-            for update in dbus_values {
-                match update {
-                    DBusUpdate::PropertyUpdate((key, new_value_option)) => {
-                        let old_value = state_lock.get_value_dbus(key)?;
-
-                        match old_value {
-                            Some(_val) if new_value_option.is_some() => {
-                                state_lock
-                                    .update_dbus(key, &new_value_option.expect(""))
-                                    .expect("Error applying DBus update to state");
-                                updated = true;
-                            }
-                            Some(_val) => println!("{} Recieved empty value????", log::ERROR),
-                            None => {
-                                println!(
-                                    "{} Could not match into Application state: \n{} {}",
-                                    log::WARN,
-                                    log::DBUS,
-                                    key
-                                );
-                            }
-                        }
-                    }
-                    DBusUpdate::MethodShowImage(png) => {
-                        print!("update method show image {}", png);
-                        updated |= state_lock
-                            .update("rear-image-path", Some(StateValueType::String(png)))?;
-                    }
-                    DBusUpdate::MethodSetWorkspaces(active, count) => {
-                        updated |= state_lock
-                            .update("workspace:active", Some(StateValueType::U64(active as u64)))?;
-                        updated |= state_lock
-                            .update("workspace:count", Some(StateValueType::U64(count as u64)))?;
-                    }
-                }
-            }
-            drop(state_lock);
-        }
-        if updated {
-            update_tx
-                .send(())
-                .await
-                .expect("Could not send dbus update");
-        }
+        let declarations = HashMap::from([("custom:counter".to_string(), StateConfig::default())]);
+        let state = Arc::new(Mutex::new(build_state_map(&declarations, 2)));
+        state
+            .lock()
+            .await
+            .update(
+                "display:1:status",
+                Some(StateValueType::String("ready".to_string())),
+            )
+            .unwrap();
+
+        let (resource, conn) = bus.connect();
+        tokio::spawn(resource);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        let images = PushedImages::default();
+        images.set_sizes(vec![Size::new(16, 16), Size::new(16, 16)]);
+        let library_dir = tempfile::tempdir().unwrap();
+        let mut api = serve_methods(
+            &conn,
+            &tx,
+            control_tx,
+            state.clone(),
+            images.clone(),
+            ImageLibrary::new(library_dir.path().to_path_buf()),
+        )
+        .await
+        .unwrap();
+        api.rebind(&state, &*state.lock().await);
+
+        let (client_resource, client) = bus.connect();
+        tokio::spawn(client_resource);
+        let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+        let _changed = client
+            .add_match(MatchRule::new_signal(INTERFACE, "StateChanged"))
+            .await
+            .unwrap()
+            .cb(move |_, (key, json): (String, String)| {
+                changed_tx.send((key, json)).ok();
+                true
+            });
+        let proxy = Proxy::new(INTERFACE, "/", Duration::from_secs(2), client.clone());
+
+        let _: () = proxy
+            .method_call(INTERFACE, "SetValue", ("custom:counter", "5"))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap().pop().unwrap();
+        assert!(apply_update(&mut *state.lock().await, update).unwrap());
+        let (value,): (String,) = proxy
+            .method_call(INTERFACE, "GetValue", ("custom:counter",))
+            .await
+            .unwrap();
+        assert_eq!(value, "5");
+        let (json,): (String,) = proxy.method_call(INTERFACE, "GetState", ()).await.unwrap();
+        assert!(json.contains("\"custom:counter\":5"), "{}", json);
+        assert!(proxy
+            .method_call::<(), _, _, _>(INTERFACE, "SetValue", ("missing", "1"))
+            .await
+            .is_err());
+
+        api.state_changed(&conn, &*state.lock().await).unwrap();
+        assert_eq!(
+            changed_rx.recv().await,
+            Some(("custom:counter".to_string(), "5".to_string()))
+        );
+
+        let _: () = proxy
+            .method_call(INTERFACE, "ForceRefresh", (1u32, true))
+            .await
+            .unwrap();
+        assert_eq!(
+            control_rx.recv().await,
+            Some(ControlMessage::Refresh {
+                display: 1,
+                full: true
+            })
+        );
+        assert!(proxy
+            .method_call::<(), _, _, _>(INTERFACE, "SetLed", (2u32, 1u8))
+            .await
+            .is_err());
+
+        let (displays,): (Vec<(u32, dbus::Path<'static>, String)>,) = proxy
+            .method_call(INTERFACE, "ListDisplays", ())
+            .await
+            .unwrap();
+        assert_eq!(displays.len(), 2);
+        assert_eq!(displays[1].2, "ready");
+        let display = Proxy::new(
+            INTERFACE,
+            displays[1].1.clone(),
+            Duration::from_secs(2),
+            client.clone(),
+        );
+        let status: String = display.get(DISPLAY_INTERFACE, "Status").await.unwrap();
+        assert_eq!(status, "ready");
+
+        // Images are shown by their id in the library
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(RgbaImage::new(4, 4))
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let _: () = proxy
+            .method_call(INTERFACE, "ImportImage", ("cat", png))
+            .await
+            .unwrap();
+        let (ids,): (Vec<String>,) = proxy
+            .method_call(INTERFACE, "ListImages", ())
+            .await
+            .unwrap();
+        assert_eq!(ids, vec!["cat"]);
+        assert!(proxy
+            .method_call::<(String,), _, _, _>(INTERFACE, "show", ("../cat", 1u32))
+            .await
+            .is_err());
+        assert!(proxy
+            .method_call::<(String,), _, _, _>(INTERFACE, "show", ("dog", 1u32))
+            .await
+            .is_err());
+        let _: (String,) = proxy
+            .method_call(INTERFACE, "show", ("cat", 1u32))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap().pop().unwrap();
+        assert!(apply_update(&mut *state.lock().await, update).unwrap());
+        assert_eq!(
+            state.lock().await.get("display:1:image"),
+            Some(&StateValueType::String("cat".to_string()))
+        );
+        let _: () = proxy
+            .method_call(INTERFACE, "ClearImage", (1u32,))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap().pop().unwrap();
+        assert!(apply_update(&mut *state.lock().await, update).unwrap());
+        assert_eq!(state.lock().await.get("display:1:image"), None);
+        assert!(proxy
+            .method_call::<(String,), _, _, _>(INTERFACE, "show", ("cat", 2u32))
+            .await
+            .is_err());
+
+        // A packed 2x1 image, that is gone again after the timeout
+        let _: () = proxy
+            .method_call(
+                INTERFACE,
+                "ShowPackedImage",
+                (0u32, vec![0b0110_0000u8], 2u32, 1u32, 2u8, 50u32),
+            )
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap().pop().unwrap();
+        assert!(apply_update(&mut *state.lock().await, update).unwrap());
+        let Some(StateValueType::U64(id)) = state.lock().await.get("display:0:pushed").cloned()
+        else {
+            panic!("no image pushed");
+        };
+        assert_eq!(images.get(0, id).unwrap().dimensions(), (2, 1));
+        let update = rx.recv().await.unwrap().pop().unwrap();
+        assert!(apply_update(&mut *state.lock().await, update).unwrap());
+        assert_eq!(state.lock().await.get("display:0:pushed"), None);
+        assert!(proxy
+            .method_call::<(), _, _, _>(
+                INTERFACE,
+                "ShowImageData",
+                (0u32, vec![1u8, 2, 3], "image/png", 0u32),
+            )
+            .await
+            .is_err());
     }
 }
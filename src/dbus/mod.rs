@@ -3,9 +3,18 @@ use std::fmt::Display;
 use dbus::arg::RefArg;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 
+use crate::state::value::StateValueType;
+
 pub mod dbus_interface;
+pub mod mpris;
 pub mod networkmanager;
+pub mod notifications;
+// Generated, not every binding is used
+#[allow(dead_code)]
 mod playerctld;
+#[cfg(test)]
+mod private_bus;
+mod supervisor;
 
 // Extend RefArg with Eq
 pub trait RefArgEq: RefArg + Eq + PartialEq + Clone {
@@ -19,16 +28,23 @@ pub trait RefArgEq: RefArg + Eq + PartialEq + Clone {
 // Implement RefArgEq for any type that implements RefArg
 impl<T: RefArg + Eq + PartialEq + Clone> RefArgEq for T {}
 
-type DBusPropertyUpdate = (&'static DBusPropertyAdress, Option<Box<dyn RefArg>>);
+type DBusPropertyUpdate = (DBusPropertyAdress, Option<Box<dyn RefArg>>);
 
 #[derive(Debug)]
 pub enum DBusUpdate {
     PropertyUpdate(DBusPropertyUpdate),
-    MethodShowImage(String),
+    /// Image for a display, None clears it and the pushed image
+    MethodShowImage(usize, Option<String>),
+    /// Id of the image pushed to a display
+    MethodPushImage(usize, u64),
+    /// The timeout of a pushed image passed, it is cleared unless another one was pushed
+    PushedImageExpired(usize, u64),
     MethodSetWorkspaces(u32, u32),
+    MethodSetValue(String, Option<StateValueType>),
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum BusType {
     Session,
     System,
@@ -37,13 +53,17 @@ pub enum BusType {
 // Define DBus structs
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct DBusProxyAdress {
-    bus: BusType,
-    dest: &'static str,
-    path: &'static str,
+    pub bus: BusType,
+    pub dest: String,
+    pub path: String,
 }
 impl DBusProxyAdress {
-    pub const fn new(bus: BusType, dest: &'static str, path: &'static str) -> DBusProxyAdress {
-        DBusProxyAdress { bus, dest, path }
+    pub fn new(bus: BusType, dest: &str, path: &str) -> DBusProxyAdress {
+        DBusProxyAdress {
+            bus,
+            dest: dest.to_string(),
+            path: path.to_string(),
+        }
     }
 }
 
@@ -53,9 +73,9 @@ impl DBusProxyAdress {
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct DBusPropertyAdress {
-    pub proxy: &'static DBusProxyAdress,
-    pub interface: &'static str,
-    pub property: &'static str,
+    pub proxy: DBusProxyAdress,
+    pub interface: String,
+    pub property: String,
 }
 
 // This is the trait that informs Serde how to serialize DBusPropertyAdress
@@ -85,15 +105,11 @@ impl Display for DBusPropertyAdress {
 }
 impl DBusPropertyAdress {
     /// Creates a new DBusProperty
-    pub const fn new(
-        proxy: &'static DBusProxyAdress,
-        interface: &'static str,
-        property: &'static str,
-    ) -> DBusPropertyAdress {
+    pub fn new(proxy: DBusProxyAdress, interface: &str, property: &str) -> DBusPropertyAdress {
         DBusPropertyAdress {
             proxy,
-            interface,
-            property,
+            interface: interface.to_string(),
+            property: property.to_string(),
         }
     }
 }
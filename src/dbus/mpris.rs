@@ -0,0 +1,346 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use dbus::{
+    arg::{prop_cast, PropMap, RefArg},
+    message::{MatchRule, MessageType},
+    nonblock::{Proxy, SyncConnection},
+};
+use dbus_tokio::connection::{self, IOResource};
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::state::{app::ApplicationState, value::StateValueType};
+
+use super::{
+    playerctld::{ComGithubAltdesktopPlayerctld, OrgMprisMediaPlayer2Player},
+    supervisor::SourceResult,
+};
+
+const PLAYERCTLD: &str = "org.mpris.MediaPlayer2.playerctld";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+/// Keys fed by the active media player
+pub const MEDIA_KEYS: [&str; 10] = [
+    "player:name",
+    "player:status",
+    "player:title",
+    "player:artist",
+    "player:album",
+    "player:art_url",
+    "player:length",
+    "player:position",
+    "player:shuffle",
+    "player:loop",
+];
+
+type Player = Proxy<'static, Arc<SyncConnection>>;
+
+fn string(value: String) -> Option<StateValueType> {
+    Some(StateValueType::String(value))
+}
+
+/// Microseconds as whole seconds
+fn seconds(micros: i64) -> Option<StateValueType> {
+    Some(StateValueType::U64(micros.max(0) as u64 / 1_000_000))
+}
+
+async fn read_position(player: &Player) -> Option<StateValueType> {
+    player.position().await.ok().and_then(seconds)
+}
+
+/// The track fields of the MPRIS metadata
+fn read_metadata(metadata: &PropMap) -> [(&'static str, Option<StateValueType>); 5] {
+    let text = |key: &str| prop_cast::<String>(metadata, key).cloned().and_then(string);
+    let artists =
+        prop_cast::<Vec<String>>(metadata, "xesam:artist").map(|artists| artists.join(", "));
+    let length = metadata.get("mpris:length").and_then(|length| {
+        length
+            .0
+            .as_i64()
+            .or_else(|| length.0.as_u64().map(|length| length as i64))
+    });
+    [
+        ("player:title", text("xesam:title")),
+        ("player:artist", artists.and_then(string)),
+        ("player:album", text("xesam:album")),
+        ("player:art_url", text("mpris:artUrl")),
+        ("player:length", length.and_then(seconds)),
+    ]
+}
+
+/// Read the values of `MEDIA_KEYS` from the active player, they are all unavailable when no
+/// player is running
+async fn read_player(
+    player: &Player,
+) -> Result<HashMap<&'static str, Option<StateValueType>>, dbus::Error> {
+    let mut values: HashMap<&str, Option<StateValueType>> =
+        MEDIA_KEYS.iter().map(|key| (*key, None)).collect();
+
+    // playerctld puts the active player first
+    let names = player.player_names().await?;
+    let Some(name) = names.first() else {
+        return Ok(values);
+    };
+    values.insert(
+        "player:name",
+        string(name.trim_start_matches(PLAYER_PREFIX).to_string()),
+    );
+    values.insert(
+        "player:status",
+        player.playback_status().await.ok().and_then(string),
+    );
+    if let Ok(metadata) = player.metadata().await {
+        values.extend(read_metadata(&metadata));
+    }
+    values.insert("player:position", read_position(player).await);
+    values.insert(
+        "player:shuffle",
+        player
+            .shuffle()
+            .await
+            .ok()
+            .map(|shuffle| StateValueType::U64(shuffle as u64)),
+    );
+    values.insert(
+        "player:loop",
+        player.loop_status().await.ok().and_then(string),
+    );
+
+    Ok(values)
+}
+
+/// Follow the active media player through playerctld on the session bus
+pub async fn run_mpris(update_tx: Sender<()>, state: Arc<Mutex<ApplicationState>>) -> SourceResult {
+    let (resource, conn) = connection::new_session_sync()?;
+    follow_mpris(resource, conn, update_tx, state).await
+}
+
+/// Read the player again on every signal of playerctld, and the position every second while
+/// it plays, until playerctld stops.
+async fn follow_mpris(
+    resource: IOResource<SyncConnection>,
+    conn: Arc<SyncConnection>,
+    update_tx: Sender<()>,
+    state: Arc<Mutex<ApplicationState>>,
+) -> SourceResult {
+    // The resource handles the messages of the connection, it only returns when it is lost
+    let mut resources = JoinSet::new();
+    resources.spawn(resource);
+
+    let player: Player = Proxy::new(PLAYERCTLD, MPRIS_PATH, Duration::from_secs(2), conn.clone());
+
+    // Properties of the active player, seeks and changes of the active player
+    let (changed_tx, mut changed_rx) = mpsc::channel::<()>(1);
+    let _signals = conn
+        .add_match(
+            MatchRule::new()
+                .with_type(MessageType::Signal)
+                .with_sender(PLAYERCTLD),
+        )
+        .await?
+        .msg_cb(move |_| {
+            changed_tx.try_send(()).ok();
+            true
+        });
+
+    let (stopped_tx, mut stopped_rx) = mpsc::channel::<()>(1);
+    let _owner = conn
+        .add_match(
+            MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+                .with_sender("org.freedesktop.DBus"),
+        )
+        .await?
+        .cb(move |_, (name, _old, new): (String, String, String)| {
+            if name == PLAYERCTLD && new.is_empty() {
+                stopped_tx.try_send(()).ok();
+            }
+            true
+        });
+
+    let mut position_interval = interval(Duration::from_secs(1));
+    position_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut playing = false;
+    let mut read_all = true;
+    loop {
+        let values = if read_all {
+            let values = read_player(&player).await?;
+            playing = values.get("player:status")
+                == Some(&Some(StateValueType::String("Playing".to_string())));
+            values
+        } else {
+            HashMap::from([("player:position", read_position(&player).await)])
+        };
+        if state.lock().await.update_multiple(values)? {
+            update_tx.send(()).await?;
+        }
+
+        tokio::select! {
+            Some(result) = resources.join_next() => {
+                return Err(match result {
+                    Ok(error) => error.into(),
+                    Err(error) => error.into(),
+                });
+            }
+            Some(()) = stopped_rx.recv() => return Err("playerctld stopped".into()),
+            Some(()) = changed_rx.recv() => read_all = true,
+            _ = position_interval.tick(), if playing => read_all = false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as SyncMutex;
+
+    use dbus::{
+        arg::{messageitem::MessageItem, Variant},
+        channel::{MatchingReceiver, Sender as _},
+        message::SignalArgs,
+        Path,
+    };
+    use dbus_crossroads::Crossroads;
+
+    use crate::{
+        dbus::{
+            playerctld::ComGithubAltdesktopPlayerctldActivePlayerChangeEnd,
+            private_bus::{next_update, PrivateBus},
+        },
+        state::build_state_map,
+    };
+
+    use super::*;
+
+    struct MockPlayer {
+        names: Vec<String>,
+        title: &'static str,
+    }
+
+    type Metadata = HashMap<String, Variant<MessageItem>>;
+
+    async fn serve_mock(conn: &Arc<SyncConnection>, player: Arc<SyncMutex<MockPlayer>>) {
+        let mut cr = Crossroads::new();
+        let playerctld = cr.register("com.github.altdesktop.playerctld", |b| {
+            b.property("PlayerNames")
+                .get(|_, player: &mut Arc<SyncMutex<MockPlayer>>| {
+                    Ok(player.lock().unwrap().names.clone())
+                });
+        });
+        let mpris = cr.register("org.mpris.MediaPlayer2.Player", |b| {
+            b.property("PlaybackStatus")
+                .get(|_, _: &mut Arc<SyncMutex<MockPlayer>>| Ok("Paused".to_string()));
+            b.property("Metadata")
+                .get(|_, player: &mut Arc<SyncMutex<MockPlayer>>| {
+                    let title = player.lock().unwrap().title.to_string();
+                    let artists = MessageItem::new_array(vec!["Alice".into(), "Bob".into()]);
+                    let metadata = Metadata::from([
+                        ("xesam:title".to_string(), Variant(title.into())),
+                        ("xesam:artist".to_string(), Variant(artists.unwrap())),
+                        ("xesam:album".to_string(), Variant("Album".into())),
+                        (
+                            "mpris:artUrl".to_string(),
+                            Variant("file:///tmp/art.png".into()),
+                        ),
+                        ("mpris:length".to_string(), Variant(180_000_000i64.into())),
+                    ]);
+                    Ok(metadata)
+                });
+            b.property("Position")
+                .get(|_, _: &mut Arc<SyncMutex<MockPlayer>>| Ok(42_000_000i64));
+            b.property("Shuffle")
+                .get(|_, _: &mut Arc<SyncMutex<MockPlayer>>| Ok(true));
+            b.property("LoopStatus")
+                .get(|_, _: &mut Arc<SyncMutex<MockPlayer>>| Ok("Playlist".to_string()));
+        });
+        cr.insert(MPRIS_PATH, &[playerctld, mpris], player);
+
+        conn.request_name(PLAYERCTLD, false, true, false)
+            .await
+            .unwrap();
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                cr.handle_message(msg, conn).ok();
+                true
+            }),
+        );
+    }
+
+    fn change_player(conn: &SyncConnection, player: &SyncMutex<MockPlayer>, name: &str) {
+        let mut player = player.lock().unwrap();
+        player.names = [name]
+            .into_iter()
+            .filter(|name| !name.is_empty())
+            .map(|name| format!("{}{}", PLAYER_PREFIX, name))
+            .collect();
+        player.title = "Other song";
+        let signal = ComGithubAltdesktopPlayerctldActivePlayerChangeEnd {
+            name: name.to_string(),
+        };
+        conn.send(signal.to_emit_message(&Path::from(MPRIS_PATH)))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn follows_active_player() {
+        let Some(bus) = PrivateBus::start() else {
+            println!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let (mock_resource, mock_conn) = bus.connect();
+        tokio::spawn(mock_resource);
+        let mock_player = Arc::new(SyncMutex::new(MockPlayer {
+            names: vec![format!("{}spotify", PLAYER_PREFIX)],
+            title: "Song",
+        }));
+        serve_mock(&mock_conn, mock_player.clone()).await;
+
+        let state = Arc::new(Mutex::new(build_state_map(&HashMap::new(), 0)));
+        let (update_tx, mut update_rx) = mpsc::channel(1);
+        let (resource, conn) = bus.connect();
+        tokio::spawn(follow_mpris(resource, conn, update_tx, state.clone()));
+
+        let text = |value: &str| Some(StateValueType::String(value.to_string()));
+        next_update(&mut update_rx).await;
+        {
+            let state = state.lock().await;
+            assert_eq!(state.get("player:name").cloned(), text("spotify"));
+            assert_eq!(state.get("player:status").cloned(), text("Paused"));
+            assert_eq!(state.get("player:title").cloned(), text("Song"));
+            assert_eq!(state.get("player:artist").cloned(), text("Alice, Bob"));
+            assert_eq!(state.get("player:album").cloned(), text("Album"));
+            assert_eq!(
+                state.get("player:art_url").cloned(),
+                text("file:///tmp/art.png")
+            );
+            assert_eq!(state.get("player:length"), Some(&StateValueType::U64(180)));
+            assert_eq!(state.get("player:position"), Some(&StateValueType::U64(42)));
+            assert_eq!(state.get("player:shuffle"), Some(&StateValueType::U64(1)));
+            assert_eq!(state.get("player:loop").cloned(), text("Playlist"));
+        }
+
+        change_player(&mock_conn, &mock_player, "mpv");
+        next_update(&mut update_rx).await;
+        {
+            let state = state.lock().await;
+            assert_eq!(state.get("player:name").cloned(), text("mpv"));
+            assert_eq!(state.get("player:title").cloned(), text("Other song"));
+        }
+
+        // The last player quit
+        change_player(&mock_conn, &mock_player, "");
+        next_update(&mut update_rx).await;
+        let state = state.lock().await;
+        for key in MEDIA_KEYS {
+            assert_eq!(state.get(key), None, "{}", key);
+        }
+    }
+}
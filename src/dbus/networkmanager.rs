@@ -1,4 +1,27 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use dbus::{
+    arg::PropMap,
+    message::{MatchRule, MessageType},
+    nonblock::{stdintf::org_freedesktop_dbus::Properties, Proxy, SyncConnection},
+    Path,
+};
+use dbus_tokio::connection::{self, IOResource};
 use enum_primitive::FromPrimitive;
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    task::JoinSet,
+};
+
+use crate::state::{
+    app::ApplicationState,
+    value::{NetworkState, StateValueType},
+};
+
+use super::supervisor::SourceResult;
 
 enum_from_primitive! {
 
@@ -28,3 +51,443 @@ impl NMDeviceState {
         state
     }
 }
+
+const NM_DEST: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_IFACE: &str = "org.freedesktop.NetworkManager";
+const DEVICE_IFACE: &str = "org.freedesktop.NetworkManager.Device";
+const WIRELESS_IFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const ACCESS_POINT_IFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const ACTIVE_IFACE: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const IP4_IFACE: &str = "org.freedesktop.NetworkManager.IP4Config";
+const IP6_IFACE: &str = "org.freedesktop.NetworkManager.IP6Config";
+
+const DEVICE_TYPE_ETHERNET: u32 = 1;
+const DEVICE_TYPE_WIFI: u32 = 2;
+const ACTIVE_STATE_ACTIVATED: u32 = 2;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Keys fed by NetworkManager
+pub const NETWORK_KEYS: [&str; 8] = [
+    "wifi:state",
+    "wifi:strength",
+    "wifi:ssid",
+    "eth:state",
+    "network:ipv4",
+    "network:ipv6",
+    "network:connectivity",
+    "network:vpn",
+];
+
+/// Order of the device states, the best device of a type is shown
+fn rank(state: &NetworkState) -> u8 {
+    match state {
+        NetworkState::Unknown => 0,
+        NetworkState::Disabled => 1,
+        NetworkState::Disconnected => 2,
+        NetworkState::Connecting => 3,
+        NetworkState::Connected => 4,
+    }
+}
+
+fn proxy(conn: &Arc<SyncConnection>, path: Path<'static>) -> Proxy<'static, Arc<SyncConnection>> {
+    Proxy::new(NM_DEST, path, TIMEOUT, conn.clone())
+}
+
+/// First address of the IPv4 or IPv6 config of an active connection
+async fn first_address(
+    conn: &Arc<SyncConnection>,
+    active: &Proxy<'static, Arc<SyncConnection>>,
+    property: &str,
+    interface: &str,
+) -> Option<String> {
+    let config: Path<'static> = active.get(ACTIVE_IFACE, property).await.ok()?;
+    if &*config == "/" {
+        return None;
+    }
+    let addresses: Vec<PropMap> = proxy(conn, config)
+        .get(interface, "AddressData")
+        .await
+        .ok()?;
+    addresses
+        .first()?
+        .get("address")?
+        .0
+        .as_str()
+        .map(String::from)
+}
+
+/// Read the values of `NETWORK_KEYS`, devices and connections that disappear while they are
+/// read are skipped
+async fn read_network(
+    conn: &Arc<SyncConnection>,
+) -> Result<HashMap<&'static str, Option<StateValueType>>, dbus::Error> {
+    let nm = proxy(conn, NM_PATH.into());
+
+    let mut wifi: Option<(NetworkState, Path<'static>)> = None;
+    let mut eth: Option<NetworkState> = None;
+    let devices: Vec<Path<'static>> = nm.get(NM_IFACE, "Devices").await?;
+    for path in devices {
+        let device = proxy(conn, path.clone());
+        let (Ok(device_type), Ok(state)) = (
+            device.get::<u32>(DEVICE_IFACE, "DeviceType").await,
+            device.get::<u32>(DEVICE_IFACE, "State").await,
+        ) else {
+            continue;
+        };
+        let state = NetworkState::from(NMDeviceState::from_int(state));
+        match device_type {
+            DEVICE_TYPE_WIFI
+                if wifi
+                    .as_ref()
+                    .is_none_or(|(best, _)| rank(&state) > rank(best)) =>
+            {
+                wifi = Some((state, path))
+            }
+            DEVICE_TYPE_ETHERNET if eth.as_ref().is_none_or(|best| rank(&state) > rank(best)) => {
+                eth = Some(state)
+            }
+            _ => {}
+        }
+    }
+
+    let mut strength = None;
+    let mut ssid = None;
+    if let Some((NetworkState::Connected, path)) = &wifi {
+        strength = Some(StateValueType::F64(0.0));
+        let access_point = proxy(conn, path.clone())
+            .get::<Path<'static>>(WIRELESS_IFACE, "ActiveAccessPoint")
+            .await;
+        if let Ok(access_point) = access_point.map(|path| proxy(conn, path)) {
+            if let Ok(value) = access_point.get::<u8>(ACCESS_POINT_IFACE, "Strength").await {
+                strength = Some(StateValueType::F64(value as f64));
+            }
+            if let Ok(value) = access_point
+                .get::<Vec<u8>>(ACCESS_POINT_IFACE, "Ssid")
+                .await
+            {
+                ssid = Some(StateValueType::String(
+                    String::from_utf8_lossy(&value).into_owned(),
+                ));
+            }
+        }
+    }
+
+    let mut ipv4 = None;
+    let mut ipv6 = None;
+    let primary: Path<'static> = nm.get(NM_IFACE, "PrimaryConnection").await?;
+    if &*primary != "/" {
+        let active = proxy(conn, primary);
+        ipv4 = first_address(conn, &active, "Ip4Config", IP4_IFACE).await;
+        ipv6 = first_address(conn, &active, "Ip6Config", IP6_IFACE).await;
+    }
+
+    let connectivity = match nm.get::<u32>(NM_IFACE, "Connectivity").await? {
+        1 => "none",
+        2 => "portal",
+        3 => "limited",
+        4 => "full",
+        _ => "unknown",
+    };
+
+    let mut vpns = Vec::new();
+    let active_connections: Vec<Path<'static>> = nm.get(NM_IFACE, "ActiveConnections").await?;
+    for path in active_connections {
+        let active = proxy(conn, path);
+        let activated =
+            active.get::<u32>(ACTIVE_IFACE, "State").await.ok() == Some(ACTIVE_STATE_ACTIVATED);
+        let vpn = active
+            .get::<bool>(ACTIVE_IFACE, "Vpn")
+            .await
+            .unwrap_or(false)
+            || active
+                .get::<String>(ACTIVE_IFACE, "Type")
+                .await
+                .is_ok_and(|connection_type| connection_type == "wireguard");
+        if activated && vpn {
+            if let Ok(id) = active.get::<String>(ACTIVE_IFACE, "Id").await {
+                vpns.push(id);
+            }
+        }
+    }
+
+    Ok(HashMap::from([
+        (
+            "wifi:state",
+            wifi.map(|(state, _)| StateValueType::NetworkState(state)),
+        ),
+        ("wifi:strength", strength),
+        ("wifi:ssid", ssid),
+        ("eth:state", eth.map(StateValueType::NetworkState)),
+        ("network:ipv4", ipv4.map(StateValueType::String)),
+        ("network:ipv6", ipv6.map(StateValueType::String)),
+        (
+            "network:connectivity",
+            Some(StateValueType::String(connectivity.to_string())),
+        ),
+        ("network:vpn", Some(StateValueType::String(vpns.join(", ")))),
+    ]))
+}
+
+/// Follow NetworkManager on the system bus
+pub async fn run_networkmanager(
+    update_tx: Sender<()>,
+    state: Arc<Mutex<ApplicationState>>,
+) -> SourceResult {
+    let (resource, conn) = connection::new_system_sync()?;
+    follow_networkmanager(resource, conn, update_tx, state).await
+}
+
+/// Read the network again every time NetworkManager sends a signal, until it stops.
+async fn follow_networkmanager(
+    resource: IOResource<SyncConnection>,
+    conn: Arc<SyncConnection>,
+    update_tx: Sender<()>,
+    state: Arc<Mutex<ApplicationState>>,
+) -> SourceResult {
+    // The resource handles the messages of the connection, it only returns when it is lost
+    let mut resources = JoinSet::new();
+    resources.spawn(resource);
+
+    // Devices come and go or change state, access points change strength, connections start...
+    // A full channel already means the network will be read again.
+    let (changed_tx, mut changed_rx) = mpsc::channel::<()>(1);
+    let _signals = conn
+        .add_match(
+            MatchRule::new()
+                .with_type(MessageType::Signal)
+                .with_sender(NM_DEST),
+        )
+        .await?
+        .msg_cb(move |_| {
+            changed_tx.try_send(()).ok();
+            true
+        });
+
+    let (stopped_tx, mut stopped_rx) = mpsc::channel::<()>(1);
+    let _owner = conn
+        .add_match(
+            MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+                .with_sender("org.freedesktop.DBus"),
+        )
+        .await?
+        .cb(move |_, (name, _old, new): (String, String, String)| {
+            if name == NM_DEST && new.is_empty() {
+                stopped_tx.try_send(()).ok();
+            }
+            true
+        });
+
+    loop {
+        let values = read_network(&conn).await?;
+        if state.lock().await.update_multiple(values)? {
+            update_tx.send(()).await?;
+        }
+
+        tokio::select! {
+            Some(result) = resources.join_next() => {
+                return Err(match result {
+                    Ok(error) => error.into(),
+                    Err(error) => error.into(),
+                });
+            }
+            Some(()) = stopped_rx.recv() => return Err("NetworkManager stopped".into()),
+            Some(()) = changed_rx.recv() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as SyncMutex;
+
+    use dbus::{channel::MatchingReceiver, channel::Sender as _, Message};
+    use dbus_crossroads::Crossroads;
+
+    use crate::{
+        dbus::private_bus::{next_update, PrivateBus},
+        state::build_state_map,
+    };
+
+    use super::*;
+
+    struct MockManager {
+        devices: Arc<SyncMutex<Vec<Path<'static>>>>,
+    }
+    struct MockDevice {
+        device_type: u32,
+        state: u32,
+    }
+    struct MockConnection {
+        id: &'static str,
+        vpn: bool,
+        ip4: Path<'static>,
+    }
+
+    const WIFI: &str = "/org/freedesktop/NetworkManager/Devices/1";
+    const ETHERNET: &str = "/org/freedesktop/NetworkManager/Devices/2";
+    const ACCESS_POINT: &str = "/org/freedesktop/NetworkManager/AccessPoint/1";
+    const HOME: &str = "/org/freedesktop/NetworkManager/ActiveConnection/1";
+    const WORK: &str = "/org/freedesktop/NetworkManager/ActiveConnection/2";
+    const IP4: &str = "/org/freedesktop/NetworkManager/IP4Config/1";
+
+    /// Serve a laptop on WiFi with a VPN, and a cable that is not plugged in
+    async fn serve_mock(conn: &Arc<SyncConnection>, devices: Arc<SyncMutex<Vec<Path<'static>>>>) {
+        let mut cr = Crossroads::new();
+
+        let manager = cr.register(NM_IFACE, |b| {
+            b.property("Devices")
+                .get(|_, manager: &mut MockManager| Ok(manager.devices.lock().unwrap().clone()));
+            b.property("PrimaryConnection")
+                .get(|_, _| Ok(Path::from(HOME)));
+            b.property("ActiveConnections")
+                .get(|_, _| Ok(vec![Path::from(HOME), Path::from(WORK)]));
+            b.property("Connectivity").get(|_, _| Ok(4u32));
+        });
+        let device = cr.register(DEVICE_IFACE, |b| {
+            b.property("DeviceType")
+                .get(|_, device: &mut MockDevice| Ok(device.device_type));
+            b.property("State")
+                .get(|_, device: &mut MockDevice| Ok(device.state));
+        });
+        let wireless = cr.register(WIRELESS_IFACE, |b| {
+            b.property("ActiveAccessPoint")
+                .get(|_, _: &mut MockDevice| Ok(Path::from(ACCESS_POINT)));
+        });
+        let access_point = cr.register(ACCESS_POINT_IFACE, |b| {
+            b.property("Strength").get(|_, _: &mut ()| Ok(70u8));
+            b.property("Ssid").get(|_, _: &mut ()| Ok(b"home".to_vec()));
+        });
+        let active = cr.register(ACTIVE_IFACE, |b| {
+            b.property("Id")
+                .get(|_, active: &mut MockConnection| Ok(active.id.to_string()));
+            b.property("Vpn")
+                .get(|_, active: &mut MockConnection| Ok(active.vpn));
+            b.property("State")
+                .get(|_, _: &mut MockConnection| Ok(ACTIVE_STATE_ACTIVATED));
+            b.property("Ip4Config")
+                .get(|_, active: &mut MockConnection| Ok(active.ip4.clone()));
+            b.property("Ip6Config")
+                .get(|_, _: &mut MockConnection| Ok(Path::from("/")));
+        });
+        let ip4 = cr.register(IP4_IFACE, |b| {
+            b.property("AddressData").get(|_, _: &mut ()| {
+                Ok(vec![HashMap::from([(
+                    "address".to_string(),
+                    dbus::arg::Variant("192.168.1.10".to_string()),
+                )])])
+            });
+        });
+
+        cr.insert(NM_PATH, &[manager], MockManager { devices });
+        cr.insert(
+            WIFI,
+            &[device, wireless],
+            MockDevice {
+                device_type: DEVICE_TYPE_WIFI,
+                state: 100,
+            },
+        );
+        cr.insert(
+            ETHERNET,
+            &[device],
+            MockDevice {
+                device_type: DEVICE_TYPE_ETHERNET,
+                state: 30,
+            },
+        );
+        cr.insert(ACCESS_POINT, &[access_point], ());
+        cr.insert(
+            HOME,
+            &[active],
+            MockConnection {
+                id: "home",
+                vpn: false,
+                ip4: IP4.into(),
+            },
+        );
+        cr.insert(
+            WORK,
+            &[active],
+            MockConnection {
+                id: "work",
+                vpn: true,
+                ip4: "/".into(),
+            },
+        );
+        cr.insert(IP4, &[ip4], ());
+
+        conn.request_name(NM_DEST, false, true, false)
+            .await
+            .unwrap();
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                cr.handle_message(msg, conn).ok();
+                true
+            }),
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_mock_networkmanager() {
+        let Some(bus) = PrivateBus::start() else {
+            println!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let (mock_resource, mock_conn) = bus.connect();
+        tokio::spawn(mock_resource);
+        let devices = Arc::new(SyncMutex::new(vec![Path::from(WIFI), Path::from(ETHERNET)]));
+        serve_mock(&mock_conn, devices.clone()).await;
+
+        let state = Arc::new(Mutex::new(build_state_map(&HashMap::new(), 0)));
+        let (update_tx, mut update_rx) = mpsc::channel(1);
+        let (resource, conn) = bus.connect();
+        tokio::spawn(follow_networkmanager(
+            resource,
+            conn,
+            update_tx,
+            state.clone(),
+        ));
+
+        next_update(&mut update_rx).await;
+        {
+            let state = state.lock().await;
+            let string = |value: &str| Some(StateValueType::String(value.to_string()));
+            assert_eq!(
+                state.get("wifi:state"),
+                Some(&StateValueType::NetworkState(NetworkState::Connected))
+            );
+            assert_eq!(state.get("wifi:strength"), Some(&StateValueType::F64(80.0)));
+            assert_eq!(state.get("wifi:ssid").cloned(), string("home"));
+            assert_eq!(
+                state.get("eth:state"),
+                Some(&StateValueType::NetworkState(NetworkState::Disconnected))
+            );
+            assert_eq!(state.get("network:ipv4").cloned(), string("192.168.1.10"));
+            assert_eq!(state.get("network:ipv6"), None);
+            assert_eq!(state.get("network:connectivity").cloned(), string("full"));
+            assert_eq!(state.get("network:vpn").cloned(), string("work"));
+        }
+
+        // The WiFi card is unplugged
+        devices.lock().unwrap().retain(|path| &**path != WIFI);
+        mock_conn
+            .send(
+                Message::new_signal(NM_PATH, NM_IFACE, "DeviceRemoved")
+                    .unwrap()
+                    .append1(Path::from(WIFI)),
+            )
+            .unwrap();
+        next_update(&mut update_rx).await;
+        {
+            let state = state.lock().await;
+            assert_eq!(state.get("wifi:state"), None);
+            assert_eq!(state.get("wifi:strength"), None);
+            assert_eq!(state.get("wifi:ssid"), None);
+            assert!(state.get("eth:state").is_some());
+        }
+    }
+}
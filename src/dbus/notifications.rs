@@ -0,0 +1,474 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+
+use dbus::{
+    arg::PropMap,
+    channel::{MatchingReceiver, Sender as _},
+    message::MatchRule,
+    nonblock::SyncConnection,
+    Message, Path,
+};
+use dbus_crossroads::{Context, Crossroads, MethodErr};
+use dbus_tokio::connection::{self, IOResource};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    task::JoinSet,
+    time::{sleep_until, Instant as TokioInstant},
+};
+
+use crate::{
+    log,
+    state::{app::ApplicationState, value::StateValueType},
+};
+
+use super::supervisor::SourceResult;
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+/// Shown this long when the sender leaves the timeout to the server
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keys of the notification that is shown
+pub const NOTIFICATION_KEYS: [&str; 6] = [
+    "notification:id",
+    "notification:app",
+    "notification:summary",
+    "notification:body",
+    "notification:urgency",
+    "notification:timeout",
+];
+
+/// The notification daemon, off by default. Changes need a restart.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Serve org.freedesktop.Notifications on the session bus
+    pub server: bool,
+}
+
+/// Why a notification was closed, as sent with NotificationClosed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseReason {
+    Expired = 1,
+    Closed = 3,
+}
+
+struct Notification {
+    id: u32,
+    app: String,
+    summary: String,
+    body: String,
+    urgency: u8,
+    /// How long it is shown, None when it stays until it is closed
+    timeout: Option<Duration>,
+    expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Notifications {
+    /// Open notifications, the last one is shown
+    open: Vec<Notification>,
+    /// Closed since the last update, still to be signalled
+    closed: Vec<(u32, CloseReason)>,
+    last_id: u32,
+}
+
+impl Notifications {
+    fn close(&mut self, id: u32, reason: CloseReason) -> bool {
+        let count = self.open.len();
+        self.open.retain(|notification| notification.id != id);
+        if self.open.len() == count {
+            return false;
+        }
+        self.closed.push((id, reason));
+        true
+    }
+
+    fn close_expired(&mut self, now: Instant) {
+        let expired: Vec<u32> = self
+            .open
+            .iter()
+            .filter(|notification| notification.expires.is_some_and(|time| time <= now))
+            .map(|notification| notification.id)
+            .collect();
+        for id in expired {
+            self.close(id, CloseReason::Expired);
+        }
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.open
+            .iter()
+            .filter_map(|notification| notification.expires)
+            .min()
+    }
+
+    /// Values for `NOTIFICATION_KEYS`, from the notification that is shown. They only change
+    /// with the shown notification, the timeout is the one it was created with.
+    fn values(&self) -> HashMap<&'static str, Option<StateValueType>> {
+        let shown = self.open.last();
+        let text = |text: fn(&Notification) -> &String| {
+            shown.map(|notification| StateValueType::String(text(notification).clone()))
+        };
+        let number = |number: fn(&Notification) -> u64| {
+            shown.map(|notification| StateValueType::U64(number(notification)))
+        };
+        let values = [
+            number(|n| n.id as u64),
+            text(|n| &n.app),
+            text(|n| &n.summary),
+            text(|n| &n.body),
+            number(|n| n.urgency as u64),
+            number(|n| {
+                n.timeout
+                    .map_or(0, |timeout| timeout.as_millis().max(1) as u64)
+            }),
+        ];
+        NOTIFICATION_KEYS.into_iter().zip(values).collect()
+    }
+}
+
+/// Serve org.freedesktop.Notifications, the handlers wake the main loop through `changed_tx`
+fn serve_notifications(
+    conn: &Arc<SyncConnection>,
+    notifications: Arc<SyncMutex<Notifications>>,
+    changed_tx: Sender<()>,
+) {
+    let mut cr = Crossroads::new();
+    let iface_token = cr.register(NOTIFICATIONS, |b| {
+        b.signal::<(u32, u32), _>("NotificationClosed", ("id", "reason"));
+        b.method(
+            "GetCapabilities",
+            (),
+            ("capabilities",),
+            |_: &mut Context, _: &mut Arc<SyncMutex<Notifications>>, (): ()| {
+                Ok((vec!["body".to_string()],))
+            },
+        );
+        b.method(
+            "GetServerInformation",
+            (),
+            ("name", "vendor", "version", "spec_version"),
+            |_: &mut Context, _: &mut Arc<SyncMutex<Notifications>>, (): ()| {
+                Ok((
+                    "TagDriver".to_string(),
+                    "remijn".to_string(),
+                    env!("CARGO_PKG_VERSION").to_string(),
+                    "1.2".to_string(),
+                ))
+            },
+        );
+        let notify_tx = changed_tx.clone();
+        b.method(
+            "Notify",
+            (
+                "app_name",
+                "replaces_id",
+                "app_icon",
+                "summary",
+                "body",
+                "actions",
+                "hints",
+                "expire_timeout",
+            ),
+            ("id",),
+            move |_: &mut Context,
+                  notifications: &mut Arc<SyncMutex<Notifications>>,
+                  (app, replaces_id, _icon, summary, body, _actions, hints, timeout): (
+                String,
+                u32,
+                String,
+                String,
+                String,
+                Vec<String>,
+                PropMap,
+                i32,
+            )| {
+                println!("{} Notification from {}: {}", log::DBUS, app, summary);
+                let urgency = hints
+                    .get("urgency")
+                    .and_then(|urgency| urgency.0.as_u64())
+                    .unwrap_or(1) as u8;
+                // Critical notifications stay until they are closed
+                let timeout = match timeout {
+                    0 => None,
+                    ..=-1 if urgency >= 2 => None,
+                    ..=-1 => Some(DEFAULT_TIMEOUT),
+                    ms => Some(Duration::from_millis(ms as u64)),
+                };
+
+                let mut notifications = notifications.lock().unwrap();
+                let replaced = replaces_id != 0
+                    && notifications
+                        .open
+                        .iter()
+                        .any(|notification| notification.id == replaces_id);
+                let id = if replaced {
+                    notifications
+                        .open
+                        .retain(|notification| notification.id != replaces_id);
+                    replaces_id
+                } else {
+                    notifications.last_id = notifications.last_id.wrapping_add(1).max(1);
+                    notifications.last_id
+                };
+                notifications.open.push(Notification {
+                    id,
+                    app,
+                    summary,
+                    body,
+                    urgency,
+                    timeout,
+                    expires: timeout.map(|timeout| Instant::now() + timeout),
+                });
+
+                notify_tx.try_send(()).ok();
+                Ok((id,))
+            },
+        );
+        let close_tx = changed_tx;
+        b.method(
+            "CloseNotification",
+            ("id",),
+            (),
+            move |_: &mut Context,
+                  notifications: &mut Arc<SyncMutex<Notifications>>,
+                  (id,): (u32,)| {
+                if !notifications.lock().unwrap().close(id, CloseReason::Closed) {
+                    return Err(MethodErr::invalid_arg("id"));
+                }
+                close_tx.try_send(()).ok();
+                Ok(())
+            },
+        );
+    });
+    cr.insert(NOTIFICATIONS_PATH, &[iface_token], notifications);
+
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            cr.handle_message(msg, conn).ok();
+            true
+        }),
+    );
+}
+
+/// Serve notifications on the session bus and show them in the state
+pub async fn run_notifications(
+    update_tx: Sender<()>,
+    state: Arc<Mutex<ApplicationState>>,
+) -> SourceResult {
+    let (resource, conn) = connection::new_session_sync()?;
+    serve_and_show(resource, conn, update_tx, state).await
+}
+
+/// Take the notifications name and put the shown notification in the state, until the
+/// connection is lost. Expired notifications are closed and signalled.
+async fn serve_and_show(
+    resource: IOResource<SyncConnection>,
+    conn: Arc<SyncConnection>,
+    update_tx: Sender<()>,
+    state: Arc<Mutex<ApplicationState>>,
+) -> SourceResult {
+    // The resource handles the messages of the connection, it only returns when it is lost
+    let mut resources = JoinSet::new();
+    resources.spawn(resource);
+
+    let notifications = Arc::new(SyncMutex::new(Notifications::default()));
+    let (changed_tx, mut changed_rx) = mpsc::channel::<()>(1);
+    serve_notifications(&conn, notifications.clone(), changed_tx);
+
+    // Do not queue behind another notification daemon, fail and try again later
+    let reply = conn.request_name(NOTIFICATIONS, false, false, true).await?;
+    if reply != dbus::nonblock::stdintf::org_freedesktop_dbus::RequestNameReply::PrimaryOwner {
+        return Err("another notification daemon is running".into());
+    }
+
+    loop {
+        let (values, next_expiry) = {
+            let now = Instant::now();
+            let mut notifications = notifications.lock().unwrap();
+            notifications.close_expired(now);
+            for (id, reason) in notifications.closed.drain(..) {
+                let signal = Message::signal(
+                    &Path::from(NOTIFICATIONS_PATH),
+                    &NOTIFICATIONS.into(),
+                    &"NotificationClosed".into(),
+                )
+                .append2(id, reason as u32);
+                conn.send(signal)
+                    .map_err(|()| "could not send NotificationClosed")?;
+            }
+            (notifications.values(), notifications.next_expiry())
+        };
+        if state.lock().await.update_multiple(values)? {
+            update_tx.send(()).await?;
+        }
+
+        tokio::select! {
+            Some(result) = resources.join_next() => {
+                return Err(match result {
+                    Ok(error) => error.into(),
+                    Err(error) => error.into(),
+                });
+            }
+            Some(()) = changed_rx.recv() => {}
+            _ = sleep_until(next_expiry.map_or_else(
+                || TokioInstant::now() + Duration::from_secs(3600),
+                TokioInstant::from_std,
+            )) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::{
+        arg::{RefArg, Variant},
+        nonblock::{MsgMatch, Proxy},
+    };
+    use tokio::time::sleep;
+
+    use crate::{
+        dbus::private_bus::{next_update, PrivateBus},
+        state::build_state_map,
+    };
+
+    use super::*;
+
+    struct Closed {
+        rx: mpsc::UnboundedReceiver<(u32, u32)>,
+        _match: MsgMatch,
+    }
+
+    async fn listen_closed(conn: &Arc<SyncConnection>) -> Closed {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rule = MatchRule::new_signal(NOTIFICATIONS, "NotificationClosed");
+        let msg_match =
+            conn.add_match(rule)
+                .await
+                .unwrap()
+                .cb(move |_, (id, reason): (u32, u32)| {
+                    tx.send((id, reason)).ok();
+                    true
+                });
+        Closed {
+            rx,
+            _match: msg_match,
+        }
+    }
+
+    async fn notify(
+        proxy: &Proxy<'_, Arc<SyncConnection>>,
+        summary: &str,
+        urgency: u8,
+        timeout: i32,
+    ) -> u32 {
+        let mut hints = PropMap::new();
+        hints.insert(
+            "urgency".to_string(),
+            Variant(Box::new(urgency) as Box<dyn RefArg>),
+        );
+        let (id,): (u32,) = proxy
+            .method_call(
+                NOTIFICATIONS,
+                "Notify",
+                (
+                    "test",
+                    0u32,
+                    "",
+                    summary,
+                    "Body",
+                    Vec::<String>::new(),
+                    hints,
+                    timeout,
+                ),
+            )
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn shows_and_closes_notifications() {
+        let Some(bus) = PrivateBus::start() else {
+            println!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let state = Arc::new(Mutex::new(build_state_map(&HashMap::new(), 0)));
+        let (update_tx, mut update_rx) = mpsc::channel(1);
+        let (resource, conn) = bus.connect();
+        tokio::spawn(serve_and_show(resource, conn, update_tx, state.clone()));
+
+        let (client_resource, client) = bus.connect();
+        tokio::spawn(client_resource);
+        let mut closed = listen_closed(&client).await;
+        let proxy = Proxy::new(
+            NOTIFICATIONS,
+            NOTIFICATIONS_PATH,
+            Duration::from_secs(2),
+            client.clone(),
+        );
+        // Wait until the server has its name
+        for _ in 0..50 {
+            let capabilities: Result<(Vec<String>,), _> = proxy
+                .method_call(NOTIFICATIONS, "GetCapabilities", ())
+                .await;
+            if capabilities.is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let critical = notify(&proxy, "Battery low", 2, -1).await;
+        next_update(&mut update_rx).await;
+        {
+            let state = state.lock().await;
+            assert_eq!(
+                state.get("notification:summary"),
+                Some(&StateValueType::String("Battery low".to_string()))
+            );
+            assert_eq!(
+                state.get("notification:urgency"),
+                Some(&StateValueType::U64(2))
+            );
+            assert_eq!(
+                state.get("notification:timeout"),
+                Some(&StateValueType::U64(0))
+            );
+        }
+
+        // A short one on top, the critical one is shown again when it expires
+        let short = notify(&proxy, "Copied", 1, 100).await;
+        next_update(&mut update_rx).await;
+        assert_eq!(
+            state.lock().await.get("notification:id"),
+            Some(&StateValueType::U64(short as u64))
+        );
+        assert_eq!(closed.rx.recv().await, Some((short, 1)));
+        next_update(&mut update_rx).await;
+        assert_eq!(
+            state.lock().await.get("notification:id"),
+            Some(&StateValueType::U64(critical as u64))
+        );
+
+        let _: () = proxy
+            .method_call(NOTIFICATIONS, "CloseNotification", (critical,))
+            .await
+            .unwrap();
+        assert_eq!(closed.rx.recv().await, Some((critical, 3)));
+        next_update(&mut update_rx).await;
+        let state = state.lock().await;
+        for key in NOTIFICATION_KEYS {
+            assert_eq!(state.get(key), None, "{}", key);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! A private bus for tests that need DBus services
+
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use dbus::{channel::Channel, nonblock::SyncConnection};
+use dbus_tokio::connection::{self, IOResource};
+use tokio::{sync::mpsc::Receiver, time::timeout};
+
+/// A dbus-daemon of our own, stopped when dropped
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    /// None when dbus-daemon is not installed
+    pub fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take()?)
+            .read_line(&mut address)
+            .ok()?;
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+
+    pub fn connect(&self) -> (IOResource<SyncConnection>, Arc<SyncConnection>) {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        connection::from_channel(channel).unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        self.daemon.kill().ok();
+    }
+}
+
+/// Wait for a source to update the state
+pub async fn next_update(update_rx: &mut Receiver<()>) {
+    timeout(Duration::from_secs(5), update_rx.recv())
+        .await
+        .expect("No update from the source");
+}
@@ -0,0 +1,116 @@
+use std::{error::Error, future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc::Sender, Mutex},
+    time::{sleep, Instant},
+};
+
+use crate::{log, state::app::ApplicationState};
+
+pub type SourceResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A source that ran this long before it failed is restarted without a long wait
+const STABLE_TIME: Duration = Duration::from_secs(60);
+
+/// Run a data source forever. When it fails or panics the error is logged, the state keys it
+/// feeds are marked unavailable and it is started again, waiting twice as long every time it
+/// fails right away.
+pub async fn supervise<S, F, K>(
+    name: &str,
+    state: Arc<Mutex<ApplicationState>>,
+    update_tx: Sender<()>,
+    keys: K,
+    mut start: S,
+) where
+    S: FnMut() -> F,
+    F: Future<Output = SourceResult> + Send + 'static,
+    K: Fn(&ApplicationState) -> Vec<String>,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        let error = match tokio::spawn(start()).await {
+            Ok(Ok(())) => "stopped".to_string(),
+            Ok(Err(error)) => error.to_string(),
+            Err(error) => error.to_string(),
+        };
+
+        if started.elapsed() >= STABLE_TIME {
+            backoff = MIN_BACKOFF;
+        }
+        println!(
+            "{} {} failed: {}, restarting in {}s",
+            log::ERROR,
+            name,
+            error,
+            backoff.as_secs()
+        );
+
+        let mut updated = false;
+        {
+            let mut state_lock = state.lock().await;
+            for key in keys(&state_lock) {
+                updated |= state_lock.update(&key, None).unwrap_or(false);
+            }
+        }
+        if updated {
+            update_tx.send(()).await.ok();
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use tokio::sync::mpsc;
+
+    use crate::state::{
+        app::ApplicationState,
+        value::{StateValue, StateValueType},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn restarts_and_marks_unavailable() {
+        let mut app_state = ApplicationState {
+            map: Default::default(),
+        };
+        app_state.map.insert(
+            "source:value".to_string(),
+            StateValue::filtered(Some(StateValueType::U64(1)), vec![]),
+        );
+        let state = Arc::new(Mutex::new(app_state));
+        let (update_tx, mut update_rx) = mpsc::channel(1);
+        let attempts = Arc::new(AtomicU32::new(0));
+
+        let supervised_attempts = attempts.clone();
+        tokio::spawn(supervise(
+            "test source",
+            state.clone(),
+            update_tx,
+            |_: &ApplicationState| vec!["source:value".to_string()],
+            move || {
+                let attempt = supervised_attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        panic!("first attempt");
+                    }
+                    std::future::pending().await
+                }
+            },
+        ));
+
+        update_rx.recv().await.expect("No update after the failure");
+        assert_eq!(state.lock().await.get("source:value"), None);
+
+        sleep(MIN_BACKOFF + Duration::from_millis(100)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub mod watch;
+
+const TTY_CLASS: &str = "sys/class/tty";
+
+/// A serial port that belongs to an interface of a USB device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbSerialDevice {
+    /// Device node, i.e. /dev/ttyACM0
+    pub port: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// USB interface number, every display on a board has its own interface
+    pub interface: u8,
+}
+
+impl std::fmt::Display for UsbSerialDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {} {} serial {} interface {}",
+            self.port,
+            self.vendor_id,
+            self.product_id,
+            self.manufacturer.as_deref().unwrap_or("?"),
+            self.product.as_deref().unwrap_or("?"),
+            self.serial.as_deref().unwrap_or("?"),
+            self.interface
+        )
+    }
+}
+
+/// Which USB devices are driver boards, every field that is set has to match
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self {
+            vendor_id: None,
+            product_id: None,
+            manufacturer: Some("RemijnPi".to_string()),
+            product: Some("Eink Driver".to_string()),
+        }
+    }
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, device: &UsbSerialDevice) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.product_id.is_none_or(|id| id == device.product_id)
+            && self
+                .manufacturer
+                .as_ref()
+                .is_none_or(|name| device.manufacturer.as_ref() == Some(name))
+            && self
+                .product
+                .as_ref()
+                .is_none_or(|name| device.product.as_ref() == Some(name))
+    }
+}
+
+/// List the USB serial ports in the sysfs tree under `root`, sorted by port.
+///
+/// `root` is `/` on a real system, the tests use a fake tree.
+pub fn scan_devices(root: &Path) -> io::Result<Vec<UsbSerialDevice>> {
+    let mut devices = Vec::new();
+
+    for entry in fs::read_dir(root.join(TTY_CLASS))? {
+        let entry = entry?;
+        // Virtual terminals have no device
+        let Ok(device_dir) = fs::canonicalize(entry.path().join("device")) else {
+            continue;
+        };
+        let Some(device) = read_device(&entry.file_name().to_string_lossy(), &device_dir) else {
+            continue;
+        };
+        devices.push(device);
+    }
+
+    devices.sort_by(|a, b| a.port.cmp(&b.port));
+    Ok(devices)
+}
+
+fn read_device(name: &str, device_dir: &Path) -> Option<UsbSerialDevice> {
+    // cdc-acm links to the USB interface, usb-serial drivers to a child of it
+    let interface_dir: PathBuf = device_dir
+        .ancestors()
+        .take(2)
+        .find(|dir| dir.join("bInterfaceNumber").exists())?
+        .to_path_buf();
+    let usb_dir = interface_dir.parent()?;
+
+    Some(UsbSerialDevice {
+        port: format!("/dev/{}", name),
+        vendor_id: read_hex(&usb_dir.join("idVendor"))?,
+        product_id: read_hex(&usb_dir.join("idProduct"))?,
+        manufacturer: read_string(&usb_dir.join("manufacturer")),
+        product: read_string(&usb_dir.join("product")),
+        serial: read_string(&usb_dir.join("serial")),
+        interface: read_hex(&interface_dir.join("bInterfaceNumber"))? as u8,
+    })
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    Some(value.trim().to_string())
+}
+
+fn read_hex(path: &Path) -> Option<u16> {
+    u16::from_str_radix(&read_string(path)?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// Add a board like the kernel shows it: /sys/devices/.../<usb>/<usb>:1.<interface>/tty/<tty>
+    fn add_board(root: &Path, usb: &str, serial: &str, interfaces: &[(u8, &str)]) {
+        let usb_dir = root.join("sys/devices/pci0000:00/usb1").join(usb);
+        fs::create_dir_all(&usb_dir).unwrap();
+        fs::write(usb_dir.join("idVendor"), "2e8a\n").unwrap();
+        fs::write(usb_dir.join("idProduct"), "000a\n").unwrap();
+        fs::write(usb_dir.join("manufacturer"), "RemijnPi\n").unwrap();
+        fs::write(usb_dir.join("product"), "Eink Driver\n").unwrap();
+        fs::write(usb_dir.join("serial"), format!("{}\n", serial)).unwrap();
+
+        for (interface, tty) in interfaces {
+            let interface_dir = usb_dir.join(format!("{}:1.{}", usb, interface));
+            let tty_dir = interface_dir.join("tty").join(tty);
+            fs::create_dir_all(&tty_dir).unwrap();
+            fs::write(
+                interface_dir.join("bInterfaceNumber"),
+                format!("{:02x}\n", interface),
+            )
+            .unwrap();
+            symlink(&interface_dir, tty_dir.join("device")).unwrap();
+            symlink(&tty_dir, root.join(TTY_CLASS).join(tty)).unwrap();
+        }
+    }
+
+    #[test]
+    fn scan_fake_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join(TTY_CLASS)).unwrap();
+        // A virtual terminal without a device
+        fs::create_dir_all(root.path().join(TTY_CLASS).join("tty0")).unwrap();
+
+        add_board(
+            root.path(),
+            "1-2",
+            "DE6270431F67292B",
+            &[(0, "ttyACM0"), (2, "ttyACM1"), (4, "ttyACM2")],
+        );
+
+        let devices = scan_devices(root.path()).unwrap();
+        assert_eq!(devices.len(), 3);
+        assert_eq!(
+            devices[1],
+            UsbSerialDevice {
+                port: "/dev/ttyACM1".to_string(),
+                vendor_id: 0x2e8a,
+                product_id: 0x000a,
+                manufacturer: Some("RemijnPi".to_string()),
+                product: Some("Eink Driver".to_string()),
+                serial: Some("DE6270431F67292B".to_string()),
+                interface: 2,
+            }
+        );
+        assert!(devices
+            .iter()
+            .all(|device| DeviceFilter::default().matches(device)));
+    }
+
+    #[test]
+    fn filter_devices() {
+        let mut device = UsbSerialDevice {
+            port: "/dev/ttyUSB0".to_string(),
+            vendor_id: 0x0403,
+            product_id: 0x6001,
+            manufacturer: Some("FTDI".to_string()),
+            product: Some("FT232R USB UART".to_string()),
+            serial: None,
+            interface: 0,
+        };
+        assert!(!DeviceFilter::default().matches(&device));
+
+        let filter = DeviceFilter {
+            vendor_id: Some(0x0403),
+            product_id: Some(0x6001),
+            manufacturer: None,
+            product: None,
+        };
+        assert!(filter.matches(&device));
+
+        device.product_id = 0x6015;
+        assert!(!filter.matches(&device));
+    }
+}
@@ -0,0 +1,44 @@
+use std::{io, time::Duration};
+
+use futures_util::StreamExt;
+use inotify::{Inotify, WatchMask};
+use tokio::{sync::mpsc::Sender, time::timeout};
+
+use crate::{control::ControlMessage, log};
+
+const DEV_DIR: &str = "/dev";
+
+/// udev sets up the permissions after the node is created, wait for it before opening the port
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Watch /dev for serial ports being added or removed, and request a new device scan.
+pub async fn watch_devices(control_tx: Sender<ControlMessage>) -> io::Result<()> {
+    let inotify = Inotify::init()?;
+    inotify
+        .watches()
+        .add(DEV_DIR, WatchMask::CREATE | WatchMask::DELETE)?;
+
+    let mut events = inotify.into_event_stream([0; 1024])?;
+    while let Some(event) = events.next().await {
+        let event = event?;
+        let is_tty = event
+            .name
+            .is_some_and(|name| name.to_string_lossy().starts_with("tty"));
+        if !is_tty {
+            continue;
+        }
+
+        // A board adds all its ports at once
+        while let Ok(Some(_)) = timeout(SETTLE_TIME, events.next()).await {}
+
+        println!("{} Serial ports changed", log::USB);
+        if control_tx
+            .send(ControlMessage::DevicesChanged)
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -70,18 +70,26 @@ impl BWRDisplay {
         (black_buffer, red_buffer)
     }
 
-    #[allow(dead_code)]
-    pub fn partial_buffer(&mut self, black_buffer: &[u8], point: Point, size: Size) -> Vec<u8> {
-        let mut pbuf: Vec<u8> = vec![0; (size.width * size.height / 8) as usize];
-
-        for tx in 0..size.width {
-            for ty in 0..size.height / 8 {
-                let buf_i: u32 = tx * size.height / 8 + ty;
-
-                let old_i: u32 =
-                    (tx + point.x as u32) * self.buffer_height / 8 + ty + (point.y as u32 / 8);
-
-                pbuf[buf_i as usize] = black_buffer[old_i as usize];
+    /// Crop an area out of a buffer from `get_fixed_buffer`, a red plane after the black one is cropped too
+    pub fn partial_buffer(&mut self, buffer: &[u8], point: Point, size: Size) -> Vec<u8> {
+        let plane_size = (self.width * self.buffer_height / 8) as usize;
+        let planes = buffer.len() / plane_size;
+        let mut pbuf: Vec<u8> = vec![0; (size.width * size.height / 8) as usize * planes];
+
+        for (plane, pbuf) in pbuf
+            .chunks_mut((size.width * size.height / 8) as usize)
+            .enumerate()
+        {
+            let plane_buffer = &buffer[plane * plane_size..];
+            for tx in 0..size.width {
+                for ty in 0..size.height / 8 {
+                    let buf_i: u32 = tx * size.height / 8 + ty;
+
+                    let old_i: u32 =
+                        (tx + point.x as u32) * self.buffer_height / 8 + ty + (point.y as u32 / 8);
+
+                    pbuf[buf_i as usize] = plane_buffer[old_i as usize];
+                }
             }
         }
         pbuf
@@ -17,25 +17,47 @@ use crate::{
     state::{app::ApplicationState, value::StateValueType},
 };
 
-use super::{ApplicationStateConsumer, DisplayAreaType, DisplayComponent, IconComponent};
+use super::{
+    icons::ValueIcon, ApplicationStateConsumer, DisplayAreaType, DisplayComponent, IconComponent,
+    NextRefresh, RefreshType,
+};
 
 pub struct BarDialog {
-    pub name: &'static str,
-    pub property: &'static str,
+    pub name: String,
+    pub property: String,
     pub display: u8,
     close_at: Instant,
     old_state: ApplicationState, // Values last drawn
-    _draw_icon: Box<dyn Fn(&mut Canvas<BWRColor>, f64, Point)>,
+    _draw_icon: ValueIcon,
 }
 
 const OPEN_TIME: Duration = Duration::from_secs(5);
+
+/// Draw an outlined bar in `area`, filled for `value` between 0.0 and 1.0
+pub fn draw_bar(
+    target: &mut Canvas<BWRColor>,
+    area: Rectangle,
+    value: f64,
+) -> Result<(), Box<dyn Error>> {
+    // Draw outline
+    area.into_styled(OUTLINE_STYLE_FG).draw(target)?;
+
+    // Draw fill
+    let filled_width = (value.clamp(0.0, 1.0) * area.size.width as f64) as u32;
+    Rectangle::new(area.top_left, Size::new(filled_width, area.size.height))
+        .into_styled(FILL_STYLE_FG)
+        .draw(target)?;
+
+    Ok(())
+}
+
 impl BarDialog {
     pub fn new(
-        name: &'static str,
-        property: &'static str,
+        name: String,
+        property: String,
         display: u8,
         initial_state: ApplicationState,
-        draw_icon: Box<dyn Fn(&mut Canvas<BWRColor>, f64, Point)>,
+        draw_icon: ValueIcon,
     ) -> Self {
         Self {
             name,
@@ -56,7 +78,7 @@ impl IconComponent for BarDialog {
 
 impl DisplayComponent for BarDialog {
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
     fn get_type(&self) -> DisplayAreaType {
         DisplayAreaType::Dialog
@@ -72,7 +94,7 @@ impl DisplayComponent for BarDialog {
     }
 
     fn get_z_index(&self, values: &ApplicationState) -> u32 {
-        let res = values.get(self.property);
+        let res = values.get(&self.property);
 
         if res.is_none() {
             println!(
@@ -90,7 +112,7 @@ impl DisplayComponent for BarDialog {
             )
         });
 
-        if let Some(val) = self.old_state.get(self.property) {
+        if let Some(val) = self.old_state.get(&self.property) {
             if val != value {
                 return 100; //changed value
             }
@@ -113,7 +135,7 @@ impl DisplayComponent for BarDialog {
         target: &mut Canvas<BWRColor>,
         values: &ApplicationState,
     ) -> Result<(), Box<dyn Error>> {
-        let res = values.get(self.property);
+        let res = values.get(&self.property);
         if res.is_none() {
             println!(
                 "{} Can't get z-index, property {} does not exist in values",
@@ -129,7 +151,7 @@ impl DisplayComponent for BarDialog {
             )
         });
 
-        if let Some(val) = self.old_state.get(self.property) {
+        if let Some(val) = self.old_state.get(&self.property) {
             if val != value {
                 // Different value, we reset timeout and open
                 self.close_at = Instant::now() + OPEN_TIME;
@@ -152,42 +174,39 @@ impl DisplayComponent for BarDialog {
             StateValueType::U64(val) => *val as f64,
             StateValueType::I64(val) => *val as f64,
             _ => {
-                panic!("Cannot convert to f64");
+                return Err(format!(
+                    "Property {} can not be shown as a bar, it is not a number",
+                    self.property
+                )
+                .into());
             }
         } / 100.0;
 
         self.draw_icon(target, float_value, icon_center);
 
-        let filled_width = (float_value * bar_width as f64) as u32;
-
-        // Draw outline
-        Rectangle::new(
-            Point { x: bar_x, y: bar_y },
-            Size {
-                width: bar_width,
-                height: bar_height,
-            },
-        )
-        .into_styled(OUTLINE_STYLE_FG)
-        .draw(target)?;
-
-        // Draw fill
-        Rectangle::new(
-            Point { x: bar_x, y: bar_y },
-            Size {
-                width: filled_width,
-                height: bar_height,
-            },
-        )
-        .into_styled(FILL_STYLE_FG)
-        .draw(target)?;
+        draw_bar(
+            target,
+            Rectangle::new(
+                Point { x: bar_x, y: bar_y },
+                Size {
+                    width: bar_width,
+                    height: bar_height,
+                },
+            ),
+            float_value,
+        )?;
 
         Ok(())
     }
 
-    fn get_refresh_at(&self) -> Option<Instant> {
+    // The popup changes often, a fast refresh of what changed is enough
+    fn get_refresh_type(&self) -> Option<RefreshType> {
+        Some(RefreshType::Partial)
+    }
+
+    fn get_next_refresh(&self) -> Option<NextRefresh> {
         if self.close_at > Instant::now() {
-            return Some(self.close_at);
+            return Some((self.close_at, RefreshType::Fast));
         }
         None
     }
@@ -195,7 +214,7 @@ impl DisplayComponent for BarDialog {
 
 impl ApplicationStateConsumer for BarDialog {
     fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
-        let property = self.property;
+        let property = self.property.as_str();
 
         let new_value = new_state
             .map
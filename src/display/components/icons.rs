@@ -0,0 +1,250 @@
+#![allow(clippy::type_complexity)]
+use core::fmt;
+
+use embedded_canvas::Canvas;
+use embedded_graphics::{
+    geometry::{Angle, OriginDimensions, Size},
+    image::Image,
+    prelude::Point,
+    primitives::{Arc, Circle, Primitive, PrimitiveStyle},
+    Drawable,
+};
+use embedded_icon::mdi::{
+    size32px::{
+        Battery, Battery10, Battery20, Battery30, Battery40, Battery50, Battery60, Battery70,
+        Battery80, Battery90, BatteryCharging10, BatteryCharging100, BatteryCharging20,
+        BatteryCharging30, BatteryCharging40, BatteryCharging50, BatteryCharging60,
+        BatteryCharging70, BatteryCharging80, BatteryCharging90, BatteryChargingOutline,
+        BatteryOffOutline, BatteryOutline, PowerPlug, WifiStrength1, WifiStrength2, WifiStrength3,
+        WifiStrength4, WifiStrengthAlertOutline, WifiStrengthOffOutline, WifiStrengthOutline,
+    },
+    size48px::{
+        Brightness1, Brightness2, Brightness3, Brightness4, Brightness5, Brightness6, Brightness7,
+        VolumeHigh, VolumeLow, VolumeMedium, VolumeVariantOff,
+    },
+};
+use embedded_icon::NewIcon;
+
+use crate::{
+    display::{bwr_color::BWRColor, COLOR_FG},
+    state::{
+        app::ApplicationState,
+        value::{NetworkState, StateValueType},
+    },
+};
+
+const ICON_COLOR: BWRColor = COLOR_FG;
+
+/// Icon for a `BarDialog`, drawn from the bar value between 0.0 and 1.0
+pub type ValueIcon = Box<dyn Fn(&mut Canvas<BWRColor>, f64, Point)>;
+
+/// Icon for a `StateItem`, drawn from the values in the state
+pub type StateIcon = Box<dyn Fn(&mut Canvas<BWRColor>, &ApplicationState, Point)>;
+
+pub fn brightness_icon() -> ValueIcon {
+    const BRIGHTNESS_ICON_COUNT: u32 = 6;
+    Box::new(|target: &mut Canvas<BWRColor>, val, center| {
+        match (val * BRIGHTNESS_ICON_COUNT as f64).round() as u32 {
+            6 => Image::with_center(&Brightness7::new(ICON_COLOR), center).draw(target),
+            5 => Image::with_center(&Brightness6::new(ICON_COLOR), center).draw(target),
+            4 => Image::with_center(&Brightness5::new(ICON_COLOR), center).draw(target),
+            3 => Image::with_center(&Brightness4::new(ICON_COLOR), center).draw(target),
+            2 => Image::with_center(&Brightness3::new(ICON_COLOR), center).draw(target),
+            1 => Image::with_center(&Brightness2::new(ICON_COLOR), center).draw(target),
+            _ => Image::with_center(&Brightness1::new(ICON_COLOR), center).draw(target),
+        }
+        .ok();
+    })
+}
+
+pub fn volume_icon() -> ValueIcon {
+    const PLAYER_VOLUME_ICON_COUNT: u32 = 3;
+    Box::new(|target: &mut Canvas<BWRColor>, val, center| {
+        match (val * PLAYER_VOLUME_ICON_COUNT as f64).ceil() as u16 {
+            3 => Image::with_center(&VolumeHigh::new(ICON_COLOR), center).draw(target),
+            2 => Image::with_center(&VolumeMedium::new(ICON_COLOR), center).draw(target),
+            1 => Image::with_center(&VolumeLow::new(ICON_COLOR), center).draw(target),
+            _ => Image::with_center(&VolumeVariantOff::new(ICON_COLOR), center).draw(target),
+        }
+        .ok();
+    })
+}
+
+enum BatteryState {
+    Unknown,
+    Charging,
+    Discharging,
+    Empty,
+    Full,
+}
+
+impl fmt::Debug for BatteryState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                BatteryState::Unknown => "Unknown",
+                BatteryState::Charging => "Charging",
+                BatteryState::Discharging => "Discharging",
+                BatteryState::Empty => "Empty",
+                BatteryState::Full => "Full",
+            }
+        )
+    }
+}
+
+fn draw_arc(target: &mut Canvas<BWRColor>, value: f64, center: Point) {
+    let circle = Circle::with_center(center, target.size().width.min(target.size().height) - 7);
+    Arc::from_circle(
+        circle,
+        Angle::from_degrees(-90.0),
+        Angle::from_degrees((360.0 * value) as f32),
+    )
+    .into_styled(PrimitiveStyle::with_stroke(ICON_COLOR, 6))
+    .draw(target)
+    .ok();
+}
+
+pub fn battery_icon(level_key: String, state_key: String) -> StateIcon {
+    const BATTERY_ICON_COUNT: u32 = 10;
+    Box::new(
+        move |target: &mut Canvas<BWRColor>, values: &ApplicationState, center: Point| {
+            let (Some(StateValueType::F64(level)), Some(StateValueType::U64(bat_state))) =
+                (values.get(&level_key), values.get(&state_key))
+            else {
+                // No battery info (yet)
+                Image::with_center(&BatteryOffOutline::new(ICON_COLOR), center)
+                    .draw(target)
+                    .ok();
+                return;
+            };
+
+            let bat_percentage = level / 100.0;
+
+            let bat_state = match bat_state {
+                0 => BatteryState::Unknown,
+                1 => BatteryState::Charging,
+                2 => BatteryState::Discharging,
+                3 => BatteryState::Empty,
+                4 => BatteryState::Full,
+                _ => BatteryState::Unknown,
+            };
+
+            match bat_state {
+                BatteryState::Unknown => {
+                    Image::with_center(&BatteryOffOutline::new(ICON_COLOR), center)
+                        .draw(target)
+                        .ok();
+                }
+                BatteryState::Full => {
+                    draw_arc(target, bat_percentage, center);
+                    Image::with_center(&PowerPlug::new(ICON_COLOR), center)
+                        .draw(target)
+                        .ok();
+                }
+                BatteryState::Discharging | BatteryState::Empty => {
+                    draw_arc(target, bat_percentage, center);
+                    match (bat_percentage * BATTERY_ICON_COUNT as f64).round() as u16 {
+                        10 => Image::with_center(&Battery::new(ICON_COLOR), center).draw(target),
+                        9 => Image::with_center(&Battery90::new(ICON_COLOR), center).draw(target),
+                        8 => Image::with_center(&Battery80::new(ICON_COLOR), center).draw(target),
+                        7 => Image::with_center(&Battery70::new(ICON_COLOR), center).draw(target),
+                        6 => Image::with_center(&Battery60::new(ICON_COLOR), center).draw(target),
+                        5 => Image::with_center(&Battery50::new(ICON_COLOR), center).draw(target),
+                        4 => Image::with_center(&Battery40::new(ICON_COLOR), center).draw(target),
+                        3 => Image::with_center(&Battery30::new(ICON_COLOR), center).draw(target),
+                        2 => Image::with_center(&Battery20::new(ICON_COLOR), center).draw(target),
+                        1 => Image::with_center(&Battery10::new(ICON_COLOR), center).draw(target),
+                        _ => Image::with_center(&BatteryOutline::new(ICON_COLOR), center)
+                            .draw(target),
+                    }
+                    .ok();
+                }
+                BatteryState::Charging => {
+                    draw_arc(target, bat_percentage, center);
+                    let center = center + Size::new(1, 0);
+                    match (bat_percentage * BATTERY_ICON_COUNT as f64).round() as u16 {
+                        10 => Image::with_center(&BatteryCharging100::new(ICON_COLOR), center)
+                            .draw(target),
+                        9 => Image::with_center(&BatteryCharging90::new(ICON_COLOR), center)
+                            .draw(target),
+                        8 => Image::with_center(&BatteryCharging80::new(ICON_COLOR), center)
+                            .draw(target),
+                        7 => Image::with_center(&BatteryCharging70::new(ICON_COLOR), center)
+                            .draw(target),
+                        6 => Image::with_center(&BatteryCharging60::new(ICON_COLOR), center)
+                            .draw(target),
+                        5 => Image::with_center(&BatteryCharging50::new(ICON_COLOR), center)
+                            .draw(target),
+                        4 => Image::with_center(&BatteryCharging40::new(ICON_COLOR), center)
+                            .draw(target),
+                        3 => Image::with_center(&BatteryCharging30::new(ICON_COLOR), center)
+                            .draw(target),
+                        2 => Image::with_center(&BatteryCharging20::new(ICON_COLOR), center)
+                            .draw(target),
+                        1 => Image::with_center(&BatteryCharging10::new(ICON_COLOR), center)
+                            .draw(target),
+                        _ => Image::with_center(&BatteryChargingOutline::new(ICON_COLOR), center)
+                            .draw(target),
+                    }
+                    .ok();
+                }
+            }
+        },
+    )
+}
+
+pub fn wifi_icon(state_key: String, strength_key: String) -> StateIcon {
+    Box::new(
+        move |target: &mut Canvas<BWRColor>, values: &ApplicationState, center: Point| {
+            let Some(StateValueType::NetworkState(state)) = values.get(&state_key) else {
+                // No network info (yet)
+                Image::with_center(&WifiStrengthOffOutline::new(ICON_COLOR), center)
+                    .draw(target)
+                    .ok();
+                return;
+            };
+
+            match state {
+                NetworkState::Connecting => {
+                    Image::with_center(&WifiStrengthAlertOutline::new(ICON_COLOR), center)
+                        .draw(target)
+                        .ok();
+                }
+                NetworkState::Connected => {
+                    let Some(StateValueType::F64(strength)) = values.get(&strength_key) else {
+                        Image::with_center(&WifiStrengthAlertOutline::new(ICON_COLOR), center)
+                            .draw(target)
+                            .ok();
+                        return;
+                    };
+                    match strength.round() as u32 {
+                        0..=20 => Image::with_center(&WifiStrengthOutline::new(ICON_COLOR), center)
+                            .draw(target),
+                        21..=40 => {
+                            Image::with_center(&WifiStrength1::new(ICON_COLOR), center).draw(target)
+                        }
+                        41..=60 => {
+                            Image::with_center(&WifiStrength2::new(ICON_COLOR), center).draw(target)
+                        }
+                        61..=80 => {
+                            Image::with_center(&WifiStrength3::new(ICON_COLOR), center).draw(target)
+                        }
+                        81..=100 => {
+                            Image::with_center(&WifiStrength4::new(ICON_COLOR), center).draw(target)
+                        }
+                        _ => Image::with_center(&WifiStrengthOutline::new(ICON_COLOR), center)
+                            .draw(target),
+                    }
+                    .ok();
+                }
+                _ => {
+                    Image::with_center(&WifiStrengthOffOutline::new(ICON_COLOR), center)
+                        .draw(target)
+                        .ok();
+                }
+            }
+        },
+    )
+}
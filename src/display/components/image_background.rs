@@ -1,50 +1,75 @@
-use std::{
-    io::{self},
-    path::Path,
-};
-
 use embedded_canvas::Canvas;
 use embedded_graphics::{
     geometry::{OriginDimensions, Point, Size},
     image::{Image, ImageDrawable},
     Drawable, Pixel,
 };
-use image::io::Reader as ImageReader;
-use thiserror::Error;
+use image::RgbaImage;
 use tinybmp::Bmp;
 
 use crate::{
     display::bwr_color::BWRColor,
+    library::{Bitmap, ImageLibrary, LibraryError},
     log,
     state::{app::ApplicationState, value::StateValueType},
 };
 
-use super::{ApplicationStateConsumer, DisplayComponent};
+use super::{ApplicationStateConsumer, DisplayComponent, RefreshType};
 
 pub struct StaticImageBackground<'a> {
-    pub name: &'a str,
+    pub name: String,
     pub display: u8,
+    pub z_index: u32,
     image: Box<Bmp<'a, BWRColor>>,
 }
 
 impl<'a> StaticImageBackground<'a> {
-    pub fn new(name: &'a str, display: u8, image: Box<Bmp<'a, BWRColor>>) -> Self {
+    pub fn new(name: String, display: u8, image: Box<Bmp<'a, BWRColor>>) -> Self {
         Self {
             name,
             display,
+            z_index: 10,
             image,
         }
     }
 }
 
-#[derive(Error, Debug)]
-pub enum LoadImageError {
-    #[error("File error")]
-    FileError(#[from] io::Error),
-    #[error("Error loading image")]
-    ImageError(#[from] image::ImageError),
-    #[error("Unknown error")]
-    Unknown,
+/// Dither an image into `BWRColor` with Floyd-Steinberg, strongly red pixels become red
+pub fn dither(image: &RgbaImage) -> Vec<Pixel<BWRColor>> {
+    let width = image.width() as usize;
+    // Error carried to the current and the next row
+    let mut errors = vec![[0i32; 2]; width + 2];
+    let mut pixels = Vec::with_capacity(width * image.height() as usize);
+
+    for (y, row) in image.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            let [r, g, b, _a] = pixel.0;
+            let point = Point::new(x as i32, y as i32);
+            if r > 128 && g < 128 && b < 128 {
+                pixels.push(Pixel(point, BWRColor::Red));
+                continue;
+            }
+
+            let luma = (r as i32 * 299 + g as i32 * 587 + b as i32 * 114) / 1000;
+            let value = luma + errors[x + 1][0] / 16;
+            let (color, error) = if value > 128 {
+                (BWRColor::On, value - 255)
+            } else {
+                (BWRColor::Off, value)
+            };
+            pixels.push(Pixel(point, color));
+
+            errors[x + 2][0] += error * 7;
+            errors[x][1] += error * 3;
+            errors[x + 1][1] += error * 5;
+            errors[x + 2][1] += error;
+        }
+        for error in errors.iter_mut() {
+            *error = [error[1], 0];
+        }
+    }
+
+    pixels
 }
 
 impl<'a> DisplayComponent for StaticImageBackground<'a> {
@@ -57,7 +82,7 @@ impl<'a> DisplayComponent for StaticImageBackground<'a> {
     }
 
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
 
     fn draw(
@@ -70,86 +95,59 @@ impl<'a> DisplayComponent for StaticImageBackground<'a> {
     }
 
     fn get_z_index(&self, _state: &ApplicationState) -> u32 {
-        10
+        self.z_index
     }
 }
 
-pub struct LoadingImageBackground<'a> {
-    pub name: &'a str,
+pub struct LoadingImageBackground {
+    pub name: String,
     pub display: u8,
     pub size: Size,
-    pub display_buffer: Vec<Pixel<BWRColor>>,
+    pub z_index: u32,
+    pub display_buffer: Bitmap,
     loaded: String,
-    pub image_property: &'a str,
+    pub image_property: String,
     pub old_state: ApplicationState, // Values last drawn
-    base_path: &'a Path,
+    library: ImageLibrary,
+    /// A new image was drawn, it is shown with a full refresh
+    image_changed: bool,
 }
 
-impl<'a> LoadingImageBackground<'a> {
+impl LoadingImageBackground {
     pub fn new(
-        name: &'a str,
+        name: String,
         display: u8,
         size: Size,
-        path_property: &'a str,
+        id_property: String,
         initial_state: ApplicationState,
-        base_path: &'a Path,
+        library: ImageLibrary,
     ) -> Self {
         Self {
             name,
             display,
             size,
-            display_buffer: vec![
-                Pixel(Point::zero(), BWRColor::Off);
-                (size.width * size.height) as usize
-            ],
+            z_index: 10,
+            display_buffer: Bitmap::default(),
             loaded: "".to_string(),
-            image_property: path_property,
+            image_property: id_property,
             old_state: initial_state,
-            base_path,
+            library,
+            image_changed: false,
         }
     }
 
-    pub fn load_image(&mut self, image: String) -> Result<(), LoadImageError> {
-        println!("{} Loading image: {}", log::RENDER, image);
-
-        let image_path = self.base_path.join(image.to_string() + ".png");
-
-        let img = ImageReader::open(image_path)?.decode()?.resize_exact(
-            self.size.width,
-            self.size.height,
-            image::imageops::FilterType::Nearest,
-        );
-        let img = img.into_rgba8();
-
-        let img = img.enumerate_pixels().map(|pix| {
-            Pixel(
-                Point {
-                    x: pix.0 as i32,
-                    y: pix.1 as i32,
-                },
-                match pix.2 .0 {
-                    [r, g, _b, _a] if (r > 128 && g < 128) => BWRColor::Red,
-                    [_r, g, _b, _a] if g > 128 => BWRColor::On,
-                    _ => BWRColor::Off,
-                },
-            )
-        });
-        self.loaded = image;
-
-        self.display_buffer = img.collect();
-
+    pub fn load_image(&mut self, id: String) -> Result<(), LibraryError> {
+        self.display_buffer = self.library.bitmap(&id, self.size)?;
+        self.loaded = id;
         Ok(())
     }
 
     pub fn clear_image(&mut self) {
-        self.display_buffer = vec![
-            Pixel(Point::zero(), BWRColor::Off);
-            (self.size.width * self.size.height) as usize
-        ];
+        self.display_buffer = Bitmap::default();
     }
 }
 
-impl<'a> DisplayComponent for LoadingImageBackground<'a> {
+impl DisplayComponent for LoadingImageBackground {
     fn get_display(&self) -> u8 {
         self.display
     }
@@ -159,7 +157,7 @@ impl<'a> DisplayComponent for LoadingImageBackground<'a> {
     }
 
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
 
     fn draw(
@@ -167,16 +165,23 @@ impl<'a> DisplayComponent for LoadingImageBackground<'a> {
         target: &mut Canvas<BWRColor>,
         _state: &ApplicationState,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let value = _state.get(self.image_property);
-
-        if let Some(StateValueType::String(state_path)) = value {
-            if *state_path != self.loaded {
-                let path = state_path.clone();
-                let res = self.load_image(path);
-                if res.is_err() {
+        let value = _state.get(&self.image_property);
+
+        self.image_changed = false;
+        match value {
+            Some(StateValueType::String(id)) if *id != self.loaded => {
+                self.image_changed = true;
+                if let Err(error) = self.load_image(id.clone()) {
+                    println!("{} Can't show image: {}", log::ERROR, error);
                     self.clear_image();
+                    self.loaded = id.clone();
                 }
             }
+            Some(StateValueType::String(_)) => {}
+            _ => {
+                self.loaded.clear();
+                self.clear_image();
+            }
         }
 
         Image::new(self, Point::new(0, 0)).draw(target)?;
@@ -185,8 +190,15 @@ impl<'a> DisplayComponent for LoadingImageBackground<'a> {
         Ok(())
     }
 
-    fn get_z_index(&self, _state: &ApplicationState) -> u32 {
-        10
+    // Hidden while no image is set
+    fn get_z_index(&self, state: &ApplicationState) -> u32 {
+        match state.get(&self.image_property) {
+            Some(StateValueType::String(_)) => self.z_index,
+            _ => 0,
+        }
+    }
+    fn get_refresh_type(&self) -> Option<RefreshType> {
+        self.image_changed.then_some(RefreshType::Full)
     }
     fn state_consumer(&self) -> Option<&dyn ApplicationStateConsumer> {
         Some(self)
@@ -197,14 +209,14 @@ impl<'a> DisplayComponent for LoadingImageBackground<'a> {
     }
 }
 
-impl<'a> ImageDrawable for LoadingImageBackground<'a> {
+impl ImageDrawable for LoadingImageBackground {
     type Color = BWRColor;
 
     fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
     where
         D: embedded_graphics::prelude::DrawTarget<Color = Self::Color>,
     {
-        target.draw_iter(self.display_buffer.clone().into_iter())?;
+        target.draw_iter(self.display_buffer.iter().copied())?;
         Ok(())
     }
 
@@ -220,41 +232,15 @@ impl<'a> ImageDrawable for LoadingImageBackground<'a> {
     }
 }
 
-impl<'a> OriginDimensions for LoadingImageBackground<'a> {
+impl OriginDimensions for LoadingImageBackground {
     fn size(&self) -> Size {
         self.size
     }
 }
 
-impl<'a> ApplicationStateConsumer for LoadingImageBackground<'a> {
+impl ApplicationStateConsumer for LoadingImageBackground {
     fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
-        let property = self.image_property;
-
-        let new_value = new_state
-            .map
-            .get(property)
-            .expect("Property not found in app state");
-
-        if let Some(new_value_type) = &new_value.get() {
-            let old_value = self
-                .old_state
-                .map
-                .get(property)
-                .expect("Property not found in old app state")
-                .get();
-
-            match new_value_type {
-                StateValueType::String(val) => {
-                    if let Some(StateValueType::String(old)) = &old_value {
-                        return *old != *val;
-                    } else {
-                        return true; // only new value, no old value, we should refresh
-                    }
-                }
-                _ => return false,
-            };
-        }
-        // our key was not found
-        false
+        let property = self.image_property.as_str();
+        self.old_state.get(property) != new_state.get(property)
     }
 }
@@ -0,0 +1,386 @@
+use embedded_graphics::{
+    geometry::{Point, Size},
+    primitives::Rectangle,
+};
+use embedded_icon::{
+    mdi::size48px::{Arch, Cannabis},
+    NewIcon,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tinybmp::Bmp;
+
+use crate::{
+    config::DisplayConfig,
+    dbus::{mpris::MEDIA_KEYS, notifications::NOTIFICATION_KEYS},
+    display::{bwr_color::BWRColor, COLOR_FG},
+    library::ImageLibrary,
+    state::app::ApplicationState,
+};
+
+use super::{
+    bar_dialog::BarDialog,
+    icons::{battery_icon, brightness_icon, volume_icon, wifi_icon},
+    image_background::{LoadingImageBackground, StaticImageBackground},
+    notification_dialog::NotificationDialog,
+    now_playing::NowPlaying,
+    simple_item::SimpleItem,
+    state_item::StateItem,
+    workspace_indicator::WorkspaceIndicator,
+    DisplayComponent,
+};
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error("Component '{component}' on display {display}: state key '{key}' does not exist")]
+    UnknownKey {
+        display: usize,
+        component: String,
+        key: String,
+    },
+    #[error("Component '{component}' on display {display}: {message}")]
+    Invalid {
+        display: usize,
+        component: String,
+        message: String,
+    },
+}
+
+/// Rectangle on the display, in rotated coordinates
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AreaConfig {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<AreaConfig> for Rectangle {
+    fn from(area: AreaConfig) -> Self {
+        Rectangle::new(
+            Point::new(area.x, area.y),
+            Size::new(area.width, area.height),
+        )
+    }
+}
+
+/// Icon shown next to the bar of a `bar_dialog`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BarIcon {
+    Brightness,
+    Volume,
+}
+
+/// Fixed icon of a `simple_item`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SimpleIcon {
+    Arch,
+    Cannabis,
+}
+
+/// Icon of a `state_item`, drawn from one or more state keys
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StateIcon {
+    /// keys: level, state
+    Battery,
+    /// keys: state, strength
+    Wifi,
+}
+
+impl StateIcon {
+    fn default_keys(&self) -> Vec<String> {
+        match self {
+            StateIcon::Battery => vec!["battery:level".to_string(), "battery:state".to_string()],
+            StateIcon::Wifi => vec!["wifi:state".to_string(), "wifi:strength".to_string()],
+        }
+    }
+}
+
+/// Images that are built into the driver
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinImage {
+    Logo250,
+    Logo400,
+}
+
+impl BuiltinImage {
+    fn bmp(&self) -> Bmp<'static, BWRColor> {
+        let bytes: &'static [u8] = match self {
+            BuiltinImage::Logo250 => include_bytes!("../../../resources/logo250.bmp"),
+            BuiltinImage::Logo400 => include_bytes!("../../../resources/logo400.bmp"),
+        };
+        Bmp::<BWRColor>::from_slice(bytes).expect("Builtin image is not a valid bmp")
+    }
+}
+
+/// A single component in the layout of a display
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ComponentConfig {
+    /// Fullscreen popup with an icon and a bar, shown when the value changes
+    BarDialog {
+        name: String,
+        key: String,
+        icon: BarIcon,
+    },
+    /// Static icon
+    SimpleItem {
+        name: String,
+        icon: SimpleIcon,
+        area: Option<AreaConfig>,
+        z_index: Option<u32>,
+    },
+    /// Icon that shows one or more state values
+    StateItem {
+        name: String,
+        icon: StateIcon,
+        keys: Option<Vec<String>>,
+        area: Option<AreaConfig>,
+        z_index: Option<u32>,
+    },
+    WorkspaceIndicator {
+        name: String,
+        area: AreaConfig,
+        /// active workspace, workspace count
+        keys: Option<(String, String)>,
+        z_index: Option<u32>,
+    },
+    /// Fullscreen image built into the driver
+    StaticImage {
+        name: String,
+        image: BuiltinImage,
+        z_index: Option<u32>,
+    },
+    /// Fullscreen image from the image library, its id is read from the state `key`
+    Image {
+        name: String,
+        key: String,
+        z_index: Option<u32>,
+    },
+    /// Title, artist, album art and progress of the active media player
+    NowPlaying {
+        name: String,
+        area: Option<AreaConfig>,
+        z_index: Option<u32>,
+    },
+    /// Fullscreen popup with the notification from the notification server
+    NotificationDialog { name: String },
+}
+
+impl ComponentConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            ComponentConfig::BarDialog { name, .. }
+            | ComponentConfig::SimpleItem { name, .. }
+            | ComponentConfig::StateItem { name, .. }
+            | ComponentConfig::WorkspaceIndicator { name, .. }
+            | ComponentConfig::StaticImage { name, .. }
+            | ComponentConfig::Image { name, .. }
+            | ComponentConfig::NowPlaying { name, .. }
+            | ComponentConfig::NotificationDialog { name } => name,
+        }
+    }
+
+    /// The state keys this component reads
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            ComponentConfig::BarDialog { key, .. } | ComponentConfig::Image { key, .. } => {
+                vec![key.clone()]
+            }
+            ComponentConfig::StateItem { icon, keys, .. } => {
+                keys.clone().unwrap_or_else(|| icon.default_keys())
+            }
+            ComponentConfig::WorkspaceIndicator { keys, .. } => {
+                let (active, count) = keys.clone().unwrap_or_else(default_workspace_keys);
+                vec![active, count]
+            }
+            ComponentConfig::NowPlaying { .. } => {
+                MEDIA_KEYS.iter().map(|key| key.to_string()).collect()
+            }
+            ComponentConfig::NotificationDialog { .. } => NOTIFICATION_KEYS
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+            ComponentConfig::SimpleItem { .. } | ComponentConfig::StaticImage { .. } => vec![],
+        }
+    }
+
+    /// Check the component against the display and state it will be used with
+    pub fn validate(
+        &self,
+        display: usize,
+        display_config: &DisplayConfig,
+        state: &ApplicationState,
+    ) -> Result<(), LayoutError> {
+        let invalid = |message: String| LayoutError::Invalid {
+            display,
+            component: self.name().to_string(),
+            message,
+        };
+
+        for key in self.keys() {
+            if !state.map.contains_key(&key) {
+                return Err(LayoutError::UnknownKey {
+                    display,
+                    component: self.name().to_string(),
+                    key,
+                });
+            }
+        }
+
+        if let ComponentConfig::StateItem { icon, keys, .. } = self {
+            let expected = icon.default_keys().len();
+            if keys.as_ref().is_some_and(|keys| keys.len() != expected) {
+                return Err(invalid(format!("icon {:?} needs {} keys", icon, expected)));
+            }
+        }
+
+        let area = match self {
+            ComponentConfig::SimpleItem { area, .. }
+            | ComponentConfig::StateItem { area, .. }
+            | ComponentConfig::NowPlaying { area, .. } => *area,
+            ComponentConfig::WorkspaceIndicator { area, .. } => Some(*area),
+            _ => None,
+        };
+        if let Some(area) = area {
+            let display_area = Rectangle::new(
+                Point::zero(),
+                Size::new(display_config.width, display_config.height),
+            );
+            let area: Rectangle = area.into();
+            if area.is_zero_sized()
+                || !display_area.contains(area.top_left)
+                || !area
+                    .bottom_right()
+                    .is_some_and(|corner| display_area.contains(corner))
+            {
+                return Err(invalid(format!(
+                    "area {:?} does not fit on the {}x{} display",
+                    area, display_config.width, display_config.height
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(
+        &self,
+        display: u8,
+        display_config: &DisplayConfig,
+        state: &ApplicationState,
+        library: &ImageLibrary,
+    ) -> Box<dyn DisplayComponent> {
+        const ICON_COLOR: BWRColor = COLOR_FG;
+
+        let name = self.name().to_string();
+        match self {
+            ComponentConfig::BarDialog { key, icon, .. } => {
+                let draw_icon = match icon {
+                    BarIcon::Brightness => brightness_icon(),
+                    BarIcon::Volume => volume_icon(),
+                };
+                Box::new(BarDialog::new(
+                    name,
+                    key.clone(),
+                    display,
+                    state.clone(),
+                    draw_icon,
+                ))
+            }
+            ComponentConfig::SimpleItem {
+                icon,
+                area,
+                z_index,
+                ..
+            } => {
+                let area = area.map(Rectangle::from);
+                match icon {
+                    SimpleIcon::Arch => {
+                        let mut item = SimpleItem::new(name, display, Arch::new(ICON_COLOR));
+                        item.area = area;
+                        item.z_index = z_index.unwrap_or(item.z_index);
+                        Box::new(item)
+                    }
+                    SimpleIcon::Cannabis => {
+                        let mut item = SimpleItem::new(name, display, Cannabis::new(ICON_COLOR));
+                        item.area = area;
+                        item.z_index = z_index.unwrap_or(item.z_index);
+                        Box::new(item)
+                    }
+                }
+            }
+            ComponentConfig::StateItem {
+                icon,
+                area,
+                z_index,
+                ..
+            } => {
+                let keys = self.keys();
+                let draw_icon = match icon {
+                    StateIcon::Battery => battery_icon(keys[0].clone(), keys[1].clone()),
+                    StateIcon::Wifi => wifi_icon(keys[0].clone(), keys[1].clone()),
+                };
+                let mut item = StateItem::new(name, keys, display, state.clone(), draw_icon);
+                item.area = area.map(Rectangle::from);
+                item.z_index = z_index.unwrap_or(item.z_index);
+                Box::new(item)
+            }
+            ComponentConfig::WorkspaceIndicator {
+                area,
+                keys,
+                z_index,
+                ..
+            } => {
+                let mut indicator = WorkspaceIndicator::new(
+                    name,
+                    display,
+                    (*area).into(),
+                    keys.clone().unwrap_or_else(default_workspace_keys),
+                    state.clone(),
+                );
+                indicator.z_index = z_index.unwrap_or(indicator.z_index);
+                Box::new(indicator)
+            }
+            ComponentConfig::StaticImage { image, z_index, .. } => {
+                let mut background =
+                    StaticImageBackground::new(name, display, Box::new(image.bmp()));
+                background.z_index = z_index.unwrap_or(background.z_index);
+                Box::new(background)
+            }
+            ComponentConfig::Image { key, z_index, .. } => {
+                let mut background = LoadingImageBackground::new(
+                    name,
+                    display,
+                    Size::new(display_config.width, display_config.height),
+                    key.clone(),
+                    state.clone(),
+                    library.clone(),
+                );
+                background.z_index = z_index.unwrap_or(background.z_index);
+                Box::new(background)
+            }
+            ComponentConfig::NowPlaying { area, z_index, .. } => {
+                let mut now_playing = NowPlaying::new(name, display, state.clone());
+                now_playing.area = area.map(Rectangle::from);
+                now_playing.z_index = z_index.unwrap_or(now_playing.z_index);
+                Box::new(now_playing)
+            }
+            ComponentConfig::NotificationDialog { .. } => {
+                Box::new(NotificationDialog::new(name, display, state.clone()))
+            }
+        }
+    }
+}
+
+fn default_workspace_keys() -> (String, String) {
+    (
+        "workspace:active".to_string(),
+        "workspace:count".to_string(),
+    )
+}
@@ -0,0 +1,229 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use embedded_canvas::Canvas;
+use embedded_graphics::{
+    geometry::{OriginDimensions, Point},
+    mono_font::{MonoFont, MonoTextStyle},
+    primitives::{Primitive, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+    Drawable,
+};
+use profont::{PROFONT_12_POINT, PROFONT_18_POINT};
+
+use crate::{
+    dbus::notifications::NOTIFICATION_KEYS,
+    display::{bwr_color::BWRColor, COLOR_FG, STROKE_WIDTH},
+    state::{app::ApplicationState, value::StateValueType},
+};
+
+use super::{
+    ApplicationStateConsumer, DisplayAreaType, DisplayComponent, NextRefresh, RefreshType,
+};
+
+/// Urgency of critical notifications, they are drawn in red
+const CRITICAL: u64 = 2;
+const MARGIN: u32 = 8;
+
+/// The notification from the notification server, shown until it expires or is closed
+pub struct NotificationDialog {
+    pub name: String,
+    pub display: u8,
+    old_state: ApplicationState, // Values last drawn
+    /// None while it stays open until it is closed
+    close_at: Option<Instant>,
+}
+
+impl NotificationDialog {
+    pub fn new(name: String, display: u8, initial_state: ApplicationState) -> Self {
+        Self {
+            name,
+            display,
+            old_state: initial_state,
+            close_at: None,
+        }
+    }
+
+    /// Whether the state holds another notification than the one last drawn, a replaced
+    /// notification changes the keys as well
+    fn changed(&self, values: &ApplicationState) -> bool {
+        NOTIFICATION_KEYS
+            .iter()
+            .any(|key| self.old_state.get(key) != values.get(key))
+    }
+
+    fn is_open(&self) -> bool {
+        notification_id(&self.old_state).is_some()
+            && self.close_at.is_none_or(|time| time > Instant::now())
+    }
+}
+
+fn notification_id(state: &ApplicationState) -> Option<u64> {
+    match state.get("notification:id") {
+        Some(StateValueType::U64(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+fn text<'a>(state: &'a ApplicationState, key: &str) -> &'a str {
+    match state.get(key) {
+        Some(StateValueType::String(text)) => text,
+        _ => "",
+    }
+}
+
+/// Break the text into the lines that fit `width` pixels of the mono font
+fn wrap(text: &str, font: &MonoFont, width: u32) -> Vec<String> {
+    let max = (width / (font.character_size.width + font.character_spacing)).max(1) as usize;
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            // Words that are longer than a line are broken up
+            while !word.is_empty() {
+                let used = line.chars().count();
+                let space = if used > 0 { 1 } else { 0 };
+                if used + space + word.len() <= max {
+                    if space > 0 {
+                        line.push(' ');
+                    }
+                    line.extend(word.drain(..));
+                } else if used > 0 {
+                    lines.push(std::mem::take(&mut line));
+                } else {
+                    line.extend(word.drain(..max));
+                    lines.push(std::mem::take(&mut line));
+                }
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+impl DisplayComponent for NotificationDialog {
+    fn get_display(&self) -> u8 {
+        self.display
+    }
+
+    fn get_type(&self) -> DisplayAreaType {
+        DisplayAreaType::Dialog
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn draw(
+        &mut self,
+        target: &mut Canvas<BWRColor>,
+        values: &ApplicationState,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.changed(values) {
+            // A new notification, open for its timeout. One that is shown again expires earlier,
+            // the server closes it then.
+            self.close_at = match values.get("notification:timeout") {
+                Some(StateValueType::U64(timeout)) if *timeout > 0 => {
+                    Some(Instant::now() + Duration::from_millis(*timeout))
+                }
+                _ => None,
+            };
+        }
+        self.old_state = values.clone();
+
+        let color = match values.get("notification:urgency") {
+            Some(StateValueType::U64(CRITICAL)) => BWRColor::Red,
+            _ => COLOR_FG,
+        };
+        let size = target.size();
+        Rectangle::new(Point::zero(), size)
+            .into_styled(PrimitiveStyle::with_stroke(color, STROKE_WIDTH))
+            .draw(target)?;
+
+        // App name, summary and as much of the body as fits
+        let width = size.width.saturating_sub(2 * MARGIN);
+        let bottom = size.height.saturating_sub(MARGIN) as i32;
+        let mut y = MARGIN as i32;
+        let lines = [
+            ("notification:app", &PROFONT_12_POINT),
+            ("notification:summary", &PROFONT_18_POINT),
+            ("notification:body", &PROFONT_12_POINT),
+        ]
+        .into_iter()
+        .flat_map(|(key, font)| {
+            wrap(text(values, key), font, width)
+                .into_iter()
+                .filter(|line| !line.is_empty())
+                .map(move |line| (line, font))
+        });
+        for (line, font) in lines {
+            let height = font.character_size.height as i32;
+            if y + height > bottom {
+                break;
+            }
+            Text::with_baseline(
+                &line,
+                Point::new(MARGIN as i32, y),
+                MonoTextStyle::new(font, color),
+                Baseline::Top,
+            )
+            .draw(target)?;
+            y += height + 2;
+        }
+
+        Ok(())
+    }
+
+    fn get_z_index(&self, values: &ApplicationState) -> u32 {
+        match notification_id(values) {
+            None => 0,
+            _ if self.changed(values) => 100, // new notification
+            _ if self.is_open() => 100,
+            _ => 0,
+        }
+    }
+
+    fn get_next_refresh(&self) -> Option<NextRefresh> {
+        match self.close_at {
+            Some(time) if self.is_open() => Some((time, RefreshType::Fast)),
+            _ => None,
+        }
+    }
+
+    fn state_consumer(&self) -> Option<&dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+
+    fn state_consumer_mut(&mut self) -> Option<&mut dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+}
+
+impl ApplicationStateConsumer for NotificationDialog {
+    fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
+        match notification_id(new_state) {
+            // Closing a notification that already expired on screen changes nothing
+            None => self.is_open(),
+            _ => self.changed(new_state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_words() {
+        // 5 characters per line
+        let width =
+            5 * (PROFONT_12_POINT.character_size.width + PROFONT_12_POINT.character_spacing);
+        assert_eq!(
+            wrap("ab cd efghijkl\nx", &PROFONT_12_POINT, width),
+            vec!["ab cd", "efghi", "jkl", "x"]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::{error::Error, path::PathBuf};
+
+use embedded_canvas::Canvas;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{OriginDimensions, Point, Size},
+    image::Image,
+    mono_font::{MonoFont, MonoTextStyle},
+    primitives::{Primitive, Rectangle},
+    text::{Baseline, Text},
+    Drawable, Pixel,
+};
+use embedded_icon::{
+    mdi::size32px::{Music, Pause, Play, Stop},
+    NewIcon,
+};
+use image::io::Reader as ImageReader;
+use profont::{PROFONT_14_POINT, PROFONT_18_POINT};
+
+use crate::{
+    display::{bwr_color::BWRColor, COLOR_FG, OUTLINE_STYLE_FG},
+    log,
+    state::{app::ApplicationState, value::StateValueType},
+};
+
+use super::{
+    bar_dialog::draw_bar, image_background::dither, ApplicationStateConsumer, DisplayAreaType,
+    DisplayComponent, RefreshType,
+};
+
+/// Keys of the track, a change of any of them redraws the component. The position is left
+/// out so the panel is not refreshed every second while playing.
+const TRACK_KEYS: [&str; 6] = [
+    "player:name",
+    "player:status",
+    "player:title",
+    "player:artist",
+    "player:album",
+    "player:art_url",
+];
+
+const MARGIN: u32 = 8;
+const ICON_SIZE: u32 = 32;
+const BAR_HEIGHT: u32 = 16;
+
+/// Title, artist, album art and progress of the active media player, hidden when no player
+/// is running
+pub struct NowPlaying {
+    pub name: String,
+    pub display: u8,
+    pub area: Option<Rectangle>, // Fullscreen when not set
+    pub z_index: u32,
+    old_state: ApplicationState, // Values last drawn
+    /// Art url and the dithered art, with the size it was loaded for
+    art: Option<(String, Size, Vec<Pixel<BWRColor>>)>,
+    track_changed: bool,
+}
+
+impl NowPlaying {
+    pub fn new(name: String, display: u8, initial_state: ApplicationState) -> Self {
+        Self {
+            name,
+            display,
+            area: None,
+            z_index: 15,
+            old_state: initial_state,
+            art: None,
+            track_changed: false,
+        }
+    }
+
+    /// Load and dither the art when the url or size changed, None if it can not be loaded
+    fn load_art(&mut self, url: &str, size: Size) -> Option<&[Pixel<BWRColor>]> {
+        let loaded = self
+            .art
+            .as_ref()
+            .is_some_and(|(art_url, art_size, _)| art_url == url && *art_size == size);
+        if !loaded {
+            println!("{} Loading album art: {}", log::RENDER, url);
+            let pixels = file_path(url)
+                .ok_or_else(|| "not a file:// url".into())
+                .and_then(|path| load_dithered(path, size))
+                .unwrap_or_else(|error| {
+                    println!("{} Can't load album art {}: {}", log::ERROR, url, error);
+                    vec![]
+                });
+            self.art = Some((url.to_string(), size, pixels));
+        }
+
+        self.art
+            .as_ref()
+            .map(|(_, _, pixels)| pixels.as_slice())
+            .filter(|pixels| !pixels.is_empty())
+    }
+}
+
+fn load_dithered(path: PathBuf, size: Size) -> Result<Vec<Pixel<BWRColor>>, Box<dyn Error>> {
+    let image = ImageReader::open(path)?.decode()?.resize_to_fill(
+        size.width,
+        size.height,
+        image::imageops::FilterType::Triangle,
+    );
+    Ok(dither(&image.into_rgba8()))
+}
+
+/// Path of a `file://` url, with the percent escapes decoded
+fn file_path(url: &str) -> Option<PathBuf> {
+    let encoded = url.strip_prefix("file://")?.as_bytes();
+    let mut path = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = (encoded[i] == b'%')
+            .then(|| encoded.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                path.push(byte);
+                i += 3;
+            }
+            None => {
+                path.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(path).ok()?))
+}
+
+/// Cut the text to `width` pixels of the mono font
+fn fit(text: &str, font: &MonoFont, width: u32) -> String {
+    let max = (width / (font.character_size.width + font.character_spacing)) as usize;
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut text: String = text.chars().take(max.saturating_sub(2)).collect();
+    text.push_str("..");
+    text
+}
+
+fn text<'a>(state: &'a ApplicationState, key: &str) -> Option<&'a str> {
+    match state.get(key) {
+        Some(StateValueType::String(text)) => Some(text),
+        _ => None,
+    }
+}
+
+fn seconds(state: &ApplicationState, key: &str) -> Option<u64> {
+    match state.get(key) {
+        Some(StateValueType::U64(seconds)) => Some(*seconds),
+        _ => None,
+    }
+}
+
+impl DisplayComponent for NowPlaying {
+    fn get_display(&self) -> u8 {
+        self.display
+    }
+
+    fn get_type(&self) -> DisplayAreaType {
+        match self.area {
+            Some(area) => DisplayAreaType::DisplayArea(area),
+            None => DisplayAreaType::Fullscreen,
+        }
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn draw(
+        &mut self,
+        target: &mut Canvas<BWRColor>,
+        values: &ApplicationState,
+    ) -> Result<(), Box<dyn Error>> {
+        self.track_changed = ["player:name", "player:title", "player:art_url"]
+            .iter()
+            .any(|key| self.old_state.get(key) != values.get(key));
+        self.old_state = values.clone();
+
+        let size = target.size();
+        let art_side = (size.height.saturating_sub(2 * MARGIN))
+            .min((size.width / 2).saturating_sub(MARGIN))
+            .max(1);
+        let art_area = Rectangle::new(
+            Point::new(MARGIN as i32, ((size.height - art_side) / 2) as i32),
+            Size::new(art_side, art_side),
+        );
+
+        // Album art, or a music note when there is none
+        let art = text(values, "player:art_url").and_then(|url| {
+            let url = url.to_string();
+            self.load_art(&url, art_area.size)
+        });
+        match art {
+            Some(pixels) => target.draw_iter(
+                pixels
+                    .iter()
+                    .map(|Pixel(point, color)| Pixel(*point + art_area.top_left, *color)),
+            )?,
+            None => {
+                art_area.into_styled(OUTLINE_STYLE_FG).draw(target)?;
+                Image::with_center(&Music::new(COLOR_FG), art_area.center()).draw(target)?;
+            }
+        }
+
+        // Title and artist next to the art
+        let column_x = art_area.top_left.x + (art_side + 2 * MARGIN) as i32;
+        let column_width = size.width.saturating_sub(column_x as u32 + MARGIN);
+        let mut y = art_area.top_left.y;
+        for (key, font) in [
+            ("player:title", &PROFONT_18_POINT),
+            ("player:artist", &PROFONT_14_POINT),
+        ] {
+            if let Some(value) = text(values, key) {
+                Text::with_baseline(
+                    &fit(value, font, column_width),
+                    Point::new(column_x, y),
+                    MonoTextStyle::new(font, COLOR_FG),
+                    Baseline::Top,
+                )
+                .draw(target)?;
+                y += (font.character_size.height + MARGIN / 2) as i32;
+            }
+        }
+
+        // Status icon and progress below it
+        let bottom = art_area.bottom_right().unwrap_or(art_area.top_left).y;
+        let icon_center = Point::new(
+            column_x + (ICON_SIZE / 2) as i32,
+            bottom - (ICON_SIZE / 2) as i32,
+        );
+        match text(values, "player:status") {
+            Some("Playing") => Image::with_center(&Play::new(COLOR_FG), icon_center).draw(target),
+            Some("Paused") => Image::with_center(&Pause::new(COLOR_FG), icon_center).draw(target),
+            _ => Image::with_center(&Stop::new(COLOR_FG), icon_center).draw(target),
+        }?;
+
+        let bar_x = column_x + (ICON_SIZE + MARGIN) as i32;
+        let progress = match (
+            seconds(values, "player:position"),
+            seconds(values, "player:length"),
+        ) {
+            (Some(position), Some(length)) if length > 0 => position as f64 / length as f64,
+            _ => 0.0,
+        };
+        draw_bar(
+            target,
+            Rectangle::new(
+                Point::new(bar_x, icon_center.y - (BAR_HEIGHT / 2) as i32),
+                Size::new(size.width.saturating_sub(bar_x as u32 + MARGIN), BAR_HEIGHT),
+            ),
+            progress,
+        )?;
+
+        Ok(())
+    }
+
+    fn get_z_index(&self, values: &ApplicationState) -> u32 {
+        match values.get("player:name") {
+            Some(_) => self.z_index,
+            None => 0,
+        }
+    }
+
+    // A new track is drawn with new art, a full refresh keeps it from ghosting
+    fn get_refresh_type(&self) -> Option<RefreshType> {
+        self.track_changed.then_some(RefreshType::Full)
+    }
+
+    fn state_consumer(&self) -> Option<&dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+
+    fn state_consumer_mut(&mut self) -> Option<&mut dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+}
+
+impl ApplicationStateConsumer for NowPlaying {
+    fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
+        TRACK_KEYS
+            .iter()
+            .any(|key| self.old_state.get(key) != new_state.get(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            file_path("file:///home/user/My%20Music/cover.jpg"),
+            Some(PathBuf::from("/home/user/My Music/cover.jpg"))
+        );
+        assert_eq!(file_path("https://example.com/cover.jpg"), None);
+    }
+
+    #[test]
+    fn dithers_gray_to_half_on() {
+        let gray = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+        let on = dither(&gray)
+            .iter()
+            .filter(|Pixel(_, color)| *color == BWRColor::On)
+            .count();
+        assert!((112..=144).contains(&on), "{} of 256 pixels on", on);
+
+        let red = RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        assert!(dither(&red)
+            .iter()
+            .all(|Pixel(_, color)| *color == BWRColor::Red));
+    }
+}
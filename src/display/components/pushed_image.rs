@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use embedded_canvas::Canvas;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{OriginDimensions, Size},
+    Pixel,
+};
+use image::{imageops::FilterType, ImageFormat, Rgba, RgbaImage};
+use thiserror::Error;
+
+use crate::{
+    display::bwr_color::BWRColor,
+    log,
+    state::{app::ApplicationState, value::StateValueType},
+};
+
+use super::{
+    image_background::dither, ApplicationStateConsumer, DisplayAreaType, DisplayComponent,
+    RefreshType,
+};
+
+#[derive(Error, Debug)]
+pub enum PushImageError {
+    #[error("Unsupported image type {0}")]
+    UnsupportedType(String),
+    #[error("Error decoding image")]
+    ImageError(#[from] image::ImageError),
+    #[error("Packed images have 1 or 2 bits per pixel, not {0}")]
+    UnsupportedDepth(u8),
+    #[error("Expected {expected} bytes for the packed image, got {got}")]
+    WrongLength { expected: usize, got: usize },
+    #[error("The packed image of {width}x{height} has more pixels than the display")]
+    TooLarge { width: u32, height: u32 },
+}
+
+/// Decode a png, jpeg or other image in memory, `mime` is its type, i.e. `image/png`
+pub fn decode_image(data: &[u8], mime: &str) -> Result<RgbaImage, PushImageError> {
+    let format = ImageFormat::from_mime_type(mime)
+        .ok_or_else(|| PushImageError::UnsupportedType(mime.to_string()))?;
+    Ok(image::load_from_memory_with_format(data, format)?.into_rgba8())
+}
+
+/// Unpack a raw image, the rows are packed with the first pixel in the highest bits and start
+/// on a new byte. With 1 bit per pixel 1 is on, with 2 bits 0 is off, 1 on and 2 or 3 red.
+/// The image can not have more pixels than `panel`.
+pub fn unpack_image(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    bits: u8,
+    panel: Size,
+) -> Result<RgbaImage, PushImageError> {
+    if width as u64 * height as u64 > panel.width as u64 * panel.height as u64 {
+        return Err(PushImageError::TooLarge { width, height });
+    }
+    if bits != 1 && bits != 2 {
+        return Err(PushImageError::UnsupportedDepth(bits));
+    }
+    let stride = (width as usize * bits as usize).div_ceil(8);
+    let expected = stride * height as usize;
+    if data.len() != expected {
+        return Err(PushImageError::WrongLength {
+            expected,
+            got: data.len(),
+        });
+    }
+
+    let per_byte = 8 / bits as u32;
+    let mask = (1u8 << bits) - 1;
+    Ok(RgbaImage::from_fn(width, height, |x, y| {
+        let byte = data[y as usize * stride + (x / per_byte) as usize];
+        let shift = 8 - bits as u32 * (x % per_byte + 1);
+        // Dithering keeps these colors as they are
+        match (byte >> shift) & mask {
+            0 => Rgba([0, 0, 0, 255]),
+            1 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([255, 0, 0, 255]),
+        }
+    }))
+}
+
+#[derive(Default)]
+struct Pushed {
+    /// For every display the id of its image and the image
+    images: HashMap<usize, (u64, Arc<RgbaImage>)>,
+    last_id: u64,
+    /// Size of every display, by index
+    sizes: Vec<Size>,
+}
+
+/// Images pushed over DBus, the last one of every display
+#[derive(Clone, Default)]
+pub struct PushedImages(Arc<Mutex<Pushed>>);
+
+impl PushedImages {
+    pub fn set_sizes(&self, sizes: Vec<Size>) {
+        self.0.lock().unwrap().sizes = sizes;
+    }
+
+    /// Size of the display, None when there is no such display
+    pub fn size(&self, display: usize) -> Option<Size> {
+        self.0.lock().unwrap().sizes.get(display).copied()
+    }
+
+    /// Keep the image for the display, returns the id to set in `display:<index>:pushed`
+    pub fn push(&self, display: usize, image: RgbaImage) -> u64 {
+        let mut pushed = self.0.lock().unwrap();
+        pushed.last_id += 1;
+        let id = pushed.last_id;
+        pushed.images.insert(display, (id, Arc::new(image)));
+        id
+    }
+
+    pub fn get(&self, display: usize, id: u64) -> Option<Arc<RgbaImage>> {
+        match self.0.lock().unwrap().images.get(&display) {
+            Some((image_id, image)) if *image_id == id => Some(image.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The image pushed to the display over DBus, hidden while `key` has no id
+pub struct PushedImage {
+    pub name: String,
+    pub display: u8,
+    pub z_index: u32,
+    key: String,
+    images: PushedImages,
+    old_state: ApplicationState, // Values last drawn
+    /// Id of the image and its dithered pixels
+    drawn: Option<(u64, Vec<Pixel<BWRColor>>)>,
+    image_changed: bool,
+}
+
+impl PushedImage {
+    pub fn new(
+        name: String,
+        display: u8,
+        key: String,
+        images: PushedImages,
+        initial_state: ApplicationState,
+    ) -> Self {
+        Self {
+            name,
+            display,
+            z_index: 13,
+            key,
+            images,
+            old_state: initial_state,
+            drawn: None,
+            image_changed: false,
+        }
+    }
+
+    /// Resize and dither the pushed image when it is new
+    fn load(&mut self, id: u64, size: Size) -> &[Pixel<BWRColor>] {
+        if self.drawn.as_ref().is_none_or(|(drawn, _)| *drawn != id) {
+            println!("{} Drawing pushed image {}", log::RENDER, id);
+            let pixels = match self.images.get(self.display as usize, id) {
+                Some(image) if image.dimensions() == (size.width, size.height) => dither(&image),
+                Some(image) => dither(&image::imageops::resize(
+                    image.as_ref(),
+                    size.width,
+                    size.height,
+                    FilterType::Triangle,
+                )),
+                None => vec![],
+            };
+            self.drawn = Some((id, pixels));
+        }
+        self.drawn
+            .as_ref()
+            .map(|(_, pixels)| pixels.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+fn image_id(state: &ApplicationState, key: &str) -> Option<u64> {
+    match state.get(key) {
+        Some(StateValueType::U64(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+impl DisplayComponent for PushedImage {
+    fn get_display(&self) -> u8 {
+        self.display
+    }
+
+    fn get_type(&self) -> DisplayAreaType {
+        DisplayAreaType::Fullscreen
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn draw(
+        &mut self,
+        target: &mut Canvas<BWRColor>,
+        values: &ApplicationState,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = image_id(values, &self.key);
+        self.image_changed = id != image_id(&self.old_state, &self.key);
+        self.old_state = values.clone();
+
+        match id {
+            Some(id) => {
+                let size = target.size();
+                target.draw_iter(self.load(id, size).iter().copied())?;
+            }
+            None => self.drawn = None,
+        }
+        Ok(())
+    }
+
+    fn get_z_index(&self, values: &ApplicationState) -> u32 {
+        match image_id(values, &self.key) {
+            Some(_) => self.z_index,
+            None => 0,
+        }
+    }
+
+    fn get_refresh_type(&self) -> Option<RefreshType> {
+        self.image_changed.then_some(RefreshType::Full)
+    }
+
+    fn state_consumer(&self) -> Option<&dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+
+    fn state_consumer_mut(&mut self) -> Option<&mut dyn ApplicationStateConsumer> {
+        Some(self)
+    }
+}
+
+impl ApplicationStateConsumer for PushedImage {
+    fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
+        self.old_state.get(&self.key) != new_state.get(&self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpacks_packed_images() {
+        let panel = Size::new(4, 2);
+        // 3 pixels wide, so the rows start on a new byte
+        let image = unpack_image(&[0b1010_0000, 0b0100_0000], 3, 2, 1, panel).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 1), &Rgba([255, 255, 255, 255]));
+
+        let image = unpack_image(&[0b0001_1000], 3, 1, 2, panel).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(image.get_pixel(2, 0), &Rgba([255, 0, 0, 255]));
+
+        assert!(matches!(
+            unpack_image(&[0; 3], 3, 2, 1, panel),
+            Err(PushImageError::WrongLength {
+                expected: 2,
+                got: 3
+            })
+        ));
+        assert!(matches!(
+            unpack_image(&[0; 5], 5, 2, 1, panel),
+            Err(PushImageError::TooLarge { .. })
+        ));
+    }
+}
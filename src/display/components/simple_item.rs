@@ -1,7 +1,8 @@
 use embedded_canvas::Canvas;
 use embedded_graphics::{
-    geometry::{Point, Size},
+    geometry::{OriginDimensions, Point, Size},
     image::Image,
+    primitives::Rectangle,
     Drawable,
 };
 use embedded_icon::{EmbeddedIcon, Icon};
@@ -11,19 +12,23 @@ use crate::{display::bwr_color::BWRColor, state::app::ApplicationState};
 use super::{DisplayComponent, IconComponent};
 
 pub struct SimpleItem<T: EmbeddedIcon> {
-    pub name: &'static str,
+    pub name: String,
     pub display: u8,
     pub size: Size,
     pub icon: Icon<BWRColor, T>,
+    pub area: Option<Rectangle>, // Fixed position, instead of the icon row
+    pub z_index: u32,
 }
 
 impl<T: EmbeddedIcon> SimpleItem<T> {
-    pub fn new(name: &'static str, display: u8, icon: Icon<BWRColor, T>) -> Self {
+    pub fn new(name: String, display: u8, icon: Icon<BWRColor, T>) -> Self {
         Self {
             name,
             display,
             size: Size::new(50, 50),
             icon,
+            area: None,
+            z_index: 20,
         }
     }
 }
@@ -40,11 +45,14 @@ impl<T: EmbeddedIcon> DisplayComponent for SimpleItem<T> {
     }
 
     fn get_type(&self) -> super::DisplayAreaType {
-        super::DisplayAreaType::Icon(self.size)
+        match self.area {
+            Some(area) => super::DisplayAreaType::DisplayArea(area),
+            None => super::DisplayAreaType::Icon(self.size),
+        }
     }
 
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
 
     fn draw(
@@ -52,14 +60,15 @@ impl<T: EmbeddedIcon> DisplayComponent for SimpleItem<T> {
         target: &mut Canvas<BWRColor>,
         _values: &ApplicationState,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let center = Point::new((self.size.width / 2) as i32, (self.size.height / 2) as i32);
+        let size = target.size();
+        let center = Point::new((size.width / 2) as i32, (size.height / 2) as i32);
         self.draw_icon(target, 0.0, center);
 
         Ok(())
     }
 
     fn get_z_index(&self, _values: &ApplicationState) -> u32 {
-        20
+        self.z_index
     }
 
     fn state_consumer(&self) -> Option<&dyn super::ApplicationStateConsumer> {
@@ -1,35 +1,42 @@
 #![allow(clippy::type_complexity)]
 use embedded_canvas::Canvas;
-use embedded_graphics::geometry::{Point, Size};
+use embedded_graphics::{
+    geometry::{OriginDimensions, Point, Size},
+    primitives::Rectangle,
+};
 
 use crate::{
     display::bwr_color::BWRColor,
     state::{app::ApplicationState, value::StateValueType},
 };
 
-use super::{ApplicationStateConsumer, DisplayComponent, IconComponent};
+use super::{icons::StateIcon, ApplicationStateConsumer, DisplayComponent, IconComponent};
 
 pub struct StateItem {
-    pub name: &'static str,
-    pub properties: Vec<&'static str>,
+    pub name: String,
+    pub properties: Vec<String>,
     pub display: u8,
     pub size: Size,
+    pub area: Option<Rectangle>, // Fixed position, instead of the icon row
+    pub z_index: u32,
     old_state: ApplicationState, // Values last drawn
-    _draw_icon: Box<dyn Fn(&mut Canvas<BWRColor>, &ApplicationState, Point)>,
+    _draw_icon: StateIcon,
 }
 
 impl StateItem {
     pub fn new(
-        name: &'static str,
-        properties: Vec<&'static str>,
+        name: String,
+        properties: Vec<String>,
         display: u8,
         initial_state: ApplicationState,
-        draw_icon: Box<dyn Fn(&mut Canvas<BWRColor>, &ApplicationState, Point)>,
+        draw_icon: StateIcon,
     ) -> Self {
         Self {
             name,
             display,
             size: Size::new(50, 50),
+            area: None,
+            z_index: 20,
             old_state: initial_state,
             properties,
             _draw_icon: draw_icon,
@@ -49,11 +56,14 @@ impl DisplayComponent for StateItem {
     }
 
     fn get_type(&self) -> super::DisplayAreaType {
-        super::DisplayAreaType::Icon(self.size)
+        match self.area {
+            Some(area) => super::DisplayAreaType::DisplayArea(area),
+            None => super::DisplayAreaType::Icon(self.size),
+        }
     }
 
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
 
     fn draw(
@@ -63,7 +73,8 @@ impl DisplayComponent for StateItem {
     ) -> Result<(), Box<dyn std::error::Error>> {
         self.old_state = values.clone();
 
-        let center = Point::new((self.size.width / 2) as i32, (self.size.height / 2) as i32);
+        let size = target.size();
+        let center = Point::new((size.width / 2) as i32, (size.height / 2) as i32);
 
         self.draw_icon(target, 100.0, center);
 
@@ -71,7 +82,7 @@ impl DisplayComponent for StateItem {
     }
 
     fn get_z_index(&self, _values: &ApplicationState) -> u32 {
-        20
+        self.z_index
     }
 
     fn state_consumer(&self) -> Option<&dyn super::ApplicationStateConsumer> {
@@ -156,6 +167,8 @@ impl ApplicationStateConsumer for StateItem {
                         }
                     }
                 };
+            } else if self.old_state.get(property).is_some() {
+                return true; // the value is no longer available
             }
         }
         // our key was not found
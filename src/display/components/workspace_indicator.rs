@@ -12,33 +12,35 @@ use crate::{
 
 use super::{ApplicationStateConsumer, DisplayComponent};
 
-pub struct WorkspaceIndicator<'a> {
-    pub name: &'a str,
+pub struct WorkspaceIndicator {
+    pub name: String,
     pub display: u8,
     pub area: Rectangle,
-    pub properties: (&'a str, &'a str),
+    pub properties: (String, String),
+    pub z_index: u32,
     pub old_state: ApplicationState, // Values last drawn
 }
 
-impl<'a> WorkspaceIndicator<'a> {
+impl WorkspaceIndicator {
     pub fn new(
-        name: &'a str,
+        name: String,
         display: u8,
         area: Rectangle,
-        properties: (&'a str, &'a str),
+        properties: (String, String),
         initial_state: ApplicationState,
     ) -> Self {
         Self {
             name,
             display,
             area,
+            z_index: 10,
             old_state: initial_state,
             properties,
         }
     }
 }
 
-impl<'a> DisplayComponent for WorkspaceIndicator<'a> {
+impl DisplayComponent for WorkspaceIndicator {
     fn get_display(&self) -> u8 {
         self.display
     }
@@ -48,7 +50,7 @@ impl<'a> DisplayComponent for WorkspaceIndicator<'a> {
     }
 
     fn get_name(&self) -> &str {
-        self.name
+        &self.name
     }
 
     fn draw(
@@ -56,15 +58,20 @@ impl<'a> DisplayComponent for WorkspaceIndicator<'a> {
         target: &mut Canvas<BWRColor>,
         state: &ApplicationState,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let active_prop = self.properties.0;
-        let count_prop = self.properties.1;
+        let active_prop = self.properties.0.as_str();
+        let count_prop = self.properties.1.as_str();
 
-        let StateValueType::U64(active) = state.get(active_prop).unwrap() else {
-            panic!("Active Workspace is not a u64")
+        self.old_state = state.clone();
+
+        let Some(StateValueType::U64(active)) = state.get(active_prop) else {
+            return Err(format!("Active Workspace {} is not a u64", active_prop).into());
         };
-        let StateValueType::U64(count) = state.get(count_prop).unwrap() else {
-            panic!("Workspace Count is not a u64")
+        let Some(StateValueType::U64(count)) = state.get(count_prop) else {
+            return Err(format!("Workspace Count {} is not a u64", count_prop).into());
         };
+        if *count == 0 {
+            return Ok(());
+        }
 
         let active: u32 = *active as u32;
         let count: u32 = *count as u32;
@@ -105,12 +112,11 @@ impl<'a> DisplayComponent for WorkspaceIndicator<'a> {
             }
         }
 
-        self.old_state = state.clone();
         Ok(())
     }
 
     fn get_z_index(&self, _state: &ApplicationState) -> u32 {
-        10
+        self.z_index
     }
     fn state_consumer(&self) -> Option<&dyn ApplicationStateConsumer> {
         Some(self)
@@ -121,12 +127,12 @@ impl<'a> DisplayComponent for WorkspaceIndicator<'a> {
     }
 }
 
-impl<'a> ApplicationStateConsumer for WorkspaceIndicator<'a> {
+impl ApplicationStateConsumer for WorkspaceIndicator {
     fn needs_refresh(&self, new_state: &ApplicationState) -> bool {
         let mut update = false;
 
-        let active_prop = self.properties.0;
-        let count_prop = self.properties.1;
+        let active_prop = self.properties.0.as_str();
+        let count_prop = self.properties.1.as_str();
 
         update |= self.old_state.get(active_prop) != new_state.get(active_prop);
         update |= self.old_state.get(count_prop) != new_state.get(count_prop);
@@ -9,17 +9,57 @@ use self::bwr_color::BWRColor;
 
 use embedded_graphics::{mono_font::MonoTextStyle, primitives::*};
 use profont::PROFONT_24_POINT;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
 pub enum DisplayRotation {
+    #[default]
     Zero,
     Rotate90,
     Rotate180,
     Rotate270,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+impl DisplayRotation {
+    /// Rotations of 90 and 270 degrees swap the width and height of the panel
+    pub fn is_portrait(&self) -> bool {
+        matches!(self, DisplayRotation::Rotate90 | DisplayRotation::Rotate270)
+    }
+}
+
+impl TryFrom<u16> for DisplayRotation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(DisplayRotation::Zero),
+            90 => Ok(DisplayRotation::Rotate90),
+            180 => Ok(DisplayRotation::Rotate180),
+            270 => Ok(DisplayRotation::Rotate270),
+            _ => Err(format!(
+                "invalid rotation {}, expected 0, 90, 180 or 270",
+                degrees
+            )),
+        }
+    }
+}
+
+impl From<DisplayRotation> for u16 {
+    fn from(rotation: DisplayRotation) -> Self {
+        match rotation {
+            DisplayRotation::Zero => 0,
+            DisplayRotation::Rotate90 => 90,
+            DisplayRotation::Rotate180 => 180,
+            DisplayRotation::Rotate270 => 270,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DisplayFlip {
+    #[default]
     None,
     Horizontal,
     Vertical,
@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::eink::{
+    panel::PanelImage,
+    transport::{EInkTransport, OpenTransport},
+    EInkResponse,
+};
+
+/// A frame that was shown on a `MemoryTransport`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShownFrame {
+    pub panel: PanelImage,
+    pub full_refresh: bool,
+    pub border: bool,
+}
+
+/// Everything a `MemoryTransport` was asked to do
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    pub resets: u32,
+    pub frames: Vec<ShownFrame>,
+    pub leds: Vec<u8>,
+}
+
+/// Keeps the frames in memory, so tests can run the serial thread without hardware
+pub struct MemoryTransport {
+    panel: PanelImage,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl MemoryTransport {
+    pub fn new(recording: Arc<Mutex<Recording>>, width: u32, height: u32) -> Self {
+        Self {
+            panel: PanelImage::new(width, height),
+            recording,
+        }
+    }
+
+    /// Open function for the serial thread, every (re)connect records into the same `recording`
+    pub fn opener(recording: Arc<Mutex<Recording>>, width: u32, height: u32) -> OpenTransport {
+        Box::new(move || {
+            Ok(
+                Box::new(MemoryTransport::new(recording.clone(), width, height))
+                    as Box<dyn EInkTransport>,
+            )
+        })
+    }
+
+    fn recording(&self) -> std::sync::MutexGuard<'_, Recording> {
+        self.recording.lock().expect("Recording lock poisoned")
+    }
+}
+
+#[async_trait]
+impl EInkTransport for MemoryTransport {
+    async fn reset(&mut self) -> Result<(), EInkResponse> {
+        self.recording().resets += 1;
+        Ok(())
+    }
+
+    async fn send_image(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        self.panel.write(data, x, y, width, height, with_red)
+    }
+
+    async fn show(&mut self, full_refresh: bool, border: bool) -> Result<(), EInkResponse> {
+        let frame = ShownFrame {
+            panel: self.panel.clone(),
+            full_refresh,
+            border,
+        };
+        self.recording().frames.push(frame);
+        Ok(())
+    }
+
+    async fn set_led(&mut self, color: u8) -> Result<(), EInkResponse> {
+        self.recording().leds.push(color);
+        Ok(())
+    }
+
+    async fn wait_ready(&mut self) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::{fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::log;
+
+use super::{
+    transport::{EInkTransport, OpenTransport},
+    uart_interface::EInkUartInterface,
+    EInkResponse,
+};
+
+#[cfg(test)]
+pub mod memory;
+pub mod png;
+pub mod simulator;
+
+/// Where the frames of a display go
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BackendConfig {
+    /// Driver board on a serial port
+    #[default]
+    Uart,
+    /// Write every frame to a png file
+    Png { path: PathBuf },
+    /// Show the frames in a window
+    Simulator {
+        #[serde(default = "default_scale")]
+        scale: u32,
+    },
+}
+
+fn default_scale() -> u32 {
+    2
+}
+
+impl fmt::Display for BackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendConfig::Uart => write!(f, "uart"),
+            BackendConfig::Png { path } => write!(f, "png {}", path.display()),
+            BackendConfig::Simulator { .. } => write!(f, "simulator"),
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Open function for the serial thread, the port is only used by the UART backend
+    pub fn opener(&self, port: &str, baud: u32, width: u32, height: u32) -> OpenTransport {
+        match self.clone() {
+            BackendConfig::Uart => {
+                let port = port.to_string();
+                Box::new(move || {
+                    let interface = EInkUartInterface::open(&port, baud).map_err(|error| {
+                        println!(
+                            "{} Failed to connect to device {}: {}",
+                            log::ERROR,
+                            port,
+                            error
+                        );
+                        EInkResponse::from(error)
+                    })?;
+                    Ok(Box::new(interface) as Box<dyn EInkTransport>)
+                })
+            }
+            BackendConfig::Png { path } => Box::new(move || {
+                Ok(
+                    Box::new(png::PngTransport::new(path.clone(), width, height))
+                        as Box<dyn EInkTransport>,
+                )
+            }),
+            BackendConfig::Simulator { scale } => {
+                let title = format!("TagDriver {}", port);
+                Box::new(move || {
+                    Ok(Box::new(simulator::SimulatorTransport::open(
+                        &title, scale, width, height,
+                    )) as Box<dyn EInkTransport>)
+                })
+            }
+        }
+    }
+}
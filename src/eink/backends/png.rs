@@ -0,0 +1,70 @@
+use std::{fs, path::PathBuf};
+
+use async_trait::async_trait;
+use image::ImageFormat;
+
+use crate::{
+    eink::{panel::PanelImage, transport::EInkTransport, EInkResponse},
+    log,
+};
+
+/// Writes the panel to a png file every time it is shown
+pub struct PngTransport {
+    path: PathBuf,
+    panel: PanelImage,
+}
+
+impl PngTransport {
+    pub fn new(path: PathBuf, width: u32, height: u32) -> Self {
+        Self {
+            path,
+            panel: PanelImage::new(width, height),
+        }
+    }
+}
+
+#[async_trait]
+impl EInkTransport for PngTransport {
+    async fn reset(&mut self) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+
+    async fn send_image(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        self.panel.write(data, x, y, width, height, with_red)
+    }
+
+    async fn show(&mut self, _full_refresh: bool, _border: bool) -> Result<(), EInkResponse> {
+        // Write next to the file and move it over, so readers never see half a png
+        let temp_path = self.path.with_extension("png.tmp");
+        self.panel
+            .to_rgb()
+            .save_with_format(&temp_path, ImageFormat::Png)
+            .map_err(|error| {
+                println!(
+                    "{} Could not write {}: {}",
+                    log::ERROR,
+                    temp_path.display(),
+                    error
+                );
+                EInkResponse::Error
+            })?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    async fn set_led(&mut self, _color: u8) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+
+    async fn wait_ready(&mut self) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+}
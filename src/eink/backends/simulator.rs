@@ -0,0 +1,110 @@
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use embedded_graphics::{
+    pixelcolor::Rgb888,
+    prelude::{DrawTarget, Point, RgbColor, Size},
+    Pixel,
+};
+use embedded_graphics_simulator::{
+    OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+};
+
+use crate::{
+    display::bwr_color::BWRColor,
+    eink::{panel::PanelImage, transport::EInkTransport, EInkResponse},
+};
+
+/// Shows the panel in a window, closing the window stops the display
+pub struct SimulatorTransport {
+    panel: PanelImage,
+    frames: Sender<PanelImage>,
+}
+
+impl SimulatorTransport {
+    pub fn open(title: &str, scale: u32, width: u32, height: u32) -> Self {
+        let (frames, window_frames) = mpsc::channel::<PanelImage>();
+        let title = title.to_string();
+        // The window has to be updated from the thread that created it
+        thread::spawn(move || run_window(&title, scale, width, height, window_frames));
+
+        Self {
+            panel: PanelImage::new(width, height),
+            frames,
+        }
+    }
+}
+
+fn run_window(title: &str, scale: u32, width: u32, height: u32, frames: Receiver<PanelImage>) {
+    let mut display = SimulatorDisplay::<Rgb888>::new(Size::new(width, height));
+    display.clear(Rgb888::WHITE).ok();
+
+    let output_settings = OutputSettingsBuilder::new().scale(scale).build();
+    let mut window = Window::new(title, &output_settings);
+
+    loop {
+        match frames.recv_timeout(Duration::from_millis(50)) {
+            Ok(panel) => {
+                let pixels = (0..panel.width).flat_map(|x| {
+                    let panel = &panel;
+                    (0..panel.height).map(move |y| {
+                        let color = match panel.pixel(x, y) {
+                            BWRColor::Off => Rgb888::WHITE,
+                            BWRColor::On => Rgb888::BLACK,
+                            BWRColor::Red => Rgb888::RED,
+                        };
+                        Pixel(Point::new(x as i32, y as i32), color)
+                    })
+                });
+                display.draw_iter(pixels).ok();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        window.update(&display);
+        if window
+            .events()
+            .any(|event| matches!(event, SimulatorEvent::Quit))
+        {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl EInkTransport for SimulatorTransport {
+    async fn reset(&mut self) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+
+    async fn send_image(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        self.panel.write(data, x, y, width, height, with_red)
+    }
+
+    async fn show(&mut self, _full_refresh: bool, _border: bool) -> Result<(), EInkResponse> {
+        self.frames
+            .send(self.panel.clone())
+            .map_err(|_closed| EInkResponse::Closed)
+    }
+
+    async fn set_led(&mut self, _color: u8) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+
+    async fn wait_ready(&mut self) -> Result<(), EInkResponse> {
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use embedded_graphics::{
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+
+/// More areas than this are sent as one area, every area is a separate refresh
+const MAX_AREAS: usize = 2;
+/// Changes to more than this part of the panel are sent as a whole frame
+const MAX_PARTIAL_PERCENT: u32 = 50;
+
+/// How a new frame gets to the panel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameUpdate {
+    Unchanged,
+    /// Only these areas changed, in panel coordinates
+    Partial(Vec<Rectangle>),
+    Frame,
+}
+
+/// Compare two buffers in the controller layout, see `BWRDisplay::get_fixed_buffer`.
+/// With a red plane after the black one a pixel changed when it changed in either plane.
+///
+/// The areas are aligned to the bytes of 8 vertical pixels, rows of bytes with changes
+/// in overlapping or touching columns are joined to one area.
+pub fn plan_update(old: &[u8], new: &[u8], width: u32, buffer_height: u32) -> FrameUpdate {
+    let rows = (buffer_height / 8) as usize;
+    let plane_size = width as usize * rows;
+    if old.len() != new.len() || plane_size == 0 || !new.len().is_multiple_of(plane_size) {
+        return FrameUpdate::Frame;
+    }
+    let planes = new.len() / plane_size;
+
+    let mut areas: Vec<Rectangle> = Vec::new();
+    // Columns and rows of the area that is being grown, the ends are exclusive
+    let mut current: Option<(u32, u32, usize, usize)> = None;
+
+    for row in 0..rows {
+        let mut changed = (0..width).filter(|&x| {
+            let index = x as usize * rows + row;
+            (0..planes)
+                .any(|plane| old[plane * plane_size + index] != new[plane * plane_size + index])
+        });
+        let columns = changed
+            .next()
+            .map(|first| (first, changed.next_back().unwrap_or(first) + 1));
+
+        current = match (current, columns) {
+            (Some((x0, x1, row0, _)), Some((first, end))) if first <= x1 && end >= x0 => {
+                Some((x0.min(first), x1.max(end), row0, row + 1))
+            }
+            (current, columns) => {
+                if let Some(area) = current {
+                    areas.push(to_rectangle(area));
+                }
+                columns.map(|(first, end)| (first, end, row, row + 1))
+            }
+        };
+    }
+    if let Some(area) = current {
+        areas.push(to_rectangle(area));
+    }
+
+    if areas.is_empty() {
+        return FrameUpdate::Unchanged;
+    }
+    if areas.len() > MAX_AREAS {
+        let bounds = areas
+            .iter()
+            .fold(areas[0], |bounds, area| union(&bounds, area));
+        areas = vec![bounds];
+    }
+
+    let changed: u32 = areas
+        .iter()
+        .map(|area| area.size.width * area.size.height)
+        .sum();
+    if changed * 100 > width * buffer_height * MAX_PARTIAL_PERCENT {
+        FrameUpdate::Frame
+    } else {
+        FrameUpdate::Partial(areas)
+    }
+}
+
+fn to_rectangle((x0, x1, row0, row1): (u32, u32, usize, usize)) -> Rectangle {
+    Rectangle::new(
+        Point::new(x0 as i32, row0 as i32 * 8),
+        Size::new(x1 - x0, (row1 - row0) as u32 * 8),
+    )
+}
+
+fn union(a: &Rectangle, b: &Rectangle) -> Rectangle {
+    let top_left = a.top_left.component_min(b.top_left);
+    let bottom_right = (a.top_left + a.size).component_max(b.top_left + b.size);
+    Rectangle::with_corners(top_left, bottom_right - Point::new(1, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16 columns of 32 pixels, 4 bytes per column
+    fn set(buffer: &mut [u8], x: usize, y: usize) {
+        buffer[x * 4 + y / 8] |= 0b1000_0000 >> (y % 8);
+    }
+
+    #[test]
+    fn changed_areas() {
+        let old = vec![0u8; 64];
+        assert_eq!(plan_update(&old, &old, 16, 32), FrameUpdate::Unchanged);
+
+        let mut new = old.clone();
+        set(&mut new, 2, 1);
+        set(&mut new, 6, 30);
+        assert_eq!(
+            plan_update(&old, &new, 16, 32),
+            FrameUpdate::Partial(vec![
+                Rectangle::new(Point::new(2, 0), Size::new(1, 8)),
+                Rectangle::new(Point::new(6, 24), Size::new(1, 8)),
+            ])
+        );
+
+        // Too many areas are joined
+        set(&mut new, 4, 9);
+        assert_eq!(
+            plan_update(&old, &new, 16, 32),
+            FrameUpdate::Partial(vec![Rectangle::new(Point::new(2, 0), Size::new(5, 32))])
+        );
+
+        // Rows next to each other with touching columns are one area
+        let mut new = old.clone();
+        set(&mut new, 2, 1);
+        set(&mut new, 3, 9);
+        set(&mut new, 5, 9);
+        assert_eq!(
+            plan_update(&old, &new, 16, 32),
+            FrameUpdate::Partial(vec![Rectangle::new(Point::new(2, 0), Size::new(4, 16))])
+        );
+
+        let new = vec![0xff; 64];
+        assert_eq!(plan_update(&old, &new, 16, 32), FrameUpdate::Frame);
+
+        // A change in the red plane only
+        let old = vec![0u8; 128];
+        let mut new = old.clone();
+        set(&mut new[64..], 9, 17);
+        assert_eq!(
+            plan_update(&old, &new, 16, 32),
+            FrameUpdate::Partial(vec![Rectangle::new(Point::new(9, 16), Size::new(1, 8))])
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::value::StateValueType;
+
+/// Limits against the ghosting that fast refreshes leave behind, 0 turns a limit off
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GhostingConfig {
+    /// Full refresh after this many fast refreshes
+    pub max_fast_refreshes: u32,
+    /// Full refresh when the last one is older than this many seconds
+    pub max_fast_seconds: u64,
+    /// Clean cycle every this many seconds
+    pub clean_interval: u64,
+    /// Clean cycle after this many seconds without a new frame
+    pub clean_when_idle: u64,
+}
+
+impl Default for GhostingConfig {
+    fn default() -> Self {
+        Self {
+            max_fast_refreshes: 30,
+            max_fast_seconds: 30 * 60,
+            clean_interval: 0,
+            clean_when_idle: 0,
+        }
+    }
+}
+
+/// Refreshes of a display since its last full refresh and clean cycle.
+///
+/// A clean cycle shows the panel all black and all white before the frame is drawn again
+/// with a full refresh.
+#[derive(Debug, Clone)]
+pub struct Ghosting {
+    pub config: GhostingConfig,
+    fast_refreshes: u32,
+    last_full_refresh: SystemTime,
+    last_clean: SystemTime,
+    last_frame: SystemTime,
+    /// The next frame redraws the panel after a clean cycle
+    redraw: bool,
+}
+
+impl Ghosting {
+    pub fn new(config: GhostingConfig, now: SystemTime) -> Self {
+        Self {
+            config,
+            fast_refreshes: 0,
+            last_full_refresh: now,
+            last_clean: now,
+            last_frame: now,
+            redraw: false,
+        }
+    }
+
+    /// Whether the next frame needs a full refresh to clear the ghosting
+    pub fn full_refresh_due(&self, now: SystemTime) -> bool {
+        let config = &self.config;
+        (config.max_fast_refreshes > 0 && self.fast_refreshes >= config.max_fast_refreshes)
+            || (config.max_fast_seconds > 0
+                && self.fast_refreshes > 0
+                && elapsed(self.last_full_refresh, now) >= config.max_fast_seconds)
+    }
+
+    /// Whether it is time for a clean cycle
+    pub fn clean_due(&self, now: SystemTime) -> bool {
+        self.next_clean().is_some_and(|time| time <= now)
+    }
+
+    /// When the next clean cycle is due, if one is planned
+    pub fn next_clean(&self) -> Option<SystemTime> {
+        let config = &self.config;
+        if self.redraw {
+            return None;
+        }
+        let interval = (config.clean_interval > 0)
+            .then(|| self.last_clean + Duration::from_secs(config.clean_interval));
+        let idle = (config.clean_when_idle > 0 && self.last_frame > self.last_clean)
+            .then(|| self.last_frame + Duration::from_secs(config.clean_when_idle));
+        [interval, idle].into_iter().flatten().min()
+    }
+
+    /// A frame was sent to the panel
+    pub fn add_frame(&mut self, full_refresh: bool, now: SystemTime) {
+        if full_refresh {
+            self.fast_refreshes = 0;
+            self.last_full_refresh = now;
+        } else {
+            self.fast_refreshes += 1;
+        }
+        // The redraw after a clean cycle does not make the display busy again
+        if !std::mem::take(&mut self.redraw) {
+            self.last_frame = now;
+        }
+    }
+
+    /// A clean cycle was sent to the panel, the next frame redraws it
+    pub fn add_clean(&mut self, now: SystemTime) {
+        self.last_clean = now;
+        self.redraw = true;
+    }
+
+    /// Values for the refresh keys of `DISPLAY_STATUS_KEYS`
+    pub fn status_values(&self) -> [Option<StateValueType>; 3] {
+        let time = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|time| StateValueType::U64(time.as_secs()))
+        };
+        [
+            Some(StateValueType::U64(self.fast_refreshes as u64)),
+            time(self.last_full_refresh),
+            time(self.last_clean),
+        ]
+    }
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> u64 {
+    now.duration_since(since)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut ghosting = Ghosting::new(
+            GhostingConfig {
+                max_fast_refreshes: 3,
+                max_fast_seconds: 100,
+                clean_interval: 1000,
+                clean_when_idle: 50,
+            },
+            start,
+        );
+
+        for second in 1..=3 {
+            assert!(!ghosting.full_refresh_due(at(second)));
+            ghosting.add_frame(false, at(second));
+        }
+        assert!(ghosting.full_refresh_due(at(4)));
+        ghosting.add_frame(true, at(4));
+        assert!(!ghosting.full_refresh_due(at(5)));
+
+        // Too long since the last full refresh
+        ghosting.add_frame(false, at(5));
+        assert!(!ghosting.full_refresh_due(at(103)));
+        assert!(ghosting.full_refresh_due(at(104)));
+
+        // Idle after the last frame, the redraw after the clean cycle does not count
+        assert_eq!(ghosting.next_clean(), Some(at(55)));
+        assert!(!ghosting.clean_due(at(54)));
+        assert!(ghosting.clean_due(at(55)));
+        ghosting.add_clean(at(55));
+        assert!(!ghosting.clean_due(at(56)));
+        ghosting.add_frame(true, at(56));
+        assert!(!ghosting.clean_due(at(500)));
+
+        // On a schedule
+        assert_eq!(ghosting.next_clean(), Some(at(1055)));
+        assert_eq!(
+            ghosting.status_values(),
+            [
+                Some(StateValueType::U64(0)),
+                Some(StateValueType::U64(1056)),
+                Some(StateValueType::U64(1055)),
+            ]
+        );
+    }
+}
@@ -1,10 +1,26 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 
-use crate::log;
+use crate::{
+    display::{bwr_display::BWRDisplay, components::RefreshType},
+    log,
+    state::value::StateValueType,
+};
+
+use self::{
+    diff::{plan_update, FrameUpdate},
+    ghosting::Ghosting,
+};
 
+pub mod backends;
+pub mod diff;
+pub mod ghosting;
+pub mod panel;
 pub mod thread;
+pub mod transport;
 pub mod uart_interface;
 
 pub struct EInkInterface {
@@ -14,8 +30,18 @@ pub struct EInkInterface {
     pub rx: Receiver<EInkResponse>,
     pub tx: Sender<EInkCommand>,
     pub state: EInkResponse,
-    pub _port: &'static str,
+    /// When the last frame was shown
+    pub last_frame: Option<SystemTime>,
+    pub last_error: Option<EInkResponse>,
+    /// The last frame sent to the serial thread, new frames only send what changed
+    pub last_sent: Option<Vec<u8>>,
+    pub port: String,
+    pub baud: u32,
     pub black_border: bool,
+    /// The panel has a red plane, frames carry it after the black plane
+    pub with_red: bool,
+    /// Fast refreshes since the last full refresh and clean cycle
+    pub ghosting: Ghosting,
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +51,8 @@ pub enum EInkResponse {
     Busy,
     Error,
     Disconnected,
+    /// The transport is gone for good, i.e. its window was closed, it is not opened again
+    Closed,
 }
 
 #[derive(Debug, Clone)]
@@ -43,15 +71,153 @@ pub enum EInkCommand {
     Led {
         color: u8,
     },
+    /// Show the panel all black and all white, against ghosting
+    Clean,
 }
 
 impl EInkInterface {
-    #[allow(dead_code)]
+    /// Read the responses of the serial thread, returns true when there were any
+    pub fn poll_responses(&mut self) -> bool {
+        let mut received = false;
+        while let Ok(response) = self.rx.try_recv() {
+            received = true;
+            self.handle_response(response);
+        }
+        received
+    }
+
+    /// Update the status with a response of the serial thread
+    pub fn handle_response(&mut self, response: EInkResponse) {
+        match response {
+            EInkResponse::Ready if matches!(self.state, EInkResponse::Busy) => {
+                self.last_frame = Some(SystemTime::now());
+            }
+            EInkResponse::Error | EInkResponse::Disconnected | EInkResponse::Closed => {
+                // A part of the frame might be missing, send the next one completely
+                if matches!(response, EInkResponse::Error) {
+                    self.last_sent = None;
+                }
+                self.last_error = Some(response.clone());
+            }
+            _ => {}
+        }
+        self.state = response;
+    }
+
+    /// Values for the `display:<index>:*` state keys, in the order of `DISPLAY_STATUS_KEYS`
+    pub fn status_values(&self) -> [Option<StateValueType>; 6] {
+        let status = match self.state {
+            EInkResponse::OK => "connecting",
+            EInkResponse::Ready => "ready",
+            EInkResponse::Busy => "busy",
+            EInkResponse::Error => "error",
+            EInkResponse::Disconnected => "disconnected",
+            EInkResponse::Closed => "closed",
+        };
+        let last_frame = self
+            .last_frame
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| StateValueType::U64(time.as_secs()));
+        let last_error = self
+            .last_error
+            .as_ref()
+            .map(|error| StateValueType::String(error.to_string().to_lowercase()));
+
+        let [fast_refreshes, last_full_refresh, last_clean] = self.ghosting.status_values();
+
+        [
+            Some(StateValueType::String(status.to_string())),
+            last_frame,
+            last_error,
+            fast_refreshes,
+            last_full_refresh,
+            last_clean,
+        ]
+    }
+
+    /// Set the panel size, the buffer height is rounded up to whole bytes
+    pub fn set_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.buffer_height = height.div_ceil(8) * 8;
+    }
+
+    /// Send a frame, with a partial refresh only the areas that changed since the last frame are sent.
+    /// Without a red plane on the panel red pixels are drawn black.
+    /// The refresh is made full when the ghosting limits are reached.
+    pub(crate) async fn update(
+        &mut self,
+        display: &mut BWRDisplay,
+        black: Vec<u8>,
+        red: Vec<u8>,
+        refresh_type: RefreshType,
+    ) -> Result<(), SendError<EInkCommand>> {
+        let buffer = if self.with_red {
+            [black, red].concat()
+        } else {
+            black
+                .iter()
+                .zip(&red)
+                .map(|(black, red)| black | red)
+                .collect()
+        };
+        let now = SystemTime::now();
+        let full_refresh = refresh_type == RefreshType::Full || self.ghosting.full_refresh_due(now);
+        let update = match self.last_sent.replace(buffer.clone()) {
+            Some(last_sent) if !full_refresh && refresh_type == RefreshType::Partial => {
+                plan_update(&last_sent, &buffer, self.width, self.buffer_height)
+            }
+            _ => FrameUpdate::Frame,
+        };
+
+        match update {
+            FrameUpdate::Unchanged => {
+                println!("{} Nothing changed on display {}", log::DISPLAY, self.port);
+                Ok(())
+            }
+            FrameUpdate::Partial(areas) => {
+                self.ghosting.add_frame(false, now);
+                for area in areas {
+                    let partial = display.partial_buffer(&buffer, area.top_left, area.size);
+                    self.partial(
+                        partial,
+                        area.top_left.x as u32,
+                        area.top_left.y as u32,
+                        area.size.width,
+                        area.size.height,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            FrameUpdate::Frame => {
+                self.ghosting.add_frame(full_refresh, now);
+                if full_refresh {
+                    self.full(buffer).await
+                } else {
+                    self.fast(buffer).await
+                }
+            }
+        }
+    }
+
+    /// Start a clean cycle, the frame has to be drawn again after it
+    pub(crate) async fn clean(&mut self) -> Result<(), SendError<EInkCommand>> {
+        println!("{} Clean cycle on display {}", log::DISPLAY, self.port);
+        self.ghosting.add_clean(SystemTime::now());
+        self.last_sent = None;
+        self.send_command(EInkCommand::Clean).await
+    }
+
     pub(crate) async fn full(&mut self, buffer: Vec<u8>) -> Result<(), SendError<EInkCommand>> {
         println!(
             "{} Full draw on display {}",
             log::DISPLAY,
-            self._port.split('_').last().or(Some(self._port)).expect("")
+            self.port
+                .split('_')
+                .last()
+                .or(Some(self.port.as_str()))
+                .expect("")
         );
         self.send_command(EInkCommand::Show {
             buffer,
@@ -59,19 +225,22 @@ impl EInkInterface {
             y: 0,
             width: self.width,
             height: self.buffer_height,
-            with_red: false,
+            with_red: self.with_red,
             black_border: self.black_border,
             full_refresh: true,
         })
         .await
     }
 
-    #[allow(dead_code)]
     pub(crate) async fn fast(&mut self, buffer: Vec<u8>) -> Result<(), SendError<EInkCommand>> {
         println!(
             "{} Fast draw on display {}",
             log::DISPLAY,
-            self._port.split('_').last().or(Some(self._port)).expect("")
+            self.port
+                .split('_')
+                .last()
+                .or(Some(self.port.as_str()))
+                .expect("")
         );
         self.send_command(EInkCommand::Show {
             buffer,
@@ -79,14 +248,13 @@ impl EInkInterface {
             y: 0,
             width: self.width,
             height: self.buffer_height,
-            with_red: false,
+            with_red: self.with_red,
             black_border: self.black_border,
             full_refresh: false,
         })
         .await
     }
 
-    #[allow(dead_code)]
     pub(crate) async fn partial(
         &mut self,
         buffer: Vec<u8>,
@@ -98,7 +266,11 @@ impl EInkInterface {
         println!(
             "{} Partial draw on display {}",
             log::DISPLAY,
-            self._port.split('_').last().or(Some(self._port)).expect("")
+            self.port
+                .split('_')
+                .last()
+                .or(Some(self.port.as_str()))
+                .expect("")
         );
         self.send_command(EInkCommand::Show {
             buffer,
@@ -106,7 +278,7 @@ impl EInkInterface {
             y,
             width,
             height,
-            with_red: false,
+            with_red: self.with_red,
             black_border: self.black_border,
             full_refresh: false,
         })
@@ -132,6 +304,7 @@ impl std::fmt::Display for EInkResponse {
                 EInkResponse::Busy => "Busy",
                 EInkResponse::Error => "Error",
                 EInkResponse::Disconnected => "Disconnected",
+                EInkResponse::Closed => "Closed",
             }
         )
     }
@@ -141,14 +314,27 @@ impl From<serialport::Error> for EInkResponse {
     fn from(err: serialport::Error) -> EInkResponse {
         match err.kind {
             serialport::ErrorKind::NoDevice => EInkResponse::Disconnected,
+            serialport::ErrorKind::Io(kind) => std::io::Error::from(kind).into(),
             _ => EInkResponse::Error,
         }
     }
 }
 impl From<std::io::Error> for EInkResponse {
     fn from(err: std::io::Error) -> EInkResponse {
+        // EIO, ENXIO and ENODEV, returned by a tty that was unplugged
+        const UNPLUGGED: [i32; 3] = [5, 6, 19];
+
         match err.kind() {
             std::io::ErrorKind::WouldBlock => EInkResponse::Busy,
+            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::NotFound => {
+                EInkResponse::Disconnected
+            }
+            _ if err
+                .raw_os_error()
+                .is_some_and(|code| UNPLUGGED.contains(&code)) =>
+            {
+                EInkResponse::Disconnected
+            }
             _ => EInkResponse::Error,
         }
     }
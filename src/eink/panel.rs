@@ -0,0 +1,142 @@
+use image::{Rgb, RgbImage};
+
+use crate::display::bwr_color::BWRColor;
+
+use super::EInkResponse;
+
+/// The content of a panel as the controller sees it, built from the transferred image data.
+///
+/// The buffers use the layout of `BWRDisplay::get_fixed_buffer`: column by column,
+/// 8 vertical pixels per byte with the top pixel in the highest bit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PanelImage {
+    pub width: u32,
+    pub height: u32,
+    buffer_height: u32,
+    black: Vec<u8>,
+    red: Vec<u8>,
+}
+
+impl PanelImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        let buffer_height = height.div_ceil(8) * 8;
+        let size = (width * buffer_height / 8) as usize;
+        Self {
+            width,
+            height,
+            buffer_height,
+            black: vec![0; size],
+            red: vec![0; size],
+        }
+    }
+
+    /// Copy the data of an `AT+IMG` transfer into the panel.
+    /// With red the data holds the black plane followed by the red plane.
+    pub fn write(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        if !y.is_multiple_of(8)
+            || !height.is_multiple_of(8)
+            || x + width > self.width
+            || y + height > self.buffer_height
+        {
+            return Err(EInkResponse::Error);
+        }
+
+        let plane_size = (width * height / 8) as usize;
+        let planes = if with_red { 2 } else { 1 };
+        if data.len() != plane_size * planes {
+            return Err(EInkResponse::Error);
+        }
+
+        let (black, red) = data.split_at(plane_size);
+        for column in 0..width {
+            let source = (column * height / 8) as usize;
+            let target = ((x + column) * self.buffer_height / 8 + y / 8) as usize;
+            let bytes = (height / 8) as usize;
+
+            self.black[target..target + bytes].copy_from_slice(&black[source..source + bytes]);
+            if with_red {
+                self.red[target..target + bytes].copy_from_slice(&red[source..source + bytes]);
+            } else {
+                self.red[target..target + bytes].fill(0);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> BWRColor {
+        let index = (x * self.buffer_height / 8 + y / 8) as usize;
+        let bit = 0b1000_0000 >> (y % 8);
+        if self.red[index] & bit != 0 {
+            BWRColor::Red
+        } else if self.black[index] & bit != 0 {
+            BWRColor::On
+        } else {
+            BWRColor::Off
+        }
+    }
+
+    /// Render the panel like it looks on paper
+    pub fn to_rgb(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| match self.pixel(x, y) {
+            BWRColor::Off => Rgb([255, 255, 255]),
+            BWRColor::On => Rgb([0, 0, 0]),
+            BWRColor::Red => Rgb([255, 0, 0]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{
+        prelude::*,
+        primitives::{PrimitiveStyle, Rectangle},
+    };
+
+    use super::*;
+    use crate::display::{bwr_display::BWRDisplay, DisplayFlip, DisplayRotation};
+
+    #[test]
+    fn decode_display_buffer() {
+        let mut display = BWRDisplay::new(16, 12, DisplayRotation::Zero, DisplayFlip::None);
+        Rectangle::new(Point::new(2, 3), Size::new(4, 6))
+            .into_styled(PrimitiveStyle::with_fill(BWRColor::On))
+            .draw(&mut display)
+            .unwrap();
+        let (black, _red) = display.get_fixed_buffer();
+
+        let mut panel = PanelImage::new(16, 12);
+        panel.write(&black, 0, 0, 16, 16, false).unwrap();
+
+        assert_eq!(panel.pixel(2, 3), BWRColor::On);
+        assert_eq!(panel.pixel(5, 8), BWRColor::On);
+        assert_eq!(panel.pixel(1, 3), BWRColor::Off);
+        assert_eq!(panel.pixel(2, 9), BWRColor::Off);
+    }
+
+    #[test]
+    fn write_area() {
+        let mut panel = PanelImage::new(16, 16);
+        // 2 columns of 8 pixels, with the red plane
+        panel
+            .write(&[0xff, 0x00, 0x00, 0x01], 4, 8, 2, 8, true)
+            .unwrap();
+
+        assert_eq!(panel.pixel(4, 8), BWRColor::On);
+        assert_eq!(panel.pixel(4, 15), BWRColor::On);
+        assert_eq!(panel.pixel(5, 14), BWRColor::Off);
+        assert_eq!(panel.pixel(5, 15), BWRColor::Red);
+        assert_eq!(panel.pixel(4, 7), BWRColor::Off);
+
+        assert!(panel.write(&[0xff], 4, 4, 1, 8, false).is_err());
+        assert!(panel.write(&[0xff], 16, 0, 1, 8, false).is_err());
+        assert!(panel.write(&[0xff, 0xff], 0, 0, 1, 8, false).is_err());
+    }
+}
@@ -1,97 +1,168 @@
-use std::{error::Error, time::Duration};
+use std::time::{Duration, SystemTime};
 
-use serialport::{DataBits, Parity, SerialPort, StopBits};
 use tokio::{
-    sync::mpsc::{self, error::TryRecvError, Receiver, Sender},
-    time::sleep,
+    sync::mpsc::{self, Receiver, Sender},
+    time::{timeout_at, Instant},
 };
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
 use crate::log;
 
-use super::{uart_interface::EInkUartInterface, EInkCommand, EInkInterface, EInkResponse};
+use super::{
+    ghosting::{Ghosting, GhostingConfig},
+    transport::{EInkTransport, OpenTransport},
+    EInkCommand, EInkInterface, EInkResponse,
+};
+
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
 
+/// Start the serial thread for a display.
+///
+/// The transport is opened by the thread, and opened again with a backoff when it goes away.
+/// The thread stops and closes the transport when the interface is dropped, or when the
+/// transport is closed for good.
 pub fn start_eink_thread(
-    port_str: &'static str,
+    port_str: &str,
     baud: u32,
     width: u32,
     height: u32,
-) -> Result<EInkInterface, Box<dyn Error>> {
-    // Create the serial port
-    let mut port = tokio_serial::new(port_str, baud)
-        .timeout(Duration::from_millis(1000))
-        .open_native_async()
-        .unwrap_or_else(|_| panic!("Failed to connect to device {}", port_str));
-
-    port.set_exclusive(true)?;
-
-    const CONFIG_ERROR: &str = "error configuring port";
-    port.set_data_bits(DataBits::Eight).expect(CONFIG_ERROR);
-    port.set_stop_bits(StopBits::One).expect(CONFIG_ERROR);
-    port.set_parity(Parity::None).expect(CONFIG_ERROR);
-
-    // let port_clone = port.try_clone().expect("Could not clone port");
-
+    open: OpenTransport,
+) -> EInkInterface {
     // Create a channel for communication between threads
     let (thread_tx, rx) = mpsc::channel::<EInkResponse>(512);
     let (tx, thread_rx) = mpsc::channel::<EInkCommand>(1024);
 
     // Spawn the serial thread
+    let port_name = port_str.to_string();
+    let last_frame = LastFrame::new(width, height);
     tokio::spawn(async move {
-        run_thread(Box::new(port), thread_tx, thread_rx)
-            .await
-            .expect("Could not spawn thread");
+        run_thread(port_name, open, last_frame, thread_tx, thread_rx).await;
     });
 
-    let mut h = height;
-
-    if h % 8 != 0 {
-        h += 8 - height % 8;
-    }
-
-    Ok(EInkInterface {
+    let mut interface = EInkInterface {
         rx,
         tx,
         state: EInkResponse::OK,
+        last_frame: None,
+        last_error: None,
+        last_sent: None,
         width,
         height,
-        buffer_height: h,
-        _port: port_str,
+        buffer_height: height,
+        port: port_str.to_string(),
+        baud,
         black_border: false,
-    })
+        with_red: false,
+        ghosting: Ghosting::new(GhostingConfig::default(), SystemTime::now()),
+    };
+    interface.set_size(width, height);
+    interface
 }
 
+/// Keep the display connected until the interface is dropped
 pub(crate) async fn run_thread(
-    port: Box<SerialStream>,
+    port_name: String,
+    open: OpenTransport,
+    mut last_frame: LastFrame,
     tx: Sender<EInkResponse>,
     mut rx: Receiver<EInkCommand>,
-) -> Result<bool, EInkResponse> {
-    println!("{} Starting AT Thread", log::THREAD);
+) {
+    println!("{} Starting AT Thread for {}", log::THREAD, port_name);
 
-    let mut interface = EInkUartInterface::new(port).expect("Could not create interface");
+    let mut retry_delay = MIN_RETRY_DELAY;
 
-    interface.reset().await.expect("Error resetting interface");
+    loop {
+        let error = match open() {
+            Ok(mut transport) => {
+                retry_delay = MIN_RETRY_DELAY;
+
+                match transport.reset().await {
+                    Ok(()) => {
+                        println!("{} Connected to {}", log::THREAD, port_name);
+                        tx.send(EInkResponse::Ready).await.ok();
+
+                        let result = serve(transport.as_mut(), &tx, &mut rx, &mut last_frame).await;
+                        match result {
+                            Some(error) => error,
+                            // The interface was dropped, i.e. the display was removed from the config
+                            None => break,
+                        }
+                    }
+                    Err(error) => error,
+                }
+            }
+            Err(error) => error,
+        };
+
+        if matches!(error, EInkResponse::Closed) {
+            println!("{} {} was closed", log::WARN, port_name);
+            tx.send(EInkResponse::Closed).await.ok();
+            break;
+        }
+        println!(
+            "{} Lost {} ({}), retrying in {}ms",
+            log::ERROR,
+            port_name,
+            error,
+            retry_delay.as_millis()
+        );
+        tx.send(EInkResponse::Disconnected).await.ok();
+
+        if !wait_for_retry(&mut rx, &mut last_frame, retry_delay).await {
+            break;
+        }
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    println!("{} Stopping AT Thread for {}", log::THREAD, port_name);
+}
+
+/// Send the commands to the display until the port is lost or the channel is closed.
+/// Returns the error that lost the port, or None when the channel was closed.
+async fn serve(
+    interface: &mut dyn EInkTransport,
+    tx: &Sender<EInkResponse>,
+    rx: &mut Receiver<EInkCommand>,
+    last_frame: &mut LastFrame,
+) -> Option<EInkResponse> {
+    // Show the frame again with a full refresh, the panel has been reset
+    let mut resend = last_frame.resend();
+    // A newer command that could not replace the current one
+    let mut pending: Option<EInkCommand> = None;
 
     loop {
-        let mut resp: Result<EInkCommand, TryRecvError> = rx.try_recv();
+        let mut resp = match resend.take().or(pending.take()) {
+            Some(frame) => frame,
+            // Sleep until there is something to send, the channel closes when the interface is dropped
+            None => match rx.recv().await {
+                Some(command) => command,
+                None => return None,
+            },
+        };
 
         let mut frames_dropped: u32 = 0;
 
-        loop {
-            let _resp = rx.try_recv();
-            if _resp.is_err() {
-                break;
+        // Only a frame of the whole panel replaces the frames before it
+        while matches!(resp, EInkCommand::Show { .. }) {
+            match rx.try_recv() {
+                Ok(newer) if last_frame.covers(&newer) => {
+                    frames_dropped += 1;
+                    resp = newer;
+                }
+                Ok(newer) => {
+                    pending = Some(newer);
+                    break;
+                }
+                Err(_) => break,
             }
-            frames_dropped += 1;
-            resp = _resp;
         }
 
         if frames_dropped > 0 {
             println!("{} Dropped {} frames", log::ERROR, frames_dropped)
         }
 
-        match resp {
-            Ok(EInkCommand::Show {
+        let result = match resp {
+            EInkCommand::Show {
                 buffer,
                 x,
                 y,
@@ -100,30 +171,395 @@ pub(crate) async fn run_thread(
                 with_red,
                 black_border,
                 full_refresh,
-            }) => {
-                tx.send(EInkResponse::Busy).await.expect("Error Sending");
-
-                interface
-                    .send_image(
-                        &buffer,
-                        x,
-                        y,
-                        width,
-                        height,
-                        with_red,
-                        full_refresh,
-                        black_border,
-                    )
+            } => {
+                tx.send(EInkResponse::Busy).await.ok();
+
+                let mut result = interface
+                    .send_image(&buffer, x, y, width, height, with_red)
                     .await;
+                if result.is_ok() {
+                    result = interface.show(full_refresh, black_border).await;
+                }
+
+                last_frame.add(EInkCommand::Show {
+                    buffer,
+                    x,
+                    y,
+                    width,
+                    height,
+                    with_red,
+                    black_border,
+                    full_refresh,
+                });
 
                 // sleep(Duration::from_millis(200)).await;
                 // interface.wait_ready().await?;
-                tx.send(EInkResponse::Ready).await.expect("Error Sending");
+                if result.is_ok() {
+                    tx.send(EInkResponse::Ready).await.ok();
+                }
+                result
             }
-            Ok(EInkCommand::Led { color }) => {
-                interface.set_led(color).await?;
+            EInkCommand::Led { color } => interface.set_led(color).await,
+            EInkCommand::Clean => {
+                tx.send(EInkResponse::Busy).await.ok();
+
+                let result = clean(interface, last_frame.width, last_frame.buffer_height).await;
+                if result.is_ok() {
+                    tx.send(EInkResponse::Ready).await.ok();
+                }
+                result
             }
-            Err(_e) => sleep(Duration::from_millis(10)).await,
+        };
+
+        let disconnected = match &result {
+            Ok(()) => false,
+            Err(EInkResponse::Closed) => return Some(EInkResponse::Closed),
+            Err(EInkResponse::Disconnected) => true,
+            // Unplugging does not always show up as a disconnect, check if the device is still there
+            Err(_) => !interface.is_present(),
+        };
+        if disconnected {
+            if let Some(frame) = pending.take() {
+                last_frame.add(frame);
+            }
+            return Some(EInkResponse::Disconnected);
+        }
+        if let Err(error) = result {
+            tx.send(error).await.ok();
+        }
+    }
+}
+
+/// Show the panel all black and then all white with full refreshes
+async fn clean(
+    interface: &mut dyn EInkTransport,
+    width: u32,
+    buffer_height: u32,
+) -> Result<(), EInkResponse> {
+    for (value, black_border) in [(0xff, true), (0x00, false)] {
+        let buffer = vec![value; (width * buffer_height / 8) as usize];
+        interface
+            .send_image(&buffer, 0, 0, width, buffer_height, false)
+            .await?;
+        interface.show(true, black_border).await?;
+    }
+    Ok(())
+}
+
+/// Wait before opening the port again, keeping the newest frame.
+/// Returns false when the channel was closed.
+async fn wait_for_retry(
+    rx: &mut Receiver<EInkCommand>,
+    last_frame: &mut LastFrame,
+    delay: Duration,
+) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        match timeout_at(deadline, rx.recv()).await {
+            Err(_elapsed) => return true,
+            Ok(None) => return false,
+            Ok(Some(frame @ EInkCommand::Show { .. })) => last_frame.add(frame),
+            // The LED is not kept, it is set again with the next command
+            Ok(Some(EInkCommand::Led { .. })) => {}
+            // The panel gets a full refresh after the reconnect
+            Ok(Some(EInkCommand::Clean)) => {}
         }
     }
 }
+
+/// What the panel shows as a frame of the whole panel, it is shown again after a reconnect.
+/// Frames of a part of the panel are copied into it.
+pub(crate) struct LastFrame {
+    width: u32,
+    buffer_height: u32,
+    frame: Option<EInkCommand>,
+}
+
+impl LastFrame {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            buffer_height: height.div_ceil(8) * 8,
+            frame: None,
+        }
+    }
+
+    /// Whether the command replaces everything on the panel
+    fn covers(&self, command: &EInkCommand) -> bool {
+        matches!(command, EInkCommand::Show { x: 0, y: 0, width, height, .. }
+            if *width == self.width && *height == self.buffer_height)
+    }
+
+    fn add(&mut self, command: EInkCommand) {
+        let covers = self.covers(&command);
+        let (
+            Some(EInkCommand::Show {
+                buffer: last_buffer,
+                with_red: last_red,
+                black_border: last_border,
+                ..
+            }),
+            EInkCommand::Show {
+                buffer,
+                x,
+                y,
+                width,
+                height,
+                with_red,
+                black_border,
+                ..
+            },
+        ) = (&mut self.frame, &command)
+        else {
+            if covers || self.frame.is_none() {
+                self.frame = Some(command);
+            }
+            return;
+        };
+        if covers {
+            self.frame = Some(command);
+            return;
+        }
+
+        let rows = (self.buffer_height / 8) as usize;
+        let bytes = (height / 8) as usize;
+        let plane_size = self.width as usize * rows;
+        // The first red frame adds an empty red plane to the stored frame
+        if *with_red && !*last_red {
+            last_buffer.resize(2 * plane_size, 0);
+            *last_red = true;
+        }
+        // A frame without red clears the red plane of its area
+        let planes = if *last_red { 2 } else { 1 };
+        for plane in 0..planes {
+            let has_plane = plane == 0 || *with_red;
+            let plane_offset = plane * *width as usize * bytes;
+            for column in 0..*width as usize {
+                let source = plane_offset + column * bytes;
+                let target = plane * plane_size + (*x as usize + column) * rows + *y as usize / 8;
+                let Some(target) = last_buffer.get_mut(target..target + bytes) else {
+                    continue;
+                };
+                if !has_plane {
+                    target.fill(0);
+                } else if let Some(source) = buffer.get(source..source + bytes) {
+                    target.copy_from_slice(source);
+                }
+            }
+        }
+        *last_border = *black_border;
+    }
+
+    fn resend(&self) -> Option<EInkCommand> {
+        self.frame.clone().map(with_full_refresh)
+    }
+}
+
+fn with_full_refresh(frame: EInkCommand) -> EInkCommand {
+    match frame {
+        EInkCommand::Show {
+            buffer,
+            x,
+            y,
+            width,
+            height,
+            with_red,
+            black_border,
+            ..
+        } => EInkCommand::Show {
+            buffer,
+            x,
+            y,
+            width,
+            height,
+            with_red,
+            black_border,
+            full_refresh: true,
+        },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::time::sleep;
+
+    use super::*;
+    use embedded_graphics::{prelude::Point, Drawable, Pixel};
+
+    use crate::{
+        display::{
+            bwr_color::BWRColor, bwr_display::BWRDisplay, components::RefreshType, DisplayFlip,
+            DisplayRotation,
+        },
+        eink::backends::memory::MemoryTransport,
+    };
+
+    fn frame(value: u8) -> EInkCommand {
+        EInkCommand::Show {
+            buffer: vec![value],
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+            with_red: false,
+            black_border: false,
+            full_refresh: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_newest_frame_while_disconnected() {
+        let (tx, mut rx) = mpsc::channel::<EInkCommand>(8);
+        let mut last_frame = LastFrame::new(8, 8);
+        last_frame.add(frame(1));
+
+        tx.send(frame(2)).await.unwrap();
+        tx.send(EInkCommand::Led { color: 1 }).await.unwrap();
+        tx.send(frame(3)).await.unwrap();
+
+        let retry = wait_for_retry(&mut rx, &mut last_frame, Duration::from_millis(20)).await;
+        assert!(retry);
+        assert!(matches!(
+            last_frame.resend(),
+            Some(EInkCommand::Show { buffer, full_refresh: true, .. }) if buffer == vec![3]
+        ));
+
+        drop(tx);
+        assert!(!wait_for_retry(&mut rx, &mut last_frame, Duration::from_secs(10)).await);
+    }
+
+    #[tokio::test]
+    async fn show_on_memory_transport() {
+        let recording = Arc::default();
+        let open = MemoryTransport::opener(Arc::clone(&recording), 8, 8);
+        let mut interface = start_eink_thread("memory", 115200, 8, 8, open);
+
+        interface.full(vec![0b1000_0001; 8]).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        // A clean cycle shows all black and all white
+        interface.clean().await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        let recording = recording.lock().unwrap();
+        assert_eq!(recording.resets, 1);
+        assert_eq!(recording.frames.len(), 3);
+        let frame = &recording.frames[0];
+        assert!(frame.full_refresh);
+        assert_eq!(frame.panel.pixel(3, 0), BWRColor::On);
+        assert_eq!(frame.panel.pixel(3, 7), BWRColor::On);
+        assert_eq!(frame.panel.pixel(3, 4), BWRColor::Off);
+
+        assert!(recording.frames[1..].iter().all(|frame| frame.full_refresh));
+        assert_eq!(recording.frames[1].panel.pixel(3, 4), BWRColor::On);
+        assert_eq!(recording.frames[2].panel.pixel(3, 0), BWRColor::Off);
+    }
+
+    #[tokio::test]
+    async fn show_red_on_memory_transport() {
+        let recording = Arc::default();
+        let open = MemoryTransport::opener(Arc::clone(&recording), 16, 16);
+        let mut interface = start_eink_thread("memory", 115200, 16, 16, open);
+        let mut display = BWRDisplay::new(16, 16, DisplayRotation::Zero, DisplayFlip::None);
+
+        Pixel(Point::new(2, 3), BWRColor::Red)
+            .draw(&mut display)
+            .unwrap();
+        for with_red in [false, true] {
+            interface.with_red = with_red;
+            let (black, red) = display.get_fixed_buffer();
+            interface
+                .update(&mut display, black, red, RefreshType::Full)
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        // Only the area with the new red pixel is sent
+        Pixel(Point::new(9, 12), BWRColor::Red)
+            .draw(&mut display)
+            .unwrap();
+        let (black, red) = display.get_fixed_buffer();
+        interface
+            .update(&mut display, black, red, RefreshType::Partial)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        let recording = recording.lock().unwrap();
+        assert_eq!(recording.frames.len(), 3);
+        // Without red on the panel red is drawn black
+        assert_eq!(recording.frames[0].panel.pixel(2, 3), BWRColor::On);
+        assert_eq!(recording.frames[1].panel.pixel(2, 3), BWRColor::Red);
+        let frame = &recording.frames[2];
+        assert!(!frame.full_refresh);
+        assert_eq!(frame.panel.pixel(2, 3), BWRColor::Red);
+        assert_eq!(frame.panel.pixel(9, 12), BWRColor::Red);
+        assert_eq!(frame.panel.pixel(9, 8), BWRColor::Off);
+    }
+
+    #[tokio::test]
+    async fn keep_partial_frames() {
+        let recording = Arc::default();
+        let open = MemoryTransport::opener(Arc::clone(&recording), 16, 16);
+        let mut interface = start_eink_thread("memory", 115200, 16, 16, open);
+
+        // Queued before the thread gets to them, none of them may be dropped
+        interface.fast(vec![0; 32]).await.unwrap();
+        interface.partial(vec![0xff], 0, 0, 1, 8).await.unwrap();
+        interface.partial(vec![0xff], 5, 8, 1, 8).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        let recording = recording.lock().unwrap();
+        assert_eq!(recording.frames.len(), 3);
+        let panel = &recording.frames[2].panel;
+        assert_eq!(panel.pixel(0, 0), BWRColor::On);
+        assert_eq!(panel.pixel(5, 8), BWRColor::On);
+        assert_eq!(panel.pixel(5, 0), BWRColor::Off);
+
+        // After a reconnect the whole panel is sent again
+        let mut last_frame = LastFrame::new(16, 16);
+        let partial = |buffer, x, y, width, height| EInkCommand::Show {
+            buffer,
+            x,
+            y,
+            width,
+            height,
+            with_red: false,
+            black_border: false,
+            full_refresh: false,
+        };
+        last_frame.add(partial(vec![0; 32], 0, 0, 16, 16));
+        last_frame.add(partial(vec![0xff], 5, 8, 1, 8));
+        let Some(EInkCommand::Show { buffer, .. }) = last_frame.resend() else {
+            panic!("No frame to resend");
+        };
+        assert_eq!(buffer[5 * 2 + 1], 0xff);
+
+        // The red plane of a partial frame is kept when the stored frame has none
+        let red = EInkCommand::Show {
+            buffer: vec![0xff, 0x0f],
+            x: 3,
+            y: 0,
+            width: 1,
+            height: 8,
+            with_red: true,
+            black_border: false,
+            full_refresh: false,
+        };
+        last_frame.add(red);
+        let Some(EInkCommand::Show {
+            buffer, with_red, ..
+        }) = last_frame.resend()
+        else {
+            panic!("No frame to resend");
+        };
+        assert!(with_red);
+        assert_eq!(buffer.len(), 64);
+        assert_eq!(buffer[3 * 2], 0xff);
+        assert_eq!(buffer[32 + 3 * 2], 0x0f);
+        assert_eq!(buffer[32 + 5 * 2 + 1], 0);
+    }
+}
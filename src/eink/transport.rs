@@ -0,0 +1,42 @@
+use async_trait::async_trait;
+
+use super::EInkResponse;
+
+/// Opens the transport of a display, the serial thread calls it again when the transport is lost
+pub type OpenTransport = Box<dyn Fn() -> Result<Box<dyn EInkTransport>, EInkResponse> + Send>;
+
+/// What the serial thread needs from a display.
+///
+/// `EInkUartInterface` talks to the driver boards, the backends in `eink::backends`
+/// let the driver run without them.
+#[async_trait]
+pub trait EInkTransport: Send {
+    /// Bring the controller into a known state, after (re)connecting
+    async fn reset(&mut self) -> Result<(), EInkResponse>;
+
+    /// Transfer the image data for an area of the panel, see `PanelImage::write` for the layout
+    #[allow(clippy::too_many_arguments)]
+    async fn send_image(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse>;
+
+    /// Refresh the panel with the transferred image
+    async fn show(&mut self, full_refresh: bool, border: bool) -> Result<(), EInkResponse>;
+
+    async fn set_led(&mut self, color: u8) -> Result<(), EInkResponse>;
+
+    /// Wait until the controller is done refreshing
+    #[allow(dead_code)]
+    async fn wait_ready(&mut self) -> Result<(), EInkResponse>;
+
+    /// Whether the device is still there, errors on a transport that is gone are treated as a disconnect
+    fn is_present(&self) -> bool {
+        true
+    }
+}
@@ -1,44 +1,85 @@
 // use debug_print::debug_println;
-use serialport::SerialPort;
-use std::io::{Read, Write};
+use async_trait::async_trait;
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::time::{Duration, Instant};
-use tokio::time::sleep;
-// use tokio_serial::SerialPortBuilderExt;
-use tokio_serial::SerialStream;
+use tokio::time::{sleep, timeout_at};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
 use crate::log;
 
-use super::EInkResponse;
+use super::{transport::EInkTransport, EInkResponse};
 
 const DELIMITER: &str = "\r\n"; // Hardcoded to "\r\n"
 const CHUNK_DELAY: u64 = 30; // Delay in milliseconds between sending chunks (adjust as needed).
 const CHUNK_SIZE: usize = 1000;
 
+const FRAMED_VERSION: u32 = 2; // First firmware version with AT+BIMG
+const FRAME_SIZE: usize = 512; // Image bytes per frame
+const FRAME_RETRIES: u32 = 5;
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How image data is transferred, see `EInkUartInterface::negotiate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// `AT+IMG`, chunks followed by `\r\n`, checked with an 8 bit sum
+    Text,
+    /// `AT+BIMG`, COBS frames with sequence numbers that are acknowledged, checked with a CRC32
+    Framed,
+}
+
 // Define an Interface struct to manage the serial port.
 pub struct EInkUartInterface {
     // reader: BufReader<Box<dyn SerialPort>>,
     port: Box<SerialStream>,
+    path: String,
+    /// Found with `AT+VER` on the first image after a reset
+    protocol: Option<Protocol>,
+    /// Received bytes that are not a complete line yet
+    received: Vec<u8>,
 }
 
 impl EInkUartInterface {
-    pub fn new(port: Box<SerialStream>) -> Result<Self, serialport::Error> {
+    pub fn new(port: Box<SerialStream>, path: &str) -> Self {
         // let reader = BufReader::new(port);
 
-        Ok(EInkUartInterface { port })
+        EInkUartInterface {
+            port,
+            path: path.to_string(),
+            protocol: None,
+            received: Vec::new(),
+        }
     }
 
-    pub fn dump_rx(&mut self) {
-        self.port
-            .clear(serialport::ClearBuffer::Input)
-            .expect("Error clearing buffer");
+    pub fn open(path: &str, baud: u32) -> Result<Self, serialport::Error> {
+        // Create the serial port
+        let mut port = tokio_serial::new(path, baud)
+            .timeout(Duration::from_millis(1000))
+            .open_native_async()?;
+
+        port.set_exclusive(true)?;
+
+        port.set_data_bits(DataBits::Eight)?;
+        port.set_stop_bits(StopBits::One)?;
+        port.set_parity(Parity::None)?;
+
+        Ok(Self::new(Box::new(port), path))
+    }
+
+    pub fn dump_rx(&mut self) -> Result<(), EInkResponse> {
+        self.port.clear(serialport::ClearBuffer::Input)?;
+        self.received.clear();
         // self.reader
         //     .get_mut()
         //     .clear(serialport::ClearBuffer::Input)
         //     .expect("Error clearing buffer");
+        Ok(())
     }
 
-    pub async fn send_message(&mut self, message: &[u8]) -> Result<EInkResponse, EInkResponse> {
-        self.dump_rx();
+    pub async fn send_message(&mut self, message: &[u8]) -> Result<String, EInkResponse> {
+        self.dump_rx()?;
         self.port.write_all(message)?;
         self.port.flush()?;
         sleep(Duration::from_millis(20)).await; //give it a moment
@@ -54,7 +95,7 @@ impl EInkUartInterface {
                 break;
             }
 
-            response.push_str(std::str::from_utf8(&buffer[..bytes_read]).unwrap());
+            response.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
 
             if response.ends_with(DELIMITER) {
                 // If the response ends with the delimiter, it's complete.
@@ -63,7 +104,7 @@ impl EInkUartInterface {
         }
 
         if response.contains("OK") {
-            Ok(EInkResponse::OK)
+            Ok(response.trim().to_string())
         } else if response.contains("BUSY") {
             Err(EInkResponse::Busy)
         } else {
@@ -71,11 +112,11 @@ impl EInkUartInterface {
         }
     }
 
-    pub async fn send_cmd(&mut self, cmd: &String) -> Result<EInkResponse, EInkResponse> {
+    pub async fn send_cmd(&mut self, cmd: &String) -> Result<String, EInkResponse> {
         let start = Instant::now();
         println!("{} {}", log::SEND, cmd.trim());
         loop {
-            let resp: Result<EInkResponse, EInkResponse> = self.send_message(cmd.as_bytes()).await;
+            let resp: Result<String, EInkResponse> = self.send_message(cmd.as_bytes()).await;
             match resp {
                 Ok(data) => {
                     return Ok(data);
@@ -103,7 +144,7 @@ impl EInkUartInterface {
 
         // let start = Instant::now();
 
-        self.dump_rx();
+        self.dump_rx()?;
         self.port.writable().await?;
 
         let mut remaining_data: &[u8] = data;
@@ -135,7 +176,7 @@ impl EInkUartInterface {
                 break;
             }
 
-            response.push_str(std::str::from_utf8(&buffer[..bytes_read]).unwrap());
+            response.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
 
             if response.ends_with(DELIMITER) {
                 // If the response ends with the delimiter, it's complete.
@@ -161,51 +202,151 @@ impl EInkUartInterface {
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn reset(&mut self) -> Result<(), EInkResponse> {
-        // Bring RTS high for 100ms.
-
-        // self.port.write_data_terminal_ready(true)?;
-        self.port.write_request_to_send(true)?;
+    /// Read one line of the reply, None when nothing complete arrived within `timeout`
+    async fn read_line(&mut self, timeout: Duration) -> Result<Option<String>, EInkResponse> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buffer = [0; 1024];
+        loop {
+            let delimiter = DELIMITER.as_bytes();
+            if let Some(end) = self
+                .received
+                .windows(delimiter.len())
+                .position(|window| window == delimiter)
+            {
+                let line: Vec<u8> = self.received.drain(..end + delimiter.len()).collect();
+                return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+            }
 
-        sleep(Duration::from_millis(100)).await; //Sleep to reset
+            match timeout_at(deadline, self.port.readable()).await {
+                Err(_elapsed) => return Ok(None),
+                Ok(result) => result?,
+            }
+            match self.port.try_read(&mut buffer) {
+                Ok(bytes_read) => self.received.extend_from_slice(&buffer[..bytes_read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
 
-        // self.port.write_data_terminal_ready(false)?;
+    /// Ask the firmware for framed transfers, old firmware answers ERROR to `AT+VER` or nothing
+    pub async fn negotiate(&mut self) -> Result<Protocol, EInkResponse> {
+        if let Some(protocol) = self.protocol {
+            return Ok(protocol);
+        }
 
-        self.port.write_request_to_send(false)?;
-        sleep(Duration::from_millis(100)).await; //Wait for start
-        Ok(())
+        let cmd = "AT+VER\r\n";
+        println!("{} {}", log::SEND, cmd.trim());
+        let start = Instant::now();
+        let reply = loop {
+            self.dump_rx()?;
+            self.port.write_all(cmd.as_bytes())?;
+            self.port.flush()?;
+            match self.read_line(REPLY_TIMEOUT).await? {
+                Some(line) if line == "BUSY" && start.elapsed().as_secs() < 20 => {
+                    sleep(Duration::from_millis(100)).await;
+                }
+                reply => break reply,
+            }
+        };
+
+        let protocol = match reply {
+            Some(line) if line.starts_with("OK") => {
+                let version = line
+                    .strip_prefix("OK")
+                    .and_then(|version| version.trim().parse::<u32>().ok())
+                    .unwrap_or(1);
+                if version >= FRAMED_VERSION {
+                    Protocol::Framed
+                } else {
+                    Protocol::Text
+                }
+            }
+            Some(line) if line == "BUSY" => return Err(EInkResponse::Busy),
+            _ => Protocol::Text,
+        };
+
+        println!(
+            "{} Using {:?} transfers for {}",
+            log::THREAD,
+            protocol,
+            self.path
+        );
+        self.protocol = Some(protocol);
+        Ok(protocol)
     }
 
-    #[allow(dead_code)]
-    pub async fn wait_ready(&mut self) -> Result<EInkResponse, EInkResponse> {
-        let cmd: String = "AT+READY=\r\n".to_string();
-        self.send_cmd(&cmd).await
+    /// Send the image data as frames, every frame is sent again until it is acknowledged
+    pub async fn send_frames(&mut self, data: &[u8]) -> Result<(), EInkResponse> {
+        let mut resent = 0;
+        for (index, payload) in data.chunks(FRAME_SIZE).enumerate() {
+            let sequence = index as u16;
+            let frame = encode_frame(sequence, payload);
+
+            let mut attempts = 0;
+            loop {
+                self.port.write_all(&frame)?;
+                self.port.flush()?;
+                if self.wait_for_ack(sequence).await? {
+                    break;
+                }
+                if attempts == FRAME_RETRIES {
+                    println!("{} Frame {} was not acknowledged", log::ERROR, sequence);
+                    return Err(EInkResponse::Error);
+                }
+                attempts += 1;
+                resent += 1;
+            }
+        }
+
+        if resent > 0 {
+            println!("{} Resent {} frames", log::WARN, resent);
+        }
+
+        // The firmware checks the CRC32 of the whole image after the last frame
+        match self.read_line(REPLY_TIMEOUT).await? {
+            Some(line) if line == "OK" => Ok(()),
+            Some(line) => {
+                println!("{} Error: {}", log::ERROR, line);
+                Err(EInkResponse::Error)
+            }
+            None => Err(EInkResponse::Error),
+        }
     }
 
-    #[allow(dead_code)]
-    pub async fn set_led(&mut self, value: u8) -> Result<(), EInkResponse> {
-        self.dump_rx();
-        let cmd = format!("AT+LED={}\r\n", value);
-        let resp = self.send_cmd(&cmd).await;
-        if resp.is_err() {
-            return Err(EInkResponse::Error);
+    /// Returns false when the frame has to be sent again
+    async fn wait_for_ack(&mut self, sequence: u16) -> Result<bool, EInkResponse> {
+        loop {
+            let Some(line) = self.read_line(ACK_TIMEOUT).await? else {
+                return Ok(false);
+            };
+            let reply = line
+                .split_once(' ')
+                .and_then(|(reply, number)| Some((reply, number.parse::<u16>().ok()?)));
+            match reply {
+                Some(("ACK", number)) if number == sequence => return Ok(true),
+                // Late ACK of a frame that was sent again
+                Some(("ACK", _)) => continue,
+                Some(("NAK", _)) => return Ok(false),
+                // The transfer was aborted
+                _ => {
+                    println!("{} Error: {}", log::ERROR, line);
+                    return Err(EInkResponse::Error);
+                }
+            }
         }
-        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)] //reflects the uart data structure
-    pub async fn send_image(
+    async fn send_image_text(
         &mut self,
-        data: &[u8], // Full buffer
+        data: &[u8],
         x: u32,
         y: u32,
         width: u32,
         height: u32,
         with_red: bool,
-        full_refresh: bool,
-        border: bool,
-    ) {
+    ) -> Result<(), EInkResponse> {
         // Prepare AT+IMG Command
         let crc: u8 = data.iter().fold(0, |acc, &x| acc.wrapping_add(x));
         // self.dump_rx();
@@ -216,6 +357,7 @@ impl EInkUartInterface {
         );
         if let Err(error) = self.send_cmd(&cmd).await {
             println!("{} Error starting image transfer, {}", log::ERROR, error);
+            return Err(error);
         }
         // sleep(Duration::from_millis(CHUNK_DELAY)); // wait to start transfer
 
@@ -223,14 +365,285 @@ impl EInkUartInterface {
 
         if let Err(error) = self.send_data_in_chunks(data).await {
             println!("{} Error sending data, {}", log::ERROR, error);
+            return Err(error);
         }
+        Ok(())
+    }
 
-        // self.wait_ready();
+    #[allow(clippy::too_many_arguments)] //reflects the uart data structure
+    async fn send_image_framed(
+        &mut self,
+        data: &[u8],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        let crc = crc32fast::hash(data);
+        let cmd = format!(
+            "AT+BIMG={} {} {} {} {} {}\r\n",
+            with_red as u8, x, y, width, height, crc
+        );
+        if let Err(error) = self.send_cmd(&cmd).await {
+            println!("{} Error starting image transfer, {}", log::ERROR, error);
+            return Err(error);
+        }
 
+        if let Err(error) = self.send_frames(data).await {
+            println!("{} Error sending data, {}", log::ERROR, error);
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+/// COBS frame of a sequence number and payload followed by their CRC32, all little endian
+fn encode_frame(sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut content = sequence.to_le_bytes().to_vec();
+    content.extend_from_slice(payload);
+    content.extend_from_slice(&crc32fast::hash(&content).to_le_bytes());
+
+    let mut frame = cobs::encode_vec(&content);
+    frame.push(0);
+    frame
+}
+
+#[async_trait]
+impl EInkTransport for EInkUartInterface {
+    async fn reset(&mut self) -> Result<(), EInkResponse> {
+        // The firmware may have changed, ask again with the next image
+        self.protocol = None;
+
+        // Bring RTS high for 100ms.
+
+        // self.port.write_data_terminal_ready(true)?;
+        if let Err(error) = self.port.write_request_to_send(true) {
+            // A pty, like the emulator, has no modem lines. Go on without the reset,
+            // a board that is really gone fails on the next command.
+            println!("{} Can't reset {}: {}", log::WARN, self.path, error);
+            return Ok(());
+        }
+
+        sleep(Duration::from_millis(100)).await; //Sleep to reset
+
+        // self.port.write_data_terminal_ready(false)?;
+
+        self.port.write_request_to_send(false)?;
+        sleep(Duration::from_millis(100)).await; //Wait for start
+        Ok(())
+    }
+
+    async fn wait_ready(&mut self) -> Result<(), EInkResponse> {
+        let cmd: String = "AT+READY=\r\n".to_string();
+        self.send_cmd(&cmd).await?;
+        Ok(())
+    }
+
+    async fn set_led(&mut self, value: u8) -> Result<(), EInkResponse> {
+        self.dump_rx()?;
+        let cmd = format!("AT+LED={}\r\n", value);
+        self.send_cmd(&cmd).await?;
+        Ok(())
+    }
+
+    async fn send_image(
+        &mut self,
+        data: &[u8], // Full buffer
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        with_red: bool,
+    ) -> Result<(), EInkResponse> {
+        match self.negotiate().await? {
+            Protocol::Text => {
+                self.send_image_text(data, x, y, width, height, with_red)
+                    .await
+            }
+            Protocol::Framed => {
+                self.send_image_framed(data, x, y, width, height, with_red)
+                    .await
+            }
+        }
+    }
+
+    async fn show(&mut self, full_refresh: bool, border: bool) -> Result<(), EInkResponse> {
         let cmd = format!("AT+SHOW={} {}\r\n", full_refresh as u8, border as u8);
         if let Err(error) = self.send_cmd(&cmd).await {
             println!("{} Error showing image, {}", log::ERROR, error);
+            return Err(error);
         }
         sleep(Duration::from_millis(250)).await; //Wait for start
+        Ok(())
+    }
+
+    fn is_present(&self) -> bool {
+        Path::new(&self.path).exists()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use image::Rgb;
+    use tag_driver_emulator::{firmware::TRANSFER_TIMEOUT, Emulator, Faults};
+
+    use super::*;
+
+    fn start(faults: Faults, output: Option<PathBuf>) -> (Emulator, EInkUartInterface) {
+        let emulator = Emulator::start(16, 16, faults, output).unwrap();
+        let interface =
+            EInkUartInterface::open(&emulator.path().to_string_lossy(), 912600).unwrap();
+        (emulator, interface)
+    }
+
+    #[tokio::test]
+    async fn show_on_emulator() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = Some(dir.path().to_path_buf());
+        let (emulator, mut interface) = start(Faults::default(), output);
+        interface.reset().await.unwrap();
+
+        // Zeros end a frame, they must be encoded
+        let mut data = vec![0u8; 32];
+        data[0] = 0b1000_0000;
+        interface
+            .send_image(&data, 0, 0, 16, 16, false)
+            .await
+            .unwrap();
+        interface.show(true, false).await.unwrap();
+
+        assert!(emulator.wait_for_frames(1, Duration::from_secs(1)));
+        let firmware = emulator.firmware();
+        let frame = &firmware.frames[0];
+        assert!(frame.full_refresh);
+        assert_eq!(frame.image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(frame.image.get_pixel(0, 1), &Rgb([255, 255, 255]));
+
+        let png = image::open(dir.path().join("frame-0000.png")).unwrap();
+        assert_eq!(png.to_rgb8(), frame.image);
+    }
+
+    #[tokio::test]
+    async fn retry_while_busy() {
+        let faults = Faults {
+            busy_replies: 2,
+            refresh_time: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let (emulator, mut interface) = start(faults, None);
+
+        let data = vec![0xff; 32];
+        for _ in 0..2 {
+            interface
+                .send_image(&data, 0, 0, 16, 16, false)
+                .await
+                .unwrap();
+            interface.show(false, false).await.unwrap();
+        }
+        assert!(emulator.wait_for_frames(2, Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn resend_lost_frames() {
+        let faults = Faults {
+            crc_errors: 1,
+            ..Default::default()
+        };
+        let (emulator, mut interface) = start(faults, None);
+        assert_eq!(interface.negotiate().await.unwrap(), Protocol::Framed);
+
+        let data = vec![0xff; 32];
+        assert!(matches!(
+            interface.send_image(&data, 0, 0, 16, 16, false).await,
+            Err(EInkResponse::Error)
+        ));
+
+        // The damaged frame is not acknowledged and sent again
+        emulator.firmware().faults.dropped_bytes = 4;
+        interface
+            .send_image(&data, 0, 0, 16, 16, false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn text_transfer_errors() {
+        let (emulator, mut interface) = start(Faults::default(), None);
+        // Firmware without AT+VER
+        emulator.firmware().framed = false;
+        assert_eq!(interface.negotiate().await.unwrap(), Protocol::Text);
+
+        emulator.firmware().faults.crc_errors = 1;
+        let data = vec![0xff; 32];
+        assert!(matches!(
+            interface.send_image(&data, 0, 0, 16, 16, false).await,
+            Err(EInkResponse::Error)
+        ));
+
+        emulator.firmware().faults.dropped_bytes = 4;
+        assert!(matches!(
+            interface.send_image(&data, 0, 0, 16, 16, false).await,
+            Err(EInkResponse::Error)
+        ));
+        // The board waits for the missing bytes until the transfer times out
+        sleep(TRANSFER_TIMEOUT * 2).await;
+
+        // The delimiter in the data must not end the transfer
+        let mut data = vec![0u8; 32];
+        data[0] = 0b1000_0000;
+        data[2] = b'\r';
+        data[3] = b'\n';
+        interface
+            .send_image(&data, 0, 0, 16, 16, false)
+            .await
+            .unwrap();
+        interface.show(false, false).await.unwrap();
+
+        assert!(emulator.wait_for_frames(1, Duration::from_secs(1)));
+        let firmware = emulator.firmware();
+        assert_eq!(firmware.frames[0].image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn unanswered_version() {
+        let faults = Faults {
+            silent_replies: 1,
+            ..Default::default()
+        };
+        let (emulator, mut interface) = start(faults, None);
+        // Firmware that hangs on AT+VER
+        emulator.firmware().framed = false;
+        assert_eq!(interface.negotiate().await.unwrap(), Protocol::Text);
+
+        interface
+            .send_image(&[0xff; 32], 0, 0, 16, 16, false)
+            .await
+            .unwrap();
+        interface.show(false, false).await.unwrap();
+        assert!(emulator.wait_for_frames(1, Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let faults = Faults {
+            disconnect_after: Some(1),
+            ..Default::default()
+        };
+        let (emulator, mut interface) = start(faults, None);
+
+        interface
+            .send_image(&[0; 32], 0, 0, 16, 16, false)
+            .await
+            .unwrap();
+        interface.show(false, false).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        // A pty that is gone fails like an unplugged board, the serial thread checks is_present
+        assert!(!emulator.is_connected());
+        assert!(interface.set_led(1).await.is_err());
+        assert!(!interface.is_present());
     }
 }
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use embedded_graphics::{geometry::Size, Pixel};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    display::{bwr_color::BWRColor, components::image_background::dither},
+    log,
+};
+
+const DATA_DIR: &str = "tagdriver";
+const IMAGES_DIR: &str = "images";
+const MAX_ID_LENGTH: usize = 64;
+
+/// Where the images are kept. Changes need a restart.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Directory of the images, `$XDG_DATA_HOME/tagdriver/images` when not set
+    pub path: Option<PathBuf>,
+}
+
+impl LibraryConfig {
+    pub fn dir(&self) -> PathBuf {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+            .unwrap_or_else(|| PathBuf::from("."));
+
+        data_home.join(DATA_DIR).join(IMAGES_DIR)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LibraryError {
+    #[error("Invalid image id '{0}', ids are up to 64 letters, digits, '-' and '_'")]
+    InvalidId(String),
+    #[error("Image '{0}' is not in the library")]
+    NotFound(String),
+    #[error("File error")]
+    FileError(#[from] io::Error),
+    #[error("Error loading image")]
+    ImageError(#[from] image::ImageError),
+}
+
+/// An image converted to the colors of the panels, for one display size
+pub type Bitmap = Arc<Vec<Pixel<BWRColor>>>;
+
+struct Library {
+    dir: PathBuf,
+    /// Sizes of the displays, imported images are converted for them right away
+    sizes: Vec<Size>,
+    cache: HashMap<(String, Size), Bitmap>,
+}
+
+/// The png images that can be shown by id, with the bitmaps converted from them
+#[derive(Clone)]
+pub struct ImageLibrary(Arc<Mutex<Library>>);
+
+/// Ids name the files in the library, so they can't hold a path
+pub fn validate_id(id: &str) -> Result<(), LibraryError> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(LibraryError::InvalidId(id.to_string())),
+    }
+}
+
+/// Scale the image to the size and dither it to the colors of the panels
+fn convert(image: &DynamicImage, size: Size) -> Vec<Pixel<BWRColor>> {
+    dither(
+        &image
+            .resize_exact(size.width, size.height, FilterType::Triangle)
+            .into_rgba8(),
+    )
+}
+
+impl ImageLibrary {
+    pub fn new(dir: PathBuf) -> Self {
+        Self(Arc::new(Mutex::new(Library {
+            dir,
+            sizes: vec![],
+            cache: HashMap::new(),
+        })))
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.0.lock().unwrap().dir.join(format!("{}.png", id))
+    }
+
+    /// Set the sizes of the displays, the bitmaps for other sizes are dropped
+    pub fn set_sizes(&self, sizes: Vec<Size>) {
+        let mut library = self.0.lock().unwrap();
+        library.cache.retain(|(_, size), _| sizes.contains(size));
+        library.sizes = sizes;
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        validate_id(id).is_ok() && self.path(id).is_file()
+    }
+
+    /// Ids of the images in the library, sorted
+    pub fn list(&self) -> Result<Vec<String>, LibraryError> {
+        let dir = self.0.lock().unwrap().dir.clone();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "png") {
+                if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    if validate_id(id).is_ok() {
+                        ids.push(id.to_string());
+                    }
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Store an encoded image as png under the id, replacing the image that had it
+    pub fn import(&self, id: &str, data: &[u8]) -> Result<(), LibraryError> {
+        validate_id(id)?;
+        let image = image::load_from_memory(data)?;
+
+        let path = self.path(id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Written next to it first, so the image is never read half written
+        let partial = path.with_extension("png.partial");
+        image.save_with_format(&partial, ImageFormat::Png)?;
+        fs::rename(&partial, &path)?;
+        println!("{} Imported image {}", log::RENDER, id);
+
+        let sizes = {
+            let mut library = self.0.lock().unwrap();
+            library.cache.retain(|(cached, _), _| cached != id);
+            library.sizes.clone()
+        };
+        for size in sizes {
+            let bitmap = Arc::new(convert(&image, size));
+            self.0
+                .lock()
+                .unwrap()
+                .cache
+                .insert((id.to_string(), size), bitmap);
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), LibraryError> {
+        validate_id(id)?;
+        match fs::remove_file(self.path(id)) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(LibraryError::NotFound(id.to_string()))
+            }
+            result => result?,
+        }
+        println!("{} Deleted image {}", log::RENDER, id);
+
+        self.0
+            .lock()
+            .unwrap()
+            .cache
+            .retain(|(cached, _), _| cached != id);
+        Ok(())
+    }
+
+    /// The image converted for the size, only loaded from disk the first time when the size
+    /// is one of the display sizes
+    pub fn bitmap(&self, id: &str, size: Size) -> Result<Bitmap, LibraryError> {
+        validate_id(id)?;
+        let key = (id.to_string(), size);
+        if let Some(bitmap) = self.0.lock().unwrap().cache.get(&key) {
+            return Ok(bitmap.clone());
+        }
+
+        println!("{} Loading image: {}", log::RENDER, id);
+        let path = self.path(id);
+        if !path.is_file() {
+            return Err(LibraryError::NotFound(id.to_string()));
+        }
+        let bitmap = Arc::new(convert(&image::open(path)?, size));
+        let mut library = self.0.lock().unwrap();
+        if library.sizes.contains(&size) {
+            library.cache.insert(key, bitmap.clone());
+        }
+        Ok(bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn rejects_paths_as_ids() {
+        assert!(validate_id("logo_400-v2").is_ok());
+        for id in [
+            "",
+            "../secret",
+            "a/b",
+            ".hidden",
+            "name.png",
+            &"x".repeat(65),
+        ] {
+            assert!(matches!(validate_id(id), Err(LibraryError::InvalidId(_))));
+        }
+    }
+
+    #[test]
+    fn imports_lists_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = ImageLibrary::new(dir.path().join("images"));
+        let size = Size::new(4, 2);
+        library.set_sizes(vec![size]);
+        assert_eq!(library.list().unwrap(), Vec::<String>::new());
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 4, Rgba([255, 0, 0, 255])))
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        library.import("red", &png).unwrap();
+        assert!(library.import("../red", &png).is_err());
+        assert!(library.import("broken", &[1, 2, 3]).is_err());
+        assert_eq!(library.list().unwrap(), vec!["red"]);
+
+        let bitmap = library.bitmap("red", size).unwrap();
+        assert_eq!(bitmap.len(), 8);
+        assert!(bitmap.iter().all(|Pixel(_, color)| *color == BWRColor::Red));
+        // Only the display sizes are kept
+        assert_eq!(library.bitmap("red", Size::new(2, 1)).unwrap().len(), 2);
+        assert_eq!(library.0.lock().unwrap().cache.len(), 1);
+
+        library.delete("red").unwrap();
+        assert!(!library.contains("red"));
+        assert!(matches!(
+            library.bitmap("red", size),
+            Err(LibraryError::NotFound(_))
+        ));
+        assert!(matches!(
+            library.delete("red"),
+            Err(LibraryError::NotFound(_))
+        ));
+    }
+}
@@ -15,3 +15,5 @@ pub const DBUS: &str = "🔗";
 pub const ERROR: &str = "❗️";
 pub const WARN: &str = "❓️";
 pub const STATE: &str = "💾";
+pub const CONFIG: &str = "📝";
+pub const USB: &str = "🔌";
@@ -1,21 +1,33 @@
 use std::{
+    future::pending,
     io::{self},
+    iter,
+    path::Path,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 #[macro_use]
 extern crate enum_primitive;
+mod config;
+mod control;
 mod dbus;
+mod discovery;
 mod display;
 mod eink;
+mod library;
 mod log;
 mod state;
 
 use colored::Colorize;
 use display::{
-    bwr_color::BWRColor, bwr_display::BWRDisplay, components::DisplayAreaType, COLOR_BG,
+    bwr_color::BWRColor,
+    bwr_display::BWRDisplay,
+    components::{DisplayAreaType, DisplayComponent, NextRefresh, RefreshType},
+    COLOR_BG,
+};
+use eink::{
+    backends::BackendConfig, thread::start_eink_thread, EInkCommand, EInkInterface, EInkResponse,
 };
-use eink::thread::start_eink_thread;
 
 use embedded_canvas::Canvas;
 use embedded_graphics::{
@@ -26,77 +38,79 @@ use embedded_graphics::{
 
 // impl Into<IconObj<T> for Icon<C, T> {}
 
+use futures_util::future::select_all;
 use itertools::Itertools;
 use tokio::{
-    sync::{mpsc, Mutex},
-    time::sleep,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Mutex},
+    time::{sleep, sleep_until},
 };
 
 use crate::{
+    config::{watch::watch_config, Config, DisplayConfig, RefreshPolicy},
+    control::ControlMessage,
     dbus::dbus_interface::run_dbus_thread,
-    display::{components::make_ui_components, DisplayRotation},
-    state::{build_state_map, value::StateValueType},
+    discovery::{scan_devices, watch::watch_devices, UsbSerialDevice},
+    display::components::{make_ui_components, pushed_image::PushedImages},
+    library::ImageLibrary,
+    state::{
+        app::ApplicationState, build_state_map, display_key, value::StateValueType,
+        DISPLAY_IMAGE_KEY, DISPLAY_STATUS_KEYS,
+    },
 };
 
-const DISPLAY_COUNT: u8 = 3;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let config_path = config::config_path(&args);
+
+    if args.get(1).is_some_and(|command| command == "list-devices") {
+        list_devices(&config_path);
+        return Ok(());
+    }
+
     println!("{}", log::WELCOME.blue());
 
+    // ////////////
+    // Load the config
+    // ////////////
+
+    println!("{} Loading config {}", log::CONFIG, config_path.display());
+
+    let mut config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{} {}", log::ERROR, error.to_string().red());
+            std::process::exit(1);
+        }
+    };
+
     // ////////////
     // Setup the EInk interface threads, these handle the uart
     // ////////////
 
-    let mut displays = [
-        (
-            BWRDisplay::new(250, 122, DisplayRotation::Zero, display::DisplayFlip::None),
-            start_eink_thread(
-                "/dev/serial/by-id/usb-RemijnPi_Eink_Driver_DE6270431F67292B-if00",
-                912600,
-                250,
-                122,
-            )?,
-        ),
-        (
-            BWRDisplay::new(
-                250,
-                122,
-                DisplayRotation::Rotate180,
-                display::DisplayFlip::None,
-            ),
-            start_eink_thread(
-                "/dev/serial/by-id/usb-RemijnPi_Eink_Driver_DE6270431F67292B-if04",
-                912600,
-                250,
-                122,
-            )?,
-        ),
-        (
-            BWRDisplay::new(
-                400,
-                300,
-                DisplayRotation::Rotate270,
-                display::DisplayFlip::Horizontal,
-            ),
-            start_eink_thread(
-                "/dev/serial/by-id/usb-RemijnPi_Eink_Driver_DE6270431F67292B-if02",
-                912600,
-                300,
-                400,
-            )?,
-        ),
-    ];
+    let mut devices = scan_devices(Path::new("/")).unwrap_or_else(|error| {
+        println!("{} Could not scan for devices: {}", log::ERROR, error);
+        Vec::new()
+    });
+
+    let mut displays = Vec::new();
+    connect_displays(&config, &devices, &mut displays).await;
 
     // Setup the global app state
 
-    let state = Arc::new(Mutex::new(build_state_map()));
+    let state = Arc::new(Mutex::new(build_state_map(
+        &config.state,
+        config.displays.len(),
+    )));
 
     let (state_update_tx, mut state_update_rx) = mpsc::channel::<()>(20);
+    let (control_tx, mut control_rx) = mpsc::channel::<ControlMessage>(20);
 
     // Star the stdin thread
     let stdin_state = state.clone();
     let stdin_update_tx = state_update_tx.clone();
+    let stdin_control_tx = control_tx.clone();
     tokio::spawn(async move {
         loop {
             let mut buffer = String::new();
@@ -123,65 +137,228 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     );
                     drop(lock);
                 }
+                // show <display> [id], without an id the image is cleared
                 "show" if parts.len() > 1 => {
-                    let mut lock = stdin_state.lock().await;
-
-                    let updated = lock
-                        .update(
-                            "rear-image-path",
-                            Some(StateValueType::String(parts.get(1).unwrap().to_string())),
-                        )
-                        .expect("Could not set rear display image");
-                    drop(lock);
-                    if updated {
-                        stdin_update_tx.send(()).await.unwrap();
-                    }
-                }
-                "show" if parts.len() == 1 => {
+                    let Ok(display) = parts[1].parse::<usize>() else {
+                        println!("{} Usage: show <display> [id]", log::WARN);
+                        continue;
+                    };
+                    let id = parts
+                        .get(2)
+                        .map(|id| StateValueType::String(id.to_string()));
                     let updated = stdin_state
                         .lock()
                         .await
-                        .update("rear-image-path", None)
-                        .expect("Count not reset rear display image");
-                    if updated {
-                        stdin_update_tx.send(()).await.unwrap();
+                        .update(&display_key(display, DISPLAY_IMAGE_KEY), id);
+                    match updated {
+                        Ok(true) => stdin_update_tx.send(()).await.unwrap(),
+                        Ok(false) => {}
+                        Err(error) => println!("{} Can't show the image: {}", log::ERROR, error),
                     }
                 }
+                "reload" => {
+                    stdin_control_tx.send(ControlMessage::Reload).await.unwrap();
+                }
                 _ => println!("{} Unknown command {}", log::WARN, buffer.trim().red()),
             }
         }
     });
 
+    // Start the config watcher
+    let watch_path = config_path.clone();
+    let watch_control_tx = control_tx.clone();
+    tokio::spawn(async move {
+        if let Err(error) = watch_config(watch_path, watch_control_tx).await {
+            println!("{} Could not watch the config file: {}", log::ERROR, error);
+        }
+    });
+
+    // Start the hotplug watcher
+    let devices_control_tx = control_tx.clone();
+    tokio::spawn(async move {
+        if let Err(error) = watch_devices(devices_control_tx).await {
+            println!("{} Could not watch for devices: {}", log::ERROR, error);
+        }
+    });
+
     // Start the dbus thread
     let dbus_state = state.clone();
     let dbus_update_tx = state_update_tx.clone();
-    tokio::spawn(async move {
-        run_dbus_thread(dbus_update_tx, dbus_state)
-            .await
-            .expect("DBus thread crashed");
-    });
+    let dbus_control_tx = control_tx.clone();
+    let (rebind_tx, rebind_rx) = watch::channel(());
+    let (state_changed_tx, state_changed_rx) = watch::channel(());
+    let pushed_images = PushedImages::default();
+    pushed_images.set_sizes(panel_sizes(&config.displays));
+    let library = ImageLibrary::new(config.library.dir());
+    library.set_sizes(display_sizes(&config.displays));
+    println!(
+        "{} Image library in {}",
+        log::CONFIG,
+        config.library.dir().display()
+    );
+    tokio::spawn(run_dbus_thread(
+        dbus_update_tx,
+        dbus_control_tx,
+        rebind_rx,
+        state_changed_rx,
+        dbus_state,
+        pushed_images.clone(),
+        library.clone(),
+        config.notifications.server,
+    ));
 
     // let dbus get the default values before we lock the state
     sleep(Duration::from_millis(10)).await;
 
     let state_lock = state.lock().await;
 
-    let mut ui_components = make_ui_components(state_lock.clone());
+    let mut ui_components =
+        match make_ui_components(&config.displays, &state_lock, &pushed_images, &library) {
+            Ok(ui_components) => ui_components,
+            Err(error) => {
+                println!("{} {}", log::ERROR, error.to_string().red());
+                std::process::exit(1);
+            }
+        };
 
     drop(state_lock);
 
-    let mut display_refresh_after: [Option<Instant>; DISPLAY_COUNT as usize] =
-        [Some(Instant::now()); DISPLAY_COUNT as usize];
+    // Refreshes the components asked for, the first frame is drawn with a full refresh
+    let mut display_next_refresh: Vec<Vec<NextRefresh>> =
+        vec![vec![(Instant::now(), RefreshType::Full)]; displays.len()];
+    let mut publish_status = true;
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
 
     // ////////////
     // Run the main loop
     // ////////////
     loop {
-        let mut display_needs_refresh: Vec<bool> = [false; DISPLAY_COUNT as usize].to_vec().clone();
+        // Sleep until something changes, the first time round draws the displays
+        let deadline = next_deadline(&display_next_refresh, &displays);
+        let mut control_messages = Vec::new();
+        let mut state_updated = false;
+        tokio::select! {
+            Some(message) = control_rx.recv() => control_messages.push(message),
+            Some(()) = state_update_rx.recv() => state_updated = true,
+            (i, response) = next_response(&mut displays) => {
+                publish_status = true;
+                match response {
+                    Some(response) => {
+                        if let Some(interface) = &mut displays[i].1 {
+                            interface.handle_response(response);
+                        }
+                    }
+                    None => {
+                        println!("{} Serial thread of display {} stopped", log::ERROR, i);
+                        displays[i].1 = None;
+                    }
+                }
+            }
+            () = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {}
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+
+        control_messages.extend(iter::from_fn(|| control_rx.try_recv().ok()));
+        for message in control_messages {
+            match message {
+                ControlMessage::Reload => {
+                    match reload_config(
+                        &config_path,
+                        &mut config,
+                        &devices,
+                        &mut displays,
+                        &mut ui_components,
+                        &state,
+                        &pushed_images,
+                        &library,
+                    )
+                    .await
+                    {
+                        Ok(()) => {
+                            println!("{} Config reloaded", log::CONFIG);
+                            // Draw the new layout on every display, with a full refresh
+                            display_next_refresh =
+                                vec![vec![(Instant::now(), RefreshType::Full)]; displays.len()];
+                            rebind_tx.send(()).ok();
+                            publish_status = true;
+                        }
+                        Err(error) => println!(
+                            "{} Keeping the current config: {}",
+                            log::ERROR,
+                            error.to_string().red()
+                        ),
+                    }
+                }
+                ControlMessage::Refresh { display, full } => {
+                    let refresh_type = if full {
+                        RefreshType::Full
+                    } else {
+                        RefreshType::Fast
+                    };
+                    if let Some(next_refresh) = display_next_refresh.get_mut(display as usize) {
+                        next_refresh.push((Instant::now(), refresh_type));
+                    }
+                }
+                ControlMessage::SetLed { display, color } => {
+                    let Some((_display, Some(interface))) = displays.get_mut(display as usize)
+                    else {
+                        println!("{} Display {} is not connected", log::WARN, display);
+                        continue;
+                    };
+                    if let Err(error) = interface.send_command(EInkCommand::Led { color }).await {
+                        println!(
+                            "{} Could not set the LED of display {}: {}",
+                            log::ERROR,
+                            display,
+                            error
+                        );
+                    }
+                }
+                ControlMessage::DevicesChanged => {
+                    match scan_devices(Path::new("/")) {
+                        Ok(new_devices) => devices = new_devices,
+                        Err(error) => {
+                            println!("{} Could not scan for devices: {}", log::ERROR, error);
+                            continue;
+                        }
+                    }
+                    let connected = connect_displays(&config, &devices, &mut displays).await;
+                    publish_status = true;
+                    for (i, connected) in connected.into_iter().enumerate() {
+                        if connected {
+                            display_next_refresh[i].push((Instant::now(), RefreshType::Full));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut display_needs_refresh: Vec<bool> = vec![false; displays.len()];
+        let mut display_refresh_type: Vec<Option<RefreshType>> = vec![None; displays.len()];
+
+        // Read the status of the serial threads
+        let mut status_changed = std::mem::take(&mut publish_status);
+        for (_display, interface) in displays.iter_mut() {
+            if let Some(interface) = interface {
+                status_changed |= interface.poll_responses();
+            }
+        }
+        if status_changed && publish_display_status(&mut *state.lock().await, &displays) {
+            state_update_tx.try_send(()).ok();
+        }
 
         // Proccess state updates for each component and
         // set refresh for the displays with the components that need it
         while state_update_rx.try_recv().is_ok() {
+            state_updated = true;
+        }
+        if state_updated {
+            // Let DBus clients know what changed
+            state_changed_tx.send(()).ok();
+
             // We have new values, check with each component if this new state requires a refresh
             let state_lock = state.lock().await;
 
@@ -205,14 +382,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
-        // Set refresh if the timeout has been hit
-        for i in 0..DISPLAY_COUNT {
-            if let Some(time) = display_refresh_after[i as usize] {
-                if time < Instant::now() {
-                    display_needs_refresh[i as usize] = true;
-                    display_refresh_after[i as usize] = None;
-                    println!("{} Refresh After on display {}", log::DISPLAY, i)
+        // Set refresh for the scheduled refreshes that are due
+        let now = Instant::now();
+        for (i, next_refresh) in display_next_refresh.iter_mut().enumerate() {
+            next_refresh.retain(|&(time, refresh_type)| {
+                if time > now {
+                    return true;
                 }
+                println!("{} Refresh After on display {}", log::DISPLAY, i);
+                display_needs_refresh[i] = true;
+                display_refresh_type[i] = display_refresh_type[i].max(Some(refresh_type));
+                false
+            });
+        }
+
+        // Clean the panels against ghosting, and draw them again
+        for (i, (_display, interface)) in displays.iter_mut().enumerate() {
+            let Some(interface) = interface else { continue };
+            if matches!(interface.state, EInkResponse::Ready)
+                && interface.ghosting.clean_due(SystemTime::now())
+            {
+                if let Err(error) = interface.clean().await {
+                    println!("{} Could not clean display {}: {}", log::ERROR, i, error);
+                    continue;
+                }
+                display_needs_refresh[i] = true;
+                display_refresh_type[i] = Some(RefreshType::Full);
             }
         }
 
@@ -222,9 +417,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .enumerate()
             .filter(|v| display_needs_refresh[v.0])
         {
+            let Some(interface) = interface else {
+                println!("{} Display {} is not connected", log::WARN, i);
+                continue;
+            };
+
             // Display i needs an update, lets wrender
             println!("{} Rendering display {}", log::RENDER, i);
 
+            // The components that are drawn schedule their refreshes again
+            display_next_refresh[i].clear();
+
             // clear the display
             display.clear(COLOR_BG)?;
             let values = Box::new(state.lock().await.clone());
@@ -270,15 +473,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 component.0.draw(&mut canvas, &values)?;
 
-                let refresh = component.0.get_refresh_at();
-                if refresh.is_some()
-                    && (display_refresh_after[i].is_none()
-                        || refresh.expect("") > display_refresh_after[i].expect(""))
-                {
-                    display_refresh_after[i] = component.0.get_refresh_at();
+                display_refresh_type[i] =
+                    display_refresh_type[i].max(component.0.get_refresh_type());
+                if let Some((time, refresh_type)) = component.0.get_next_refresh() {
+                    display_next_refresh[i].push((time, refresh_type));
                     println!(
-                        "⏳️ Display refresh after {}ms",
-                        (display_refresh_after[i].expect("") - Instant::now()).as_millis()
+                        "⏳️ Display {:?} refresh after {}ms",
+                        refresh_type,
+                        time.saturating_duration_since(Instant::now()).as_millis()
                     );
                 }
                 canvases.push((canvas, component.0.get_type()));
@@ -336,23 +538,244 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             drop(values);
 
-            let (black, _red) = display.get_fixed_buffer();
+            let (black, red) = display.get_fixed_buffer();
 
             interface.black_border = true;
+            interface.with_red = config.displays[i].red;
 
-            // Stupid hack to force full-refresh the right display
-            if !interface._port.ends_with("if00") {
-                interface
-                    .full(black)
-                    .await
-                    .expect("Error sending to main thread");
-            } else {
-                interface
-                    .fast(black)
-                    .await
-                    .expect("Error sending to main thread");
+            // Components can ask for a better refresh than the display uses, not for a worse one
+            let refresh_type = match config.displays[i].refresh {
+                RefreshPolicy::Full => RefreshType::Full,
+                RefreshPolicy::Fast => display_refresh_type[i].unwrap_or(RefreshType::Partial),
+            };
+
+            if let Err(error) = interface.update(display, black, red, refresh_type).await {
+                println!(
+                    "{} Could not send frame to display {}: {}",
+                    log::ERROR,
+                    i,
+                    error
+                );
             }
         }
-        sleep(Duration::from_millis(10)).await;
+    }
+
+    println!("{} Shutting down", log::THREAD);
+    // Dropping the interfaces stops the serial threads, give them some time to close the ports
+    drop(displays);
+    sleep(Duration::from_millis(100)).await;
+    // The stdin thread is stuck in a blocking read, the runtime would wait for it forever
+    std::process::exit(0);
+}
+
+/// The first scheduled refresh, or clean cycle of a display that is ready for it
+fn next_deadline(
+    display_next_refresh: &[Vec<NextRefresh>],
+    displays: &[(BWRDisplay, Option<EInkInterface>)],
+) -> Option<Instant> {
+    let next_refresh = display_next_refresh.iter().flatten().map(|(time, _)| *time);
+    let next_clean = displays
+        .iter()
+        .filter_map(|(_display, interface)| interface.as_ref())
+        .filter(|interface| matches!(interface.state, EInkResponse::Ready))
+        .filter_map(|interface| interface.ghosting.next_clean())
+        .map(|time| {
+            Instant::now()
+                + time
+                    .duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO)
+        });
+    next_refresh.chain(next_clean).min()
+}
+
+/// Wait for a response of any of the serial threads, None when the thread of the display stopped
+async fn next_response(
+    displays: &mut [(BWRDisplay, Option<EInkInterface>)],
+) -> (usize, Option<EInkResponse>) {
+    let receivers: Vec<_> = displays
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, (_display, interface))| {
+            let interface = interface.as_mut()?;
+            Some(Box::pin(async move { (i, interface.rx.recv().await) }))
+        })
+        .collect();
+    if receivers.is_empty() {
+        return pending().await;
+    }
+    select_all(receivers).await.0
+}
+
+/// Load the config again and swap in the new state, components and displays.
+/// Nothing is changed when the new config is invalid.
+#[allow(clippy::too_many_arguments)]
+async fn reload_config(
+    path: &Path,
+    config: &mut Config,
+    devices: &[UsbSerialDevice],
+    displays: &mut Vec<(BWRDisplay, Option<EInkInterface>)>,
+    ui_components: &mut Vec<Box<dyn DisplayComponent>>,
+    state: &Mutex<ApplicationState>,
+    pushed_images: &PushedImages,
+    library: &ImageLibrary,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{} Reloading config {}", log::CONFIG, path.display());
+    let new_config = Config::load(path)?;
+
+    let mut state_lock = state.lock().await;
+    let mut new_state = build_state_map(&new_config.state, new_config.displays.len());
+    new_state.carry_over(&state_lock);
+    *ui_components = make_ui_components(&new_config.displays, &new_state, pushed_images, library)?;
+    *state_lock = new_state;
+    drop(state_lock);
+    library.set_sizes(display_sizes(&new_config.displays));
+    pushed_images.set_sizes(panel_sizes(&new_config.displays));
+
+    connect_displays(&new_config, devices, displays).await;
+
+    *config = new_config;
+    Ok(())
+}
+
+/// Sizes the library converts its images for
+fn display_sizes(displays: &[DisplayConfig]) -> Vec<Size> {
+    panel_sizes(displays).into_iter().unique().collect()
+}
+
+/// Size of every display, by index
+fn panel_sizes(displays: &[DisplayConfig]) -> Vec<Size> {
+    displays
+        .iter()
+        .map(|display| Size::new(display.width, display.height))
+        .collect()
+}
+
+/// Build the displays for the config and connect them to their serial ports or backends.
+/// Serial threads of displays that stay on the same port with the same size are kept.
+/// Returns which displays got a new connection.
+async fn connect_displays(
+    config: &Config,
+    devices: &[UsbSerialDevice],
+    displays: &mut Vec<(BWRDisplay, Option<EInkInterface>)>,
+) -> Vec<bool> {
+    let mut old_interfaces: Vec<EInkInterface> = std::mem::take(displays)
+        .into_iter()
+        .filter_map(|(_display, interface)| interface)
+        .collect();
+
+    let mut connected = Vec::new();
+    for (i, display_config) in config.displays.iter().enumerate() {
+        let (panel_width, panel_height) = display_config.panel_size();
+        let port = match &display_config.backend {
+            BackendConfig::Uart => display_config.resolve_port(&config.discovery, devices),
+            backend => Some(format!("display {} {}", i, backend)),
+        };
+        let old_interface = old_interfaces
+            .iter()
+            .position(|old| {
+                Some(&old.port) == port.as_ref()
+                    && old.baud == display_config.baud
+                    && (old.width, old.height) == (panel_width, panel_height)
+            })
+            .map(|index| old_interfaces.remove(index));
+
+        connected.push(old_interface.is_none() && port.is_some());
+        let interface = old_interface.or_else(|| {
+            let Some(port) = port else {
+                println!(
+                    "{} Display {} ({}) not found",
+                    log::WARN,
+                    i,
+                    display_config.location()
+                );
+                return None;
+            };
+            println!("{} Display {} on {}", log::USB, i, port);
+            let open = display_config.backend.opener(
+                &port,
+                display_config.baud,
+                panel_width,
+                panel_height,
+            );
+            Some(start_eink_thread(
+                &port,
+                display_config.baud,
+                panel_width,
+                panel_height,
+                open,
+            ))
+        });
+        let interface = interface.map(|mut interface| {
+            interface.ghosting.config = display_config.ghosting.clone();
+            interface
+        });
+        displays.push((display_config.build_display(), interface));
+    }
+
+    // Dropping the interface stops the serial thread, give it some time to close the port
+    if !old_interfaces.is_empty() {
+        drop(old_interfaces);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    connected
+}
+
+/// Publish the status of the serial threads in the `display:<index>:*` state keys.
+/// Returns true when a value changed.
+fn publish_display_status(
+    state: &mut ApplicationState,
+    displays: &[(BWRDisplay, Option<EInkInterface>)],
+) -> bool {
+    let mut updated = false;
+    for (i, (_display, interface)) in displays.iter().enumerate() {
+        let values = match interface {
+            Some(interface) => interface.status_values(),
+            None => [
+                Some(StateValueType::String("not found".to_string())),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ],
+        };
+        for (name, value) in DISPLAY_STATUS_KEYS.iter().zip(values) {
+            updated |= state.update(&display_key(i, name), value).unwrap_or(false);
+        }
+    }
+    updated
+}
+
+/// Print the driver boards that were found, and the displays they are used for
+fn list_devices(config_path: &Path) {
+    let devices = match scan_devices(Path::new("/")) {
+        Ok(devices) => devices,
+        Err(error) => {
+            println!("{} Could not scan for devices: {}", log::ERROR, error);
+            return;
+        }
+    };
+    let config = Config::load(config_path).ok();
+    let filter = config
+        .as_ref()
+        .map(|config| config.discovery.clone())
+        .unwrap_or_default();
+
+    let mut found = false;
+    for device in devices.iter().filter(|device| filter.matches(device)) {
+        found = true;
+        let display = config.as_ref().and_then(|config| {
+            config.displays.iter().position(|display: &DisplayConfig| {
+                display.resolve_port(&config.discovery, &devices).as_ref() == Some(&device.port)
+            })
+        });
+        match display {
+            Some(display) => println!("{} {} -> display {}", log::USB, device, display),
+            None => println!("{} {}", log::USB, device),
+        }
+    }
+    if !found {
+        println!("{} No driver boards found", log::WARN);
     }
 }
@@ -15,9 +15,8 @@ pub enum ApplicationStateError {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(bound(deserialize = "'de: 'static"))]
 pub struct ApplicationState {
-    pub map: HashMap<&'static str, StateValue>,
+    pub map: HashMap<String, StateValue>,
 }
 
 fn print_update(key: &str, old: &StateValue, new: &StateValue) {
@@ -27,30 +26,16 @@ fn print_update(key: &str, old: &StateValue, new: &StateValue) {
     println!("{} Updated {} old: {}, new: {}", log::STATE, key, old, new);
 }
 impl ApplicationState {
-    pub fn get_value_dbus(
-        &self,
-        property: &DBusPropertyAdress,
-    ) -> Result<Option<StateValueType>, ApplicationStateError> {
-        for value in self.map.values() {
-            if value.dbus_property.is_some() && value.dbus_property.expect("") == property {
-                return Ok(value.get());
-            }
-        }
-        Err(ApplicationStateError::DoesNotExistError(
-            property.to_string(),
-        ))
-    }
-
     pub fn update_dbus(
         &mut self,
         property: &DBusPropertyAdress,
         val: &dyn RefArg,
     ) -> Result<Option<StateValueType>, ApplicationStateError> {
         for (key, value) in self.map.iter_mut() {
-            if value.dbus_property.is_some() && value.dbus_property.expect("") == property {
+            if value.dbus_property.as_ref() == Some(property) {
                 // let mut v = value.clone();
                 let old = value.clone();
-                value.set(Some(StateValueType::from_ref_arg(val)));
+                value.set(StateValueType::from_ref_arg(val));
                 print_update(key, &old, value);
 
                 return Ok(old.get());
@@ -99,6 +84,28 @@ impl ApplicationState {
         Ok(updated)
     }
 
+    /// Keep the values of a previous state for keys that are declared the same way
+    pub fn carry_over(&mut self, old: &ApplicationState) {
+        for (key, value) in self.map.iter_mut() {
+            if let Some(old_value) = old.map.get(key) {
+                value.carry_over(old_value);
+            }
+        }
+    }
+
+    /// Every key with its value as plain JSON, null for values that are not available
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.map
+            .keys()
+            .map(|key| {
+                let value = self
+                    .get(key)
+                    .map_or(serde_json::Value::Null, |value| value.to_json());
+                (key.clone(), value)
+            })
+            .collect()
+    }
+
     pub fn get(&self, key: &str) -> Option<&StateValueType> {
         let Some(value) = self.map.get(key) else {
             return None;
@@ -1,13 +1,18 @@
 use std::collections::HashMap;
 
-use crate::dbus::{BusType, DBusPropertyAdress, DBusProxyAdress};
+use serde::{Deserialize, Serialize};
+
+use crate::dbus::{
+    mpris::MEDIA_KEYS, notifications::NOTIFICATION_KEYS, BusType, DBusPropertyAdress,
+    DBusProxyAdress,
+};
 
 pub mod app;
 pub mod value;
 
 use app::ApplicationState;
 
-use self::value::{Filter, FilterMultiply, FilterRound, StateValue, StateValueType};
+use self::value::{Filter, FilterRound, StateValue, StateValueType};
 
 // pub struct PowerState {
 //     battery_percentage: u32,
@@ -18,86 +23,211 @@ use self::value::{Filter, FilterMultiply, FilterRound, StateValue, StateValueTyp
 //     power_state: PowerState,
 // }
 
-// PROXY Backlight power settings
-static BACKLIGHT_PROXY: DBusProxyAdress = DBusProxyAdress::new(
-    BusType::Session,
-    "org.gnome.SettingsDaemon.Power",
-    "/org/gnome/SettingsDaemon/Power",
-);
-
-// PROP display brightness
-static BRIGHTNESS_PROPERTY: DBusPropertyAdress = DBusPropertyAdress::new(
-    &BACKLIGHT_PROXY,
-    "org.gnome.SettingsDaemon.Power.Screen",
-    "Brightness",
-);
-
-// PROXY playerctld Media player
-static PLAYER_PROXY: DBusProxyAdress = DBusProxyAdress::new(
-    BusType::Session,
-    "org.mpris.MediaPlayer2.playerctld",
-    "/org/mpris/MediaPlayer2",
-);
-// PROP Volume
-static PLAYER_VOLUME_PROPERTY: DBusPropertyAdress =
-    DBusPropertyAdress::new(&PLAYER_PROXY, "org.mpris.MediaPlayer2.Player", "Volume");
-
-// PROXY Battery status
-static BATTERY_PROXY: DBusProxyAdress = DBusProxyAdress::new(
-    BusType::System,
-    "org.freedesktop.UPower",
-    "/org/freedesktop/UPower/devices/battery_BAT1",
-);
-// PROP Battery Percentage
-static BATTERY_LEVEL_PROPERTY: DBusPropertyAdress = DBusPropertyAdress::new(
-    &BATTERY_PROXY,
-    "org.freedesktop.UPower.Device",
-    "Percentage",
-);
-
-// PROP Battery State
-static BATTERY_STATE_PROPERTY: DBusPropertyAdress =
-    DBusPropertyAdress::new(&BATTERY_PROXY, "org.freedesktop.UPower.Device", "State");
-
-pub fn build_state_map() -> ApplicationState {
-    let mut map: HashMap<&'static str, StateValue> = HashMap::new();
-
-    map.insert(
-        "backlight:brightness",
-        StateValue::dbus(&BRIGHTNESS_PROPERTY, vec![]),
-    );
-
-    map.insert(
-        "player:volume",
-        StateValue::dbus(
-            &PLAYER_VOLUME_PROPERTY,
-            vec![Filter::Multiply(FilterMultiply { factor: 100.0 })],
+/// A DBus property to read a state value from
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DBusSourceConfig {
+    pub bus: BusType,
+    pub dest: String,
+    pub path: String,
+    pub interface: String,
+    pub property: String,
+}
+
+impl From<&DBusSourceConfig> for DBusPropertyAdress {
+    fn from(source: &DBusSourceConfig) -> Self {
+        DBusPropertyAdress::new(
+            DBusProxyAdress::new(source.bus.clone(), &source.dest, &source.path),
+            &source.interface,
+            &source.property,
+        )
+    }
+}
+
+/// Where a state value gets its updates from
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StateSource {
+    /// Set by the driver itself, or through DBus / stdin
+    #[default]
+    Manual,
+    DBus(DBusSourceConfig),
+}
+
+/// Declaration of a single state key, as read from the config
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    #[serde(default)]
+    pub default: Option<StateValueType>,
+    #[serde(default)]
+    pub source: StateSource,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+}
+
+impl StateConfig {
+    /// Check the declaration, returns a message describing the problem
+    pub fn validate(&self) -> Result<(), String> {
+        if let StateSource::DBus(source) = &self.source {
+            for (field, value) in [
+                ("dest", &source.dest),
+                ("path", &source.path),
+                ("interface", &source.interface),
+                ("property", &source.property),
+            ] {
+                if value.trim().is_empty() {
+                    return Err(format!("dbus {} can not be empty", field));
+                }
+            }
+            if !source.path.starts_with('/') {
+                return Err(format!(
+                    "dbus path {} must be an absolute object path",
+                    source.path
+                ));
+            }
+        }
+
+        for filter in self.filters.iter() {
+            if let Filter::Round(FilterRound { to }) = filter {
+                if *to == 0.0 {
+                    return Err("round filter can not round to 0".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> StateValue {
+        match &self.source {
+            StateSource::Manual => StateValue::filtered(self.default.clone(), self.filters.clone()),
+            StateSource::DBus(source) => {
+                StateValue::dbus(self.default.clone(), source.into(), self.filters.clone())
+            }
+        }
+    }
+}
+
+/// Keys the driver publishes itself, these always exist but can be overridden in the config
+fn builtin_state() -> Vec<(&'static str, StateConfig)> {
+    let mut state = vec![
+        ("wifi:state", StateConfig::default()),
+        (
+            "wifi:strength",
+            StateConfig {
+                filters: vec![Filter::Round(FilterRound { to: 20.0 })],
+                ..Default::default()
+            },
+        ),
+        ("wifi:ssid", StateConfig::default()),
+        ("eth:state", StateConfig::default()),
+        ("network:ipv4", StateConfig::default()),
+        ("network:ipv6", StateConfig::default()),
+        ("network:connectivity", StateConfig::default()),
+        ("network:vpn", StateConfig::default()),
+        (
+            "workspace:active",
+            StateConfig {
+                default: Some(StateValueType::U64(0)),
+                ..Default::default()
+            },
+        ),
+        (
+            "workspace:count",
+            StateConfig {
+                default: Some(StateValueType::U64(1)),
+                ..Default::default()
+            },
         ),
-    );
-    map.insert(
-        "battery:level",
-        StateValue::dbus(&BATTERY_LEVEL_PROPERTY, vec![]),
-    );
-    map.insert(
-        "battery:state",
-        StateValue::dbus(&BATTERY_STATE_PROPERTY, vec![]),
-    );
-
-    map.insert("wifi:state", StateValue::new(None));
-    map.insert(
-        "wifi:strength",
-        StateValue::filtered(None, vec![Filter::Round(FilterRound { to: 20.0 })]),
-    );
-    map.insert("eth:state", StateValue::new(None));
-    map.insert(
-        "workspace:active",
-        StateValue::new(Some(StateValueType::U64(0))),
-    );
-    map.insert(
-        "workspace:count",
-        StateValue::new(Some(StateValueType::U64(1))),
-    );
-    map.insert("rear-image-path", StateValue::new(None));
+    ];
+    state.extend(MEDIA_KEYS.map(|key| (key, StateConfig::default())));
+    state.extend(NOTIFICATION_KEYS.map(|key| (key, StateConfig::default())));
+    state
+}
+
+/// Status keys the driver publishes for every display, as `display:<index>:<name>`
+pub const DISPLAY_STATUS_KEYS: [&str; 6] = [
+    "status",
+    "last_frame",
+    "last_error",
+    "fast_refreshes",
+    "last_full_refresh",
+    "last_clean",
+];
+
+/// Image shown on a display by the DBus show method, as `display:<index>:image`
+pub const DISPLAY_IMAGE_KEY: &str = "image";
+/// Id of the image pushed to a display over DBus, as `display:<index>:pushed`
+pub const DISPLAY_PUSHED_KEY: &str = "pushed";
+
+pub fn display_key(display: usize, name: &str) -> String {
+    format!("display:{}:{}", display, name)
+}
+
+pub fn build_state_map(
+    declarations: &HashMap<String, StateConfig>,
+    display_count: usize,
+) -> ApplicationState {
+    let mut map: HashMap<String, StateValue> = HashMap::new();
+
+    for (key, declaration) in builtin_state() {
+        map.insert(key.to_string(), declaration.build());
+    }
+
+    for display in 0..display_count {
+        for name in DISPLAY_STATUS_KEYS {
+            map.insert(
+                display_key(display, name),
+                StateValue::filtered(None, vec![]),
+            );
+        }
+        for name in [DISPLAY_IMAGE_KEY, DISPLAY_PUSHED_KEY] {
+            map.insert(
+                display_key(display, name),
+                StateValue::filtered(None, vec![]),
+            );
+        }
+    }
+
+    for (key, declaration) in declarations.iter() {
+        map.insert(key.clone(), declaration.build());
+    }
 
     ApplicationState { map }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carry_over_unchanged_keys() {
+        let mut declarations = HashMap::new();
+        declarations.insert("custom:counter".to_string(), StateConfig::default());
+        declarations.insert("custom:level".to_string(), StateConfig::default());
+
+        let mut old = build_state_map(&declarations, 1);
+        old.update("custom:counter", Some(StateValueType::U64(5)))
+            .unwrap();
+        old.update("custom:level", Some(StateValueType::F64(42.0)))
+            .unwrap();
+        old.update("workspace:active", Some(StateValueType::U64(2)))
+            .unwrap();
+
+        // The level is now rounded, its old value no longer applies
+        declarations.insert(
+            "custom:level".to_string(),
+            StateConfig {
+                filters: vec![Filter::Round(FilterRound { to: 10.0 })],
+                ..Default::default()
+            },
+        );
+        let mut new = build_state_map(&declarations, 1);
+        new.carry_over(&old);
+
+        assert_eq!(new.get("custom:counter"), Some(&StateValueType::U64(5)));
+        assert_eq!(new.get("workspace:active"), Some(&StateValueType::U64(2)));
+        assert_eq!(new.get("custom:level"), None);
+        assert!(new.map.contains_key("display:0:status"));
+    }
+}
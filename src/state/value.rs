@@ -44,22 +44,23 @@ pub enum StateValueType {
 }
 
 impl StateValueType {
-    pub fn from_ref_arg(ref_arg: &dyn RefArg) -> Self {
-        return match ref_arg.arg_type() {
+    /// Convert a DBus value, None for types the state can not hold
+    pub fn from_ref_arg(ref_arg: &dyn RefArg) -> Option<Self> {
+        match ref_arg.arg_type() {
             ArgType::Int16 | ArgType::Int32 | ArgType::Int64 => {
-                StateValueType::I64(ref_arg.as_i64().expect("Cast error"))
+                ref_arg.as_i64().map(StateValueType::I64)
             }
 
             ArgType::UInt16
             | ArgType::UInt32
             | ArgType::UInt64
             | ArgType::Byte
-            | ArgType::Boolean => StateValueType::U64(ref_arg.as_u64().expect("Cast error")),
-            ArgType::String => {
-                StateValueType::String(ref_arg.as_str().expect("Cast error").to_string())
-            }
+            | ArgType::Boolean => ref_arg.as_u64().map(StateValueType::U64),
+            ArgType::String => ref_arg
+                .as_str()
+                .map(|value| StateValueType::String(value.to_string())),
 
-            ArgType::Double => StateValueType::F64(ref_arg.as_f64().expect("Cast error")),
+            ArgType::Double => ref_arg.as_f64().map(StateValueType::F64),
 
             _ => {
                 println!(
@@ -67,9 +68,34 @@ impl StateValueType {
                     log::ERROR,
                     ref_arg.arg_type().as_str()
                 );
-                todo!();
+                None
             }
-        };
+        }
+    }
+
+    /// The value as plain JSON, numbers and strings
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            StateValueType::U64(value) => (*value).into(),
+            StateValueType::I64(value) => (*value).into(),
+            StateValueType::F64(value) => (*value).into(),
+            StateValueType::String(value) => value.clone().into(),
+            StateValueType::NetworkState(value) => format!("{:?}", value).into(),
+        }
+    }
+
+    /// Read a value from plain JSON, null clears the value
+    pub fn from_json(json: &serde_json::Value) -> Result<Option<Self>, String> {
+        match json {
+            serde_json::Value::Null => Ok(None),
+            serde_json::Value::String(value) => Ok(Some(StateValueType::String(value.clone()))),
+            serde_json::Value::Number(number) => Ok(number
+                .as_u64()
+                .map(StateValueType::U64)
+                .or_else(|| number.as_i64().map(StateValueType::I64))
+                .or_else(|| number.as_f64().map(StateValueType::F64))),
+            _ => Err(format!("{} is not a number or a string", json)),
+        }
     }
 }
 
@@ -127,6 +153,7 @@ impl FilterTrait for FilterRound {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Filter {
     Multiply(FilterMultiply),
     Round(FilterRound),
@@ -141,17 +168,10 @@ pub struct StateValue {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(skip_deserializing)]
-    pub dbus_property: Option<&'static DBusPropertyAdress>,
+    pub dbus_property: Option<DBusPropertyAdress>,
 }
 
 impl StateValue {
-    pub fn new(default: Option<StateValueType>) -> Self {
-        Self {
-            value: default,
-            dbus_property: None,
-            filters: vec![],
-        }
-    }
     pub fn filtered(default: Option<StateValueType>, filters: Vec<Filter>) -> Self {
         Self {
             value: default,
@@ -159,13 +179,23 @@ impl StateValue {
             filters,
         }
     }
-    pub fn dbus(property: &'static DBusPropertyAdress, filters: Vec<Filter>) -> Self {
+    pub fn dbus(
+        default: Option<StateValueType>,
+        property: DBusPropertyAdress,
+        filters: Vec<Filter>,
+    ) -> Self {
         Self {
-            value: None,
+            value: default,
             dbus_property: Some(property),
             filters,
         }
     }
+    /// Take the value of `old` if it comes from the same source and went through the same filters
+    pub fn carry_over(&mut self, old: &StateValue) {
+        if self.filters == old.filters && self.dbus_property == old.dbus_property {
+            self.value = old.value.clone();
+        }
+    }
     pub fn get(&self) -> Option<StateValueType> {
         self.value.clone()
     }